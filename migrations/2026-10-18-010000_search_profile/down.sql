@@ -0,0 +1 @@
+ALTER TABLE gallery DROP COLUMN profile;
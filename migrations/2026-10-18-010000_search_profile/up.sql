@@ -0,0 +1 @@
+ALTER TABLE gallery ADD COLUMN profile TEXT NOT NULL DEFAULT "";
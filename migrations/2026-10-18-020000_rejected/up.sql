@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS rejected (
+    gallery_id INTEGER PRIMARY KEY NOT NULL,
+    token TEXT NOT NULL,
+    title TEXT NOT NULL,
+    reason TEXT NOT NULL,
+    rejected_at DATETIME NOT NULL
+);
@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS gallery_image (
+    gallery_id INTEGER NOT NULL,
+    page INTEGER NOT NULL,
+    url TEXT,
+    error TEXT,
+    PRIMARY KEY (gallery_id, page)
+);
@@ -0,0 +1 @@
+ALTER TABLE image_hash DROP COLUMN host;
@@ -0,0 +1 @@
+ALTER TABLE image_hash ADD COLUMN host TEXT NOT NULL DEFAULT "telegraph";
@@ -0,0 +1 @@
+DROP TABLE telegraph_page;
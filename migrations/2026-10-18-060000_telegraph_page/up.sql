@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS telegraph_page (
+    message_id INTEGER NOT NULL,
+    part INTEGER NOT NULL,
+    path TEXT NOT NULL,
+    PRIMARY KEY (message_id, part)
+);
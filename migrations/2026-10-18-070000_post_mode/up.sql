@@ -0,0 +1 @@
+ALTER TABLE gallery ADD COLUMN post_mode TEXT NOT NULL DEFAULT "text";
@@ -0,0 +1,15 @@
+CREATE TABLE IF NOT EXISTS gallery_tag (
+    message_id INTEGER NOT NULL,
+    namespace TEXT NOT NULL,
+    tag TEXT NOT NULL,
+    PRIMARY KEY (message_id, namespace, tag)
+);
+
+CREATE INDEX IF NOT EXISTS gallery_tag_tag_index ON gallery_tag (namespace, tag);
+
+-- 从 gallery.tags 中的 JSON 回填，格式为 [["namespace", ["tag", ...]], ...]
+INSERT OR IGNORE INTO gallery_tag (message_id, namespace, tag)
+SELECT g.message_id, json_extract(ns.value, '$[0]'), t.value
+FROM (SELECT message_id, tags FROM gallery WHERE json_valid(tags)) AS g,
+     json_each(g.tags) AS ns,
+     json_each(ns.value, '$[1]') AS t;
@@ -0,0 +1,2 @@
+-- rowid 为画廊的消息 id，由程序负责维护，首次启动时从 gallery 表重建
+CREATE VIRTUAL TABLE IF NOT EXISTS gallery_fts USING fts5(title, title_jp, tags);
@@ -0,0 +1,3 @@
+DROP INDEX gallery_next_check_at_index;
+
+ALTER TABLE gallery DROP COLUMN next_check_at;
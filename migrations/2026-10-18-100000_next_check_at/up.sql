@@ -0,0 +1,6 @@
+-- 为空表示不再检查更新
+ALTER TABLE gallery ADD COLUMN next_check_at DATETIME;
+
+UPDATE gallery SET next_check_at = CURRENT_TIMESTAMP WHERE score != -1;
+
+CREATE INDEX IF NOT EXISTS gallery_next_check_at_index ON gallery (next_check_at);
@@ -0,0 +1,3 @@
+DROP INDEX gallery_version_parent_id_index;
+
+DROP TABLE gallery_version;
@@ -0,0 +1,12 @@
+-- 已发布画廊的新版本记录，parent_id 为发现新版本时已发布的画廊
+CREATE TABLE IF NOT EXISTS gallery_version (
+    gallery_id INTEGER PRIMARY KEY NOT NULL,
+    token TEXT NOT NULL,
+    parent_id INTEGER NOT NULL,
+    parent_token TEXT NOT NULL,
+    message_id INTEGER NOT NULL,
+    action TEXT NOT NULL,
+    created_at DATETIME NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS gallery_version_parent_id_index ON gallery_version (parent_id);
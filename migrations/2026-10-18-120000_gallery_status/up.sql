@@ -0,0 +1,2 @@
+-- 画廊状态，active 为正常，expunged 为已在 E 站被删除或隐藏
+ALTER TABLE gallery ADD COLUMN status TEXT NOT NULL DEFAULT 'active';
@@ -0,0 +1,6 @@
+DROP INDEX gallery_status_index;
+
+UPDATE gallery SET status = 'active', score = -1 WHERE status = 'deleted_by_admin';
+
+ALTER TABLE gallery DROP COLUMN deleted_at;
+ALTER TABLE gallery DROP COLUMN deleted_by;
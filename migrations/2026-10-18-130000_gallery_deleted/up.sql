@@ -0,0 +1,7 @@
+ALTER TABLE gallery ADD COLUMN deleted_by BIGINT;
+ALTER TABLE gallery ADD COLUMN deleted_at DATETIME;
+
+-- 旧版本通过将评分设为 -1 来标记被管理员删除的画廊
+UPDATE gallery SET status = 'deleted_by_admin', score = 0, next_check_at = NULL WHERE score = -1;
+
+CREATE INDEX IF NOT EXISTS gallery_status_index ON gallery (status);
@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS user_role (
+    user_id BIGINT PRIMARY KEY NOT NULL,
+    role TEXT NOT NULL,
+    granted_by BIGINT NOT NULL,
+    granted_at DATETIME NOT NULL
+);
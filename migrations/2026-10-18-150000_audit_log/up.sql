@@ -0,0 +1,10 @@
+CREATE TABLE IF NOT EXISTS audit_log (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    actor_id BIGINT NOT NULL,
+    command TEXT NOT NULL,
+    -- 操作的画廊对应的消息 id
+    target INTEGER,
+    args TEXT NOT NULL,
+    result TEXT NOT NULL,
+    created_at DATETIME NOT NULL
+);
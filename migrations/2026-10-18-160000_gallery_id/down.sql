@@ -0,0 +1,42 @@
+UPDATE telegraph_page SET gallery_ref = (SELECT message_id FROM gallery WHERE id = gallery_ref);
+UPDATE gallery_tag SET gallery_ref = (SELECT message_id FROM gallery WHERE id = gallery_ref);
+UPDATE gallery_version SET parent_ref = (SELECT message_id FROM gallery WHERE id = parent_ref);
+DELETE FROM gallery_fts;
+
+ALTER TABLE telegraph_page RENAME COLUMN gallery_ref TO message_id;
+ALTER TABLE gallery_tag RENAME COLUMN gallery_ref TO message_id;
+ALTER TABLE gallery_version RENAME COLUMN parent_ref TO message_id;
+
+CREATE TABLE IF NOT EXISTS gallery_tmp (
+    message_id INTEGER PRIMARY KEY NOT NULL,
+    gallery_id INTEGER NOT NULL,
+    token TEXT NOT NULL,
+    title TEXT NOT NULL,
+    tags TEXT NOT NULL,
+    telegraph TEXT NOT NULL,
+    upload_images INT2 NOT NULL,
+    publish_date DATE NOT NULL,
+    poll_id TEXT NOT NULL,
+    score FLOAT NOT NULL,
+    votes TEXT NOT NULL,
+    profile TEXT NOT NULL DEFAULT "",
+    post_mode TEXT NOT NULL DEFAULT "text",
+    next_check_at DATETIME,
+    status TEXT NOT NULL DEFAULT 'active',
+    deleted_by BIGINT,
+    deleted_at DATETIME
+);
+
+-- 消息 id 重复的画廊只保留最新的一条
+INSERT OR REPLACE INTO gallery_tmp SELECT
+    message_id, gallery_id, token, title, tags, telegraph, upload_images, publish_date, poll_id,
+    score, votes, profile, post_mode, next_check_at, status, deleted_by, deleted_at
+FROM gallery ORDER BY id;
+
+DROP TABLE gallery;
+ALTER TABLE gallery_tmp RENAME TO gallery;
+
+CREATE INDEX IF NOT EXISTS gallery_id_index ON gallery (gallery_id);
+CREATE INDEX IF NOT EXISTS poll_id_index ON gallery (poll_id);
+CREATE INDEX IF NOT EXISTS gallery_next_check_at_index ON gallery (next_check_at);
+CREATE INDEX IF NOT EXISTS gallery_status_index ON gallery (status);
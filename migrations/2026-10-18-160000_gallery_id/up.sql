@@ -0,0 +1,41 @@
+-- 不同频道的消息 id 可能相同，改用自增 id 作为画廊的主键
+-- 已有画廊的 id 与消息 id 相同，关联表和全文索引中的数据无需修改
+CREATE TABLE IF NOT EXISTS gallery_tmp (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    message_id INTEGER NOT NULL,
+    gallery_id INTEGER NOT NULL,
+    token TEXT NOT NULL,
+    title TEXT NOT NULL,
+    tags TEXT NOT NULL,
+    telegraph TEXT NOT NULL,
+    upload_images INT2 NOT NULL,
+    publish_date DATE NOT NULL,
+    poll_id TEXT NOT NULL,
+    score FLOAT NOT NULL,
+    votes TEXT NOT NULL,
+    profile TEXT NOT NULL DEFAULT "",
+    post_mode TEXT NOT NULL DEFAULT "text",
+    next_check_at DATETIME,
+    status TEXT NOT NULL DEFAULT 'active',
+    deleted_by BIGINT,
+    deleted_at DATETIME
+);
+
+INSERT INTO gallery_tmp SELECT
+    message_id, message_id, gallery_id, token, title, tags, telegraph, upload_images, publish_date, poll_id,
+    score, votes, profile, post_mode, next_check_at, status, deleted_by, deleted_at
+FROM gallery;
+
+DROP TABLE gallery;
+ALTER TABLE gallery_tmp RENAME TO gallery;
+
+CREATE INDEX IF NOT EXISTS gallery_message_id_index ON gallery (message_id);
+CREATE INDEX IF NOT EXISTS gallery_id_index ON gallery (gallery_id);
+CREATE INDEX IF NOT EXISTS poll_id_index ON gallery (poll_id);
+CREATE INDEX IF NOT EXISTS gallery_next_check_at_index ON gallery (next_check_at);
+CREATE INDEX IF NOT EXISTS gallery_status_index ON gallery (status);
+
+-- 关联表改为引用画廊的 id
+ALTER TABLE telegraph_page RENAME COLUMN message_id TO gallery_ref;
+ALTER TABLE gallery_tag RENAME COLUMN message_id TO gallery_ref;
+ALTER TABLE gallery_version RENAME COLUMN message_id TO parent_ref;
@@ -0,0 +1 @@
+ALTER TABLE rejected DROP COLUMN expires_at;
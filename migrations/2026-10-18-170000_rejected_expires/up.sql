@@ -0,0 +1,2 @@
+-- 只因评分或收藏数不足被过滤的画廊，过期后重新检查
+ALTER TABLE rejected ADD COLUMN expires_at DATETIME;
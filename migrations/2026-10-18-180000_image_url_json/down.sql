@@ -0,0 +1,2 @@
+UPDATE image_hash SET url = replace(replace(replace(url, '","', ' '), '["', ''), '"]', '');
+UPDATE gallery_image SET url = replace(replace(replace(url, '","', ' '), '["', ''), '"]', '') WHERE url IS NOT NULL;
@@ -0,0 +1,3 @@
+-- 上传后的图片地址改为 JSON 数组，切分过的长图每一块为一项，原来以空格分隔
+UPDATE image_hash SET url = '["' || replace(url, ' ', '","') || '"]';
+UPDATE gallery_image SET url = '["' || replace(url, ' ', '","') || '"]' WHERE url IS NOT NULL;
@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS image_hash_tmp (
+    hash TEXT NOT NULL PRIMARY KEY,
+    url TEXT NOT NULL,
+    host TEXT NOT NULL DEFAULT "telegraph"
+);
+INSERT OR IGNORE INTO image_hash_tmp (hash, url, host)
+SELECT hash, url, host FROM image_hash ORDER BY host = 'telegraph' DESC;
+DROP TABLE image_hash;
+ALTER TABLE image_hash_tmp RENAME TO image_hash;
@@ -0,0 +1,10 @@
+-- 同一张图片在不同图床上各有一条缓存记录
+CREATE TABLE IF NOT EXISTS image_hash_tmp (
+    hash TEXT NOT NULL,
+    url TEXT NOT NULL,
+    host TEXT NOT NULL DEFAULT "telegraph",
+    PRIMARY KEY (hash, host)
+);
+INSERT INTO image_hash_tmp (hash, url, host) SELECT hash, url, host FROM image_hash;
+DROP TABLE image_hash;
+ALTER TABLE image_hash_tmp RENAME TO image_hash;
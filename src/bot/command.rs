@@ -1,7 +1,7 @@
 use crate::bot::utils::*;
-use crate::database::Gallery;
-use crate::exhentai::EXHENTAI;
-use crate::*;
+use crate::context::AppContext;
+use crate::database::{Gallery, Role};
+use crate::exloli::ExLoli;
 use futures::TryFutureExt;
 use std::convert::TryInto;
 use std::fmt::{self, Debug, Formatter};
@@ -9,10 +9,15 @@ use std::str::FromStr;
 use teloxide::types::Message;
 
 pub enum CommandError {
-    /// 命令解析错误
-    WrongCommand(&'static str),
+    /// 命令解析错误，附带用法提示，为空时直接删除命令消息
+    WrongCommand(String),
     /// 不是自己的命令
     NotACommand,
+    /// 没有权限执行的命令，mention 表示命令是否指定了本 bot
+    Denied {
+        def: &'static CommandDef,
+        mention: bool,
+    },
 }
 
 #[derive(PartialEq)]
@@ -23,16 +28,17 @@ pub enum InputGallery {
 
 impl InputGallery {
     /// 转换为 `Gallery`，会自动请求历史画廊
-    pub async fn to_gallery(&self) -> anyhow::Result<Gallery> {
+    pub async fn to_gallery(&self, ctx: &AppContext) -> anyhow::Result<Gallery> {
         match &self {
             Self::Gallery(g) => Ok(g.clone()),
-            Self::ExHentaiUrl(s) => match DB.query_gallery_by_url(s) {
+            Self::ExHentaiUrl(s) => match ctx.db.query_gallery_by_url(s) {
                 Err(_) => {
-                    let gallery = EXHENTAI
-                        .get_gallery_by_url(s)
+                    let gallery = ctx
+                        .exhentai
+                        .get_gallery_by_url(s, ctx.config.default_profile())
                         .and_then(|g| g.into_full_info())
                         .await?;
-                    Ok(ExLoli::get_history_upload(&gallery).await?)
+                    Ok(ExLoli::get_history_upload(ctx, &gallery).await?)
                 }
                 v => v,
             },
@@ -49,6 +55,7 @@ impl Debug for InputGallery {
     }
 }
 
+/// 新增命令时需要同时在 `COMMANDS` 中定义，测试会检查每个定义都能解析并找回自己
 #[derive(PartialEq, Debug)]
 pub enum RuaCommand {
     // 上传指定画廊
@@ -57,10 +64,14 @@ pub enum RuaCommand {
     Query(Vec<InputGallery>),
     // Ping bot
     Ping,
+    // 查看可用的命令
+    Help,
     // 用该命令回复一条画廊以将其删除
     Delete,
     // 这是真的删除，彻底删除
     RealDelete,
+    // 恢复被删除的画廊并重新发布
+    Undelete(Vec<InputGallery>),
     // 按评分高低查询一段时间内的本子，格式 /best 最少几天前 最多几天前 多少本
     Best([i64; 2]),
     // 用该命令回复一条画廊以上传其完整版本
@@ -69,13 +80,153 @@ pub enum RuaCommand {
     ReUpload(InputGallery),
     // 更新 tag
     UpdateTag(Vec<InputGallery>),
+    // 查看上传队列
+    Queue,
+    // 按关键词搜索已上传的画廊
+    Search(String),
+    // 授予用户权限，回复用户消息或指定用户 id
+    Grant(u64, Role),
+    // 收回用户权限
+    Revoke(u64),
+    // 查看最近的管理操作记录
+    Log(i64),
+}
+
+/// 命令定义，/help、用法提示和 Telegram 的命令列表均由此生成
+pub struct CommandDef {
+    pub name: &'static str,
+    /// 参数说明，为空表示没有参数
+    pub args: &'static str,
+    pub description: &'static str,
+    /// 执行命令所需的最低权限
+    pub role: Role,
+    /// 是否记录到操作日志
+    pub audit: bool,
+}
+
+impl CommandDef {
+    /// 命令的用法
+    pub fn usage(&self) -> String {
+        match self.args {
+            "" => format!("/{}", self.name),
+            args => format!("/{} {}", self.name, args),
+        }
+    }
+}
+
+macro_rules! command {
+    ($name:expr, $args:expr, $desc:expr, $role:ident, $audit:expr) => {
+        CommandDef {
+            name: $name,
+            args: $args,
+            description: $desc,
+            role: Role::$role,
+            audit: $audit,
+        }
+    };
+}
+
+/// 所有命令，按此顺序展示
+pub const COMMANDS: &[CommandDef] = &[
+    command!("ping", "", "测试存活", Viewer, false),
+    command!("help", "", "查看可用的命令", Viewer, false),
+    command!(
+        "query",
+        "[回复|画廊地址|消息地址]...",
+        "查询画廊",
+        Viewer,
+        false
+    ),
+    command!(
+        "best",
+        "起始天数 终止天数",
+        "获取第 $1 ~ $2 天间的画廊排行",
+        Viewer,
+        false
+    ),
+    command!(
+        "search",
+        "关键词...",
+        "按标题和 tag 搜索已上传的画廊",
+        Viewer,
+        false
+    ),
+    command!("upload", "画廊地址...", "上传画廊", Uploader, true),
+    command!(
+        "full",
+        "[回复|画廊地址|消息地址]...",
+        "上传画廊的完整版本",
+        Uploader,
+        true
+    ),
+    command!(
+        "uptag",
+        "[回复|画廊地址|消息地址]...",
+        "更新画廊 tag",
+        Uploader,
+        true
+    ),
+    command!("queue", "", "查看上传队列", Uploader, false),
+    command!("delete", "（回复画廊）", "删除画廊", Admin, true),
+    command!(
+        "real_delete",
+        "（回复画廊）",
+        "彻底删除画廊（调试用命令）",
+        Admin,
+        true
+    ),
+    command!(
+        "undelete",
+        "[画廊地址|消息地址]...",
+        "恢复被删除的画廊并重新发布",
+        Admin,
+        true
+    ),
+    command!(
+        "reupload",
+        "（回复画廊）",
+        "重新上传画廊并发布到原消息",
+        Admin,
+        true
+    ),
+    command!(
+        "grant",
+        "[用户id] viewer|uploader|admin|owner（或回复用户消息）",
+        "授予用户权限",
+        Admin,
+        true
+    ),
+    command!(
+        "revoke",
+        "[用户id]（或回复用户消息）",
+        "收回用户权限",
+        Admin,
+        true
+    ),
+    command!("log", "[条数]", "查看最近的管理操作记录", Admin, false),
+];
+
+/// 根据名称查找命令定义
+pub fn find_command(name: &str) -> Option<&'static CommandDef> {
+    COMMANDS.iter().find(|c| c.name == name)
+}
+
+/// 生成指定权限可以使用的命令列表
+pub fn help_text(role: Role) -> String {
+    COMMANDS
+        .iter()
+        .filter(|c| c.role <= role)
+        .map(|c| format!("{} - {}", c.usage(), c.description))
+        .collect::<Vec<_>>()
+        .join("\n")
 }
 
 impl RuaCommand {
     /// 将消息解析为命令
-    pub async fn parse(bot: Bot, message: &Message, bot_id: &str) -> Result<Self, CommandError> {
+    pub async fn parse(ctx: &AppContext, message: &Message) -> Result<Self, CommandError> {
         use CommandError::*;
 
+        let bot_id = ctx.config.telegram.bot_id.as_str();
         let text = message.text().unwrap_or("");
 
         if !text.starts_with('/') {
@@ -95,79 +246,125 @@ impl RuaCommand {
 
         debug!("收到命令：/{} {}", cmd, args);
 
-        let (is_admin, trusted) = check_is_channel_admin(bot, message).await;
+        // 先检查权限再解析参数，没有权限时不给出用法提示
+        let mention = bot_name == bot_id;
+        let def = match find_command(cmd) {
+            Some(def) if get_role(ctx, message).await >= def.role => def,
+            Some(def) => return Err(Denied { def, mention }),
+            None if mention => return Err(WrongCommand(String::new())),
+            None => return Err(NotACommand),
+        };
 
-        match (cmd, is_admin, trusted) {
-            ("ping", _, _) => Ok(Self::Ping),
-            ("full", _, true) => {
-                let arg = get_input_gallery(message, args);
-                match arg.is_empty() {
-                    false => Ok(Self::Full(arg)),
-                    true => Err(WrongCommand("用法：/full [回复|画廊地址|消息地址]...")),
-                }
-            }
-            ("uptag", _, true) => {
-                let arg = get_input_gallery(message, args);
-                match arg.is_empty() {
-                    false => Ok(Self::UpdateTag(arg)),
-                    true => Err(WrongCommand("用法：/uptag [回复|画廊地址|消息地址]...")),
-                }
-            }
-            ("reupload", true, _) => {
-                let reply_to = message.reply_to_gallery();
-                match reply_to {
-                    Some(g) => Ok(Self::ReUpload(InputGallery::Gallery(g))),
-                    None => Err(WrongCommand(
-                        "用法：请回复一个需要替换的画廊，并附上新画廊地址",
-                    )),
-                }
-            }
-            ("delete", true, _) => {
-                if message.reply_to_gallery().is_none() {
-                    return Err(WrongCommand("用法：请回复一个需要删除的画廊"));
-                }
-                Ok(Self::Delete)
+        Self::parse_args(ctx, def.name, args, message)
+            .ok_or_else(|| WrongCommand(format!("用法：{}", def.usage())))
+    }
+
+    /// 解析命令参数，参数错误时返回 None
+    fn parse_args(ctx: &AppContext, cmd: &str, args: &str, message: &Message) -> Option<Self> {
+        let non_empty = |v: Vec<InputGallery>| (!v.is_empty()).then_some(v);
+        let ret = match cmd {
+            "ping" => Self::Ping,
+            "help" => Self::Help,
+            "full" => Self::Full(non_empty(get_input_gallery(ctx, message, args))?),
+            "uptag" => Self::UpdateTag(non_empty(get_input_gallery(ctx, message, args))?),
+            "reupload" => Self::ReUpload(InputGallery::Gallery(message.reply_to_gallery(ctx)?)),
+            "delete" => {
+                message.reply_to_gallery(ctx)?;
+                Self::Delete
             }
-            ("real_delete", true, _) => {
-                if message.reply_to_gallery().is_none() {
-                    return Err(WrongCommand("用法：请回复一个需要彻底删除的画廊"));
-                }
-                Ok(Self::RealDelete)
+            "real_delete" => {
+                message.reply_to_gallery(ctx)?;
+                Self::RealDelete
             }
-            ("upload", _, true) => {
+            "undelete" => Self::Undelete(non_empty(get_input_gallery(ctx, message, args))?),
+            "upload" => {
                 let urls = get_exhentai_urls(message.text().unwrap_or_default());
-                if urls.is_empty() {
-                    Err(WrongCommand("用法：/upload 画廊地址..."))
-                } else {
-                    Ok(Self::Upload(urls))
-                }
+                Self::Upload((!urls.is_empty()).then_some(urls)?)
             }
-            ("best", _, _) => match parse_command_best(args) {
-                Some(mut v) => {
-                    v[0] = v[0].min(3650);
-                    v[1] = v[1].min(3650);
-                    Ok(RuaCommand::Best(v))
-                }
-                _ => Err(WrongCommand("用法：/best 起始时间 终止时间")),
+            "queue" => Self::Queue,
+            "log" => match args.trim() {
+                "" => Self::Log(10),
+                n => Self::Log(n.parse::<i64>().ok().filter(|&n| n > 0)?.min(50)),
             },
-            ("query", _, _) => {
-                let arg = get_input_gallery(message, args);
-                match arg.is_empty() {
-                    false => Ok(Self::Query(arg)),
-                    true => Err(WrongCommand("用法：/query [回复|画廊地址|消息地址]...")),
-                }
+            "grant" => {
+                let mut args = args.split_whitespace().collect::<Vec<_>>();
+                let role = args.pop()?.parse::<Role>().ok()?;
+                Self::Grant(parse_user_id(message, &args)?, role)
             }
-            _ => {
-                if bot_name == bot_id {
-                    Err(WrongCommand(""))
-                } else {
-                    Err(NotACommand)
-                }
+            "revoke" => {
+                let args = args.split_whitespace().collect::<Vec<_>>();
+                Self::Revoke(parse_user_id(message, &args)?)
+            }
+            "best" => {
+                let [from, to] = parse_command_best(args)?;
+                Self::Best([from.min(3650), to.min(3650)])
             }
+            "search" => match args.trim() {
+                "" => return None,
+                keywords => Self::Search(keywords.to_owned()),
+            },
+            "query" => Self::Query(non_empty(get_input_gallery(ctx, message, args))?),
+            _ => return None,
+        };
+        Some(ret)
+    }
+
+    /// 命令名称
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Upload(_) => "upload",
+            Self::Query(_) => "query",
+            Self::Ping => "ping",
+            Self::Help => "help",
+            Self::Delete => "delete",
+            Self::RealDelete => "real_delete",
+            Self::Undelete(_) => "undelete",
+            Self::Best(_) => "best",
+            Self::Full(_) => "full",
+            Self::ReUpload(_) => "reupload",
+            Self::UpdateTag(_) => "uptag",
+            Self::Queue => "queue",
+            Self::Search(_) => "search",
+            Self::Grant(..) => "grant",
+            Self::Revoke(_) => "revoke",
+            Self::Log(_) => "log",
+        }
+    }
+
+    /// 命令定义
+    pub fn def(&self) -> &'static CommandDef {
+        find_command(self.name()).expect("命令没有定义")
+    }
+
+    /// 是否为需要记录到操作日志的管理操作
+    pub fn is_audited(&self) -> bool {
+        self.def().audit
+    }
+
+    /// 命令所操作的画廊，只有一个画廊时返回其 id
+    pub fn target(&self, ctx: &AppContext, message: &Message) -> Option<i32> {
+        let galleries = match self {
+            Self::Full(v) | Self::UpdateTag(v) | Self::Undelete(v) => v.as_slice(),
+            Self::ReUpload(g) => std::slice::from_ref(g),
+            Self::Delete | Self::RealDelete => return message.reply_to_gallery(ctx).map(|g| g.id),
+            _ => return None,
+        };
+        match galleries {
+            [InputGallery::Gallery(g)] => Some(g.id),
+            _ => None,
         }
     }
 }
 
+/// 从参数或被回复的消息中获取用户 id
+fn parse_user_id(message: &Message, args: &[&str]) -> Option<u64> {
+    match args {
+        [] => message.reply_to_user().map(|u| u.id.0),
+        [id] => id.parse().ok(),
+        _ => None,
+    }
+}
+
 /// 将字符串解析为三个数字
 fn parse_command_best(input: &str) -> Option<[i64; 2]> {
     let v = input
@@ -189,20 +386,121 @@ fn get_exhentai_urls(s: &str) -> Vec<String> {
         .collect::<Vec<_>>()
 }
 
-fn get_input_gallery(message: &Message, s: &str) -> Vec<InputGallery> {
-    let i1 = MESSAGE_URL.captures_iter(s).filter_map(|c| {
-        c.get(1)
-            .and_then(|s| s.as_str().parse::<i32>().ok())
-            .and_then(|n| DB.query_gallery(n).ok())
+fn get_input_gallery(ctx: &AppContext, message: &Message, s: &str) -> Vec<InputGallery> {
+    let i1 = ctx.config.channels().into_iter().flat_map(|channel| {
+        message_url_regex(channel)
+            .captures_iter(s)
+            .filter_map(|c| c.get(1)?.as_str().parse::<i32>().ok())
+            .filter_map(|n| {
+                ctx.db
+                    .query_gallery_by_message(n, |g| ctx.config.channel_id(&g.profile) == channel)
+                    .ok()
+            })
             .map(InputGallery::Gallery)
+            .collect::<Vec<_>>()
     });
     let i2 = EXHENTAI_URL.captures_iter(s).filter_map(|c| {
         c.get(0)
             .map(|s| InputGallery::ExHentaiUrl(s.as_str().to_owned()))
     });
     let mut ret = i1.chain(i2).collect::<Vec<_>>();
-    if let (true, Some(g)) = (ret.is_empty(), message.reply_to_gallery()) {
+    if let (true, Some(g)) = (ret.is_empty(), message.reply_to_gallery(ctx)) {
         ret.push(InputGallery::Gallery(g));
     }
     ret
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{insert_test_gallery, mock_exhentai, test_context};
+    use serde_json::json;
+
+    #[test]
+    fn test_commands() {
+        let readme = include_str!("../../README.md");
+        for (i, def) in COMMANDS.iter().enumerate() {
+            assert!(COMMANDS[..i].iter().all(|c| c.name != def.name));
+            let line = format!("{} - {}", def.usage(), def.description);
+            assert!(readme.contains(&line), "README 中缺少命令：{}", line);
+        }
+        assert!(help_text(Role::Viewer).starts_with("/ping - 测试存活\n"));
+        assert!(!help_text(Role::Uploader).contains("/delete"));
+        assert_eq!(help_text(Role::Owner).lines().count(), COMMANDS.len());
+    }
+
+    /// 所有者在讨论组中回复频道消息 100 转发过来的画廊
+    fn reply_message(text: &str) -> Message {
+        let group = json!({"id": -1002, "type": "supergroup", "title": "test"});
+        serde_json::from_value(json!({
+            "message_id": 2,
+            "date": 0,
+            "chat": group,
+            "from": {"id": 1, "is_bot": false, "first_name": "owner"},
+            "text": text,
+            "reply_to_message": {
+                "message_id": 1,
+                "date": 0,
+                "chat": group,
+                "from": {"id": 777000, "is_bot": false, "first_name": "Telegram"},
+                "forward_from_chat": {
+                    "id": -1001,
+                    "type": "channel",
+                    "title": "exloli",
+                    "username": "exloli_test"
+                },
+                "forward_from_message_id": 100,
+                "forward_date": 0,
+                "text": "原始地址",
+            },
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_parse_all_commands() {
+        let (server, ctx) = test_context();
+        mock_exhentai(&server);
+        insert_test_gallery(&ctx, &server, 100).await;
+
+        // 每个命令的合法参数，新增命令时需要在这里补充
+        let samples = [
+            ("ping", ""),
+            ("help", ""),
+            ("query", ""),
+            ("best", "1 7"),
+            ("search", "关键词"),
+            ("upload", "https://exhentai.org/g/1000/abcdef1234/"),
+            ("full", ""),
+            ("uptag", "https://exhentai.org/g/1000/abcdef1234/"),
+            ("queue", ""),
+            ("delete", ""),
+            ("real_delete", ""),
+            ("undelete", "https://exhentai.org/g/1000/abcdef1234/"),
+            ("reupload", ""),
+            ("grant", "4242 uploader"),
+            ("revoke", ""),
+            ("log", "5"),
+        ];
+        assert_eq!(samples.len(), COMMANDS.len());
+        let mut variants = std::collections::HashSet::new();
+        for def in COMMANDS {
+            let args = samples
+                .iter()
+                .find(|(name, _)| *name == def.name)
+                .map(|(_, args)| *args)
+                .unwrap_or_else(|| panic!("缺少命令的测试参数：/{}", def.name));
+            let text = format!("/{} {}", def.name, args);
+            // 解析出的命令必须能找回同一个命令定义
+            let cmd = match RuaCommand::parse(&ctx, &reply_message(text.trim_end())).await {
+                Ok(cmd) => cmd,
+                Err(_) => panic!("解析命令失败：{}", text),
+            };
+            assert_eq!(cmd.name(), def.name);
+            assert!(std::ptr::eq(cmd.def(), def));
+            variants.insert(std::mem::discriminant(&cmd));
+        }
+        // 每个命令对应不同的变体
+        assert_eq!(variants.len(), COMMANDS.len());
+    }
+}
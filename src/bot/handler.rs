@@ -1,15 +1,24 @@
 use super::utils::*;
 use crate::bot::command::*;
-use crate::database::Gallery;
-use crate::utils::get_message_url;
-use crate::*;
+use crate::context::AppContext;
+use crate::database::{Gallery, GalleryStatus, Role, SearchQuery};
+use crate::exloli::ExLoli;
+use crate::queue::JobKind;
 use anyhow::{Context, Result};
 use chrono::{Duration, Utc};
+use futures::executor::block_on;
 use futures::{FutureExt, TryFutureExt};
+use once_cell::sync::Lazy;
 use std::convert::TryInto;
 use std::future::Future;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time;
+use teloxide::prelude::*;
 use teloxide::types::*;
 use teloxide::{ApiError, RequestError};
+use tokio::time::sleep;
+use v_htmlescape::escape;
 
 macro_rules! reply_to {
     ($b:expr, $m:expr, $t:expr) => {
@@ -20,15 +29,19 @@ macro_rules! reply_to {
 static LIMIT: Lazy<RateLimiter<u64>> =
     Lazy::new(|| RateLimiter::new(std::time::Duration::from_secs(60), 10));
 //
-async fn on_new_gallery(bot: Bot, message: &Message) -> Result<()> {
+async fn on_new_gallery(
+    ctx: &AppContext,
+    bot: Bot,
+    message: &Message,
+    gallery: &Gallery,
+) -> Result<()> {
     info!("频道消息更新，发送投票");
     // 辣鸡 tg 安卓客户端在置顶消息过多时似乎在进群时会卡住
     bot.unpin_chat_message(message.chat.id)
         .message_id(message.id)
         .await?;
-    let message_id = message.forward_from_message_id().unwrap();
-    let poll_id = DB.query_poll_id(message_id)?.parse::<i32>()?;
-    let votes = Vote::new(DB.query_vote(poll_id)?);
+    let poll_id = gallery.poll_id.parse::<i32>()?;
+    let votes = Vote::new(ctx.db.query_vote(poll_id)?);
     let options = poll_keyboard(poll_id, &votes);
     reply_to!(bot, message, votes.info())
         .reply_markup(options)
@@ -36,28 +49,146 @@ async fn on_new_gallery(bot: Bot, message: &Message) -> Result<()> {
     Ok(())
 }
 //
-async fn cmd_delete(bot: Bot, message: &Message, real: bool) -> Result<Message> {
+async fn cmd_delete(ctx: &AppContext, bot: Bot, message: &Message, real: bool) -> Result<Message> {
     info!("执行命令: delete_{} {:?}", real, message.id);
     let to_del = message.reply_to_message().context("找不到回复")?;
     let channel = to_del.forward_from_chat().context("获取来源对话失败")?;
     let msg_id = to_del
         .forward_from_message_id()
         .context("获取转发来源失败")?;
+    let gallery = to_del.forward_gallery(ctx).context("找不到画廊")?;
     bot.delete_message(to_del.chat.id, to_del.id).await?;
     bot.delete_message(channel.id, MessageId(msg_id)).await?;
-    let gallery = DB.query_gallery(msg_id)?;
+    let user_id = message.from().map(|u| u.id.0 as i64).unwrap_or_default();
     match real {
-        false => DB.delete_gallery(msg_id)?,
-        _ => DB.real_delete_gallery(msg_id)?,
+        false => ctx.db.delete_gallery(gallery.id, user_id)?,
+        _ => ctx.db.real_delete_gallery(gallery.id)?,
     }
-    let text = format!("画廊 {} 已删除", gallery.get_url());
+    let text = format!("画廊 {} 已删除", gallery.get_url(ctx.config.host()));
     Ok(bot.send_message(message.chat.id, text).await?)
 }
 
+/// 恢复被删除的画廊，并作为新消息重新发布
+async fn cmd_undelete(
+    ctx: &AppContext,
+    bot: Bot,
+    message: &Message,
+    galleries: &[InputGallery],
+    failures: &mut Vec<String>,
+) -> Result<Message> {
+    info!("执行命令: undelete {:?}", galleries);
+    let mut text = "已恢复并加入上传队列：".to_owned();
+    for (idx, gallery) in galleries.iter().enumerate() {
+        match gallery.to_gallery(ctx).await {
+            Ok(g) if g.status() == GalleryStatus::DeletedByAdmin => {
+                // 旧消息已从频道删除，保持删除状态，由重新发布的新消息取代
+                let url = g.get_url(ctx.config.host());
+                let id = ctx.db.insert_job(JobKind::Republish, &url, &g.profile)?;
+                text.push_str(&format!("\n#{} {}", id, url));
+            }
+            Ok(g) => {
+                let line = format!("{} - 未被删除", g.get_url(ctx.config.host()));
+                text.push_str(&format!("\n{}", line));
+                failures.push(line);
+            }
+            Err(_) => {
+                let line = format!("第 {} 本 - 无上传记录", idx + 1);
+                text.push_str(&format!("\n{}", line));
+                failures.push(line);
+            }
+        }
+    }
+    Ok(reply_to!(bot, message, text)
+        .disable_web_page_preview(true)
+        .await?)
+}
+
+async fn cmd_grant(
+    ctx: &AppContext,
+    bot: Bot,
+    message: &Message,
+    user_id: u64,
+    role: Role,
+    failures: &mut Vec<String>,
+) -> Result<Message> {
+    info!("执行命令: grant {} {}", user_id, role);
+    let actor = get_role(ctx, message).await;
+    let current = ctx.db.query_role(user_id as i64)?.unwrap_or(Role::Viewer);
+    let text = if !actor.can_manage(role) || !actor.can_manage(current) {
+        failures.push("权限不足".to_owned());
+        "权限不足".to_owned()
+    } else {
+        let granted_by = message.from().map(|u| u.id.0 as i64).unwrap_or_default();
+        ctx.db.grant_role(user_id as i64, role, granted_by)?;
+        format!("已授予用户 {} {} 权限", user_id, role)
+    };
+    Ok(reply_to!(bot, message, text).await?)
+}
+
+async fn cmd_revoke(
+    ctx: &AppContext,
+    bot: Bot,
+    message: &Message,
+    user_id: u64,
+    failures: &mut Vec<String>,
+) -> Result<Message> {
+    info!("执行命令: revoke {}", user_id);
+    let actor = get_role(ctx, message).await;
+    let text = match ctx.db.query_role(user_id as i64)? {
+        None => {
+            let line = format!("用户 {} 没有单独授予的权限", user_id);
+            failures.push(line.clone());
+            line
+        }
+        Some(role) if !actor.can_manage(role) => {
+            failures.push("权限不足".to_owned());
+            "权限不足".to_owned()
+        }
+        Some(role) => {
+            ctx.db.revoke_role(user_id as i64)?;
+            format!("已收回用户 {} 的 {} 权限", user_id, role)
+        }
+    };
+    Ok(reply_to!(bot, message, text).await?)
+}
+
+async fn cmd_log(ctx: &AppContext, bot: Bot, message: &Message, n: i64) -> Result<Message> {
+    info!("执行命令: log {}", n);
+    let logs = ctx.db.query_audit_log(n)?;
+    let text = match logs.is_empty() {
+        true => "没有操作记录".to_owned(),
+        false => logs
+            .iter()
+            .map(|log| {
+                let target = log
+                    .target
+                    .map(|id| format!(" [{}]", id))
+                    .unwrap_or_default();
+                format!(
+                    "#{} {} {} /{}{} {}：{}",
+                    log.id,
+                    log.created_at.format("%Y-%m-%d %H:%M"),
+                    log.actor_id,
+                    log.command,
+                    target,
+                    log.args,
+                    log.result
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n"),
+    };
+    Ok(reply_to!(bot, message, text)
+        .disable_web_page_preview(true)
+        .await?)
+}
+
+/// 依次对每个画廊执行操作，失败的条目会加入 failures
 async fn do_chain_action<T, F, Fut>(
     bot: Bot,
     message: &Message,
     input: &[T],
+    failures: &mut Vec<String>,
     action: F,
 ) -> Result<Message>
 where
@@ -69,15 +200,21 @@ where
     let mut fail_cnt = 0;
     for (idx, entry) in input.iter().enumerate() {
         let message = match action(entry).await {
-            Ok(Some(_)) => format!("\n第 {} 本 - 成功", idx + 1),
-            Ok(None) => format!("\n第 {} 本 - 无上传记录", idx + 1),
+            Ok(Some(_)) => format!("第 {} 本 - 成功", idx + 1),
+            Ok(None) => {
+                let line = format!("第 {} 本 - 无上传记录", idx + 1);
+                failures.push(line.clone());
+                line
+            }
             Err(e) => {
                 let source = e.source().map(|e| e.to_string()).unwrap_or_default();
                 fail_cnt += 1;
-                format!("\n第 {} 本 - 失败：{} => {}", idx + 1, e, source)
+                let line = format!("第 {} 本 - 失败：{} => {}", idx + 1, e, source);
+                failures.push(line.clone());
+                line
             }
         };
-        text.push_str(&message);
+        text.push_str(&format!("\n{}", message));
         reply_message = bot
             .edit_message_text(reply_message.chat.id, reply_message.id, &text)
             .await?;
@@ -96,70 +233,139 @@ where
         .await?)
 }
 
-async fn cmd_upload(bot: Bot, message: &Message, urls: &[String]) -> Result<Message> {
+async fn cmd_upload(
+    ctx: &AppContext,
+    bot: Bot,
+    message: &Message,
+    urls: &[String],
+) -> Result<Message> {
     info!("执行命令: upload {:?}", urls);
-    do_chain_action(bot, message, urls, |url| {
-        let url = url.clone();
-        async move { EXLOLI.upload_gallery_by_url(&url).await.map(Some) }.boxed()
-    })
-    .await
+    let mut text = "已加入上传队列：".to_owned();
+    for url in urls {
+        let id = ctx.db.insert_job(JobKind::Upload, url, "")?;
+        text.push_str(&format!("\n#{} {}", id, url));
+    }
+    Ok(reply_to!(bot, message, text)
+        .disable_web_page_preview(true)
+        .await?)
 }
 
-async fn cmd_reupload(bot: Bot, message: &Message, old_gallery: &InputGallery) -> Result<Message> {
-    info!("执行命令: reupload {:?}", old_gallery);
-    let mut text = "收到命令，执行中……".to_owned();
-    let reply_message = reply_to!(bot, message, &text).await?;
-    let gallery = old_gallery.to_gallery().await?;
-    EXLOLI.update_gallery(&gallery, None, true).await?;
-    text.push_str("\n执行完毕");
-    Ok(bot
-        .edit_message_text(reply_message.chat.id, reply_message.id, text)
-        .parse_mode(ParseMode::Html)
+/// 将已上传过的画廊加入上传队列
+async fn enqueue_galleries(
+    ctx: &AppContext,
+    bot: Bot,
+    message: &Message,
+    galleries: &[InputGallery],
+    kind: JobKind,
+    failures: &mut Vec<String>,
+) -> Result<Message> {
+    let mut text = "已加入上传队列：".to_owned();
+    for (idx, gallery) in galleries.iter().enumerate() {
+        match gallery.to_gallery(ctx).await {
+            Ok(g) => {
+                let id = ctx.db.insert_job(kind, &g.id.to_string(), &g.profile)?;
+                text.push_str(&format!("\n#{} {}", id, g.get_url(ctx.config.host())));
+            }
+            Err(_) => {
+                let line = format!("第 {} 本 - 无上传记录", idx + 1);
+                text.push_str(&format!("\n{}", line));
+                failures.push(line);
+            }
+        }
+    }
+    Ok(reply_to!(bot, message, text)
+        .disable_web_page_preview(true)
         .await?)
 }
 
-async fn cmd_full(bot: Bot, message: &Message, galleries: &[InputGallery]) -> Result<Message> {
-    info!("执行命令: full {:?}", galleries);
-    do_chain_action(bot, message, galleries, |gallery| {
-        let gallery = match block_on(gallery.to_gallery()) {
-            Ok(v) => v,
-            _ => return async { Ok(None) }.boxed(),
-        };
-        async move { EXLOLI.update_gallery(&gallery, None, false).await.map(Some) }.boxed()
-    })
+async fn cmd_reupload(
+    ctx: &AppContext,
+    bot: Bot,
+    message: &Message,
+    old_gallery: &InputGallery,
+    failures: &mut Vec<String>,
+) -> Result<Message> {
+    info!("执行命令: reupload {:?}", old_gallery);
+    enqueue_galleries(
+        ctx,
+        bot,
+        message,
+        std::slice::from_ref(old_gallery),
+        JobKind::ReUpload,
+        failures,
+    )
     .await
 }
 
+async fn cmd_full(
+    ctx: &AppContext,
+    bot: Bot,
+    message: &Message,
+    galleries: &[InputGallery],
+    failures: &mut Vec<String>,
+) -> Result<Message> {
+    info!("执行命令: full {:?}", galleries);
+    enqueue_galleries(ctx, bot, message, galleries, JobKind::Full, failures).await
+}
+
+async fn cmd_queue(ctx: &AppContext, bot: Bot, message: &Message) -> Result<Message> {
+    info!("执行命令: queue");
+    let jobs = ctx.db.query_jobs(20)?;
+    let text = match jobs.is_empty() {
+        true => "队列为空".to_owned(),
+        false => jobs
+            .iter()
+            .map(|job| {
+                let mut line = format!(
+                    "#{} [{}] {} {}，尝试 {} 次",
+                    job.id, job.state, job.kind, job.target, job.attempts
+                );
+                if let Some(e) = &job.last_error {
+                    line.push_str(&format!("：{}", e));
+                }
+                line
+            })
+            .collect::<Vec<_>>()
+            .join("\n"),
+    };
+    Ok(reply_to!(bot, message, text)
+        .disable_web_page_preview(true)
+        .await?)
+}
+
 async fn cmd_update_tag(
+    ctx: &AppContext,
+    exloli: &ExLoli,
     bot: Bot,
     message: &Message,
     galleries: &[InputGallery],
+    failures: &mut Vec<String>,
 ) -> Result<Message> {
     info!("执行命令: uptag {:?}", galleries);
-    do_chain_action(bot, message, galleries, |gallery| {
+    do_chain_action(bot, message, galleries, failures, |gallery| {
         // TODO: 为啥要 block
-        let gallery = match block_on(gallery.to_gallery()) {
+        let gallery = match block_on(gallery.to_gallery(ctx)) {
             Ok(v) => v,
             _ => return async { Ok(None) }.boxed(),
         };
-        async move { EXLOLI.update_tag(&gallery, None).await.map(Some) }.boxed()
+        async move { exloli.update_tag(&gallery, None).await.map(Some) }.boxed()
     })
     .await
 }
 
-fn query_best_text(from: i64, to: i64, offset: i64) -> Result<String> {
+fn query_best_text(ctx: &AppContext, from: i64, to: i64, offset: i64) -> Result<String> {
     let (from_d, to_d) = (
         Utc::today().naive_utc() - Duration::days(from),
         Utc::today().naive_utc() - Duration::days(to),
     );
-    let galleries = DB.query_best(from_d, to_d, offset)?;
+    let galleries = ctx.db.query_best(from_d, to_d, offset)?;
     let list = galleries
         .iter()
         .map(|g| {
             format!(
                 r#"<code>{:.2}</code> - <a href="{}">{}</a>"#,
                 g.score * 100.,
-                get_message_url(g.message_id),
+                g.message_url(&ctx.config),
                 g.title
             )
         })
@@ -170,9 +376,15 @@ fn query_best_text(from: i64, to: i64, offset: i64) -> Result<String> {
     Ok(text)
 }
 
-async fn cmd_best(bot: Bot, message: &Message, from: i64, to: i64) -> Result<Message> {
+async fn cmd_best(
+    ctx: &AppContext,
+    bot: Bot,
+    message: &Message,
+    from: i64,
+    to: i64,
+) -> Result<Message> {
     info!("执行命令: best {} {}", from, to);
-    let text = query_best_text(from, to, 1)?;
+    let text = query_best_text(ctx, from, to, 1)?;
     let reply_markup = query_best_keyboard(from, to, 1);
     Ok(reply_to!(bot, message, text)
         .reply_markup(reply_markup)
@@ -181,17 +393,22 @@ async fn cmd_best(bot: Bot, message: &Message, from: i64, to: i64) -> Result<Mes
 }
 
 /// 查询画廊，若失败则返回失败消息，成功则直接发送
-async fn cmd_query(bot: Bot, message: &Message, galleries: &[InputGallery]) -> Result<Message> {
+async fn cmd_query(
+    ctx: &AppContext,
+    bot: Bot,
+    message: &Message,
+    galleries: &[InputGallery],
+) -> Result<Message> {
     info!("执行命令: query {:?}", galleries);
     let text = match galleries.len() {
         1 => galleries[0]
-            .to_gallery()
+            .to_gallery(ctx)
             .await
-            .and_then(|g| cmd_query_rank(&g))
+            .and_then(|g| Ok(cmd_query_rank(ctx, &g)? + &version_chain_text(ctx, &g)?))
             .unwrap_or_else(|_| "未找到！".to_owned()),
         _ => futures::future::join_all(galleries.iter().map(|g| {
-            g.to_gallery()
-                .and_then(|g| async move { Ok(get_message_url(g.message_id)) })
+            g.to_gallery(ctx)
+                .and_then(|g| async move { Ok(g.message_url(&ctx.config)) })
                 .unwrap_or_else(|_| "未找到！".to_owned())
         }))
         .await
@@ -202,108 +419,284 @@ async fn cmd_query(bot: Bot, message: &Message, galleries: &[InputGallery]) -> R
         .await?)
 }
 
-fn cmd_query_rank(gallery: &Gallery) -> Result<String> {
-    let rank = DB.get_rank(gallery.score)?;
-    Ok(format!(
+async fn cmd_search(
+    ctx: &AppContext,
+    bot: Bot,
+    message: &Message,
+    keywords: &str,
+) -> Result<Message> {
+    info!("执行命令: search {}", keywords);
+    let galleries = ctx
+        .db
+        .search_gallery(&SearchQuery::parse(keywords), 10, 0)?;
+    let text = match galleries.is_empty() {
+        true => "未找到！".to_owned(),
+        false => galleries
+            .iter()
+            .map(|g| {
+                format!(
+                    r#"<a href="{}">{}</a>"#,
+                    g.message_url(&ctx.config),
+                    escape(&g.title)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n"),
+    };
+    Ok(reply_to!(bot, message, text)
+        .parse_mode(ParseMode::Html)
+        .disable_web_page_preview(true)
+        .await?)
+}
+
+fn cmd_query_rank(ctx: &AppContext, gallery: &Gallery) -> Result<String> {
+    let rank = ctx.db.get_rank(gallery.score)?;
+    let mut text = format!(
         "标题：{}\n消息：{}\n地址：{}\n评分：{:.2}\n位置：{:.2}%\n上传日期：{}",
         gallery.title,
-        get_message_url(gallery.message_id),
-        gallery.get_url(),
+        gallery.message_url(&ctx.config),
+        gallery.get_url(ctx.config.host()),
         gallery.score * 100.,
         rank * 100.,
         gallery.publish_date,
-    ))
+    );
+    if gallery.status() != GalleryStatus::Active {
+        text.push_str(&format!("\n状态：{}", gallery.status));
+    }
+    Ok(text)
 }
 
-/// 判断是否是新本子的发布信息
-fn is_new_gallery(message: &Message) -> bool {
-    // 判断是否是由官方 bot 转发的
-    let user = match message.from() {
-        Some(v) => v,
-        _ => return false,
-    };
-    if user.id.0 != 777000 {
-        return false;
+/// 画廊的版本更新记录，没有记录时返回空字符串
+fn version_chain_text(ctx: &AppContext, gallery: &Gallery) -> Result<String> {
+    let chain = ctx.db.query_version_chain(gallery.gallery_id)?;
+    if chain.is_empty() {
+        return Ok(String::new());
     }
-    // 判断是否是新本子的发布信息
-    message
-        .text()
-        .map(|s| s.contains("原始地址"))
-        .unwrap_or(false)
+    let mut text = "\n版本记录：".to_owned();
+    for v in &chain {
+        text.push_str(&format!(
+            "\n{} → {}（{}，{}）",
+            v.parent_url(ctx.config.host()),
+            v.get_url(ctx.config.host()),
+            v.action,
+            v.created_at.date()
+        ));
+    }
+    Ok(text)
 }
 
-pub async fn message_handler(message: Message, bot: Bot) -> Result<()> {
-    use RuaCommand::*;
-
-    trace!("{:#?}", message);
-
-    // 如果是新本子上传的消息，则回复投票并取消置顶
-    if is_new_gallery(&message) && message.is_from_my_group() {
-        on_new_gallery(bot.clone(), &message)
-            .await
-            .log_on_error()
-            .await;
+/// 判断是否是新本子的发布信息，即频道中画廊消息自动转发到讨论组的消息，返回对应的画廊
+///
+/// 以图片或媒体组发布时转发的消息没有文本，因此根据转发来源的消息 id 查询
+fn new_gallery(ctx: &AppContext, message: &Message) -> Option<Gallery> {
+    if !message.is_auto_forward() || !message.is_from_my_group(&ctx.config) {
+        return None;
     }
+    message.forward_gallery(ctx)
+}
 
-    // 其他命令
-    let mut to_delete = vec![message.id];
-    let cmd = RuaCommand::parse(bot.clone(), &message, &CONFIG.telegram.bot_id).await;
-    match &cmd {
+/// 执行命令，需要定时删除的消息会加入 to_delete，部分失败的画廊等操作对象会加入 failures
+async fn execute_command(
+    ctx: &AppContext,
+    exloli: &ExLoli,
+    bot: Bot,
+    message: &Message,
+    cmd: &Result<RuaCommand, CommandError>,
+    to_delete: &mut Vec<MessageId>,
+    failures: &mut Vec<String>,
+) -> Result<()> {
+    use RuaCommand::*;
+
+    match cmd {
         Err(CommandError::WrongCommand(help)) => {
             warn!("错误的命令：{}", help);
             if !help.is_empty() {
-                let msg = reply_to!(bot, message, *help).await?;
+                let msg = reply_to!(bot, message, help.as_str()).await?;
                 to_delete.push(msg.id);
             } else {
                 bot.delete_message(message.chat.id, message.id).await?;
             }
         }
+        // 没有权限时和未知命令一样处理
+        Err(CommandError::Denied { def, mention }) => {
+            warn!("没有权限的命令：/{}", def.name);
+            if *mention {
+                bot.delete_message(message.chat.id, message.id).await?;
+            }
+        }
         Ok(Ping) => {
             info!("执行命令：ping");
             let msg = reply_to!(bot, message, "pong").await?;
             to_delete.push(msg.id);
         }
+        Ok(Help) => {
+            info!("执行命令：help");
+            let role = get_role(ctx, message).await;
+            let msg = reply_to!(bot, message, help_text(role)).await?;
+            to_delete.push(msg.id);
+        }
         Ok(Full(g)) => {
-            to_delete.push(cmd_full(bot.clone(), &message, g).await?.id);
+            to_delete.push(cmd_full(ctx, bot.clone(), &message, g, failures).await?.id);
         }
         Ok(Delete) => {
-            to_delete.push(cmd_delete(bot.clone(), &message, false).await?.id);
+            to_delete.push(cmd_delete(ctx, bot.clone(), &message, false).await?.id);
         }
         Ok(RealDelete) => {
-            to_delete.push(cmd_delete(bot.clone(), &message, true).await?.id);
+            to_delete.push(cmd_delete(ctx, bot.clone(), &message, true).await?.id);
+        }
+        Ok(Undelete(g)) => {
+            to_delete.push(
+                cmd_undelete(ctx, bot.clone(), &message, g, failures)
+                    .await?
+                    .id,
+            );
         }
         Ok(Upload(urls)) => {
-            to_delete.push(cmd_upload(bot.clone(), &message, urls).await?.id);
+            to_delete.push(cmd_upload(ctx, bot.clone(), &message, urls).await?.id);
         }
         Ok(UpdateTag(g)) => {
-            to_delete.push(cmd_update_tag(bot.clone(), &message, g).await?.id);
+            to_delete.push(
+                cmd_update_tag(ctx, exloli, bot.clone(), &message, g, failures)
+                    .await?
+                    .id,
+            );
         }
         Ok(Query(gs)) => {
-            cmd_query(bot.clone(), &message, gs).await?;
+            cmd_query(ctx, bot.clone(), &message, gs).await?;
         }
         Ok(Best([from, to])) => {
-            to_delete.push(cmd_best(bot.clone(), &message, *from, *to).await?.id);
+            to_delete.push(cmd_best(ctx, bot.clone(), &message, *from, *to).await?.id);
         }
         Ok(ReUpload(g)) => {
-            to_delete.push(cmd_reupload(bot.clone(), &message, g).await?.id);
+            to_delete.push(
+                cmd_reupload(ctx, bot.clone(), &message, g, failures)
+                    .await?
+                    .id,
+            );
+        }
+        Ok(Queue) => {
+            to_delete.push(cmd_queue(ctx, bot.clone(), &message).await?.id);
+        }
+        Ok(Search(keywords)) => {
+            cmd_search(ctx, bot.clone(), &message, keywords).await?;
+        }
+        Ok(Grant(user_id, role)) => {
+            to_delete.push(
+                cmd_grant(ctx, bot.clone(), &message, *user_id, *role, failures)
+                    .await?
+                    .id,
+            );
         }
-        // 收到无效命令则立即返回
-        Err(CommandError::NotACommand) => return Ok(()),
+        Ok(Revoke(user_id)) => {
+            to_delete.push(
+                cmd_revoke(ctx, bot.clone(), &message, *user_id, failures)
+                    .await?
+                    .id,
+            );
+        }
+        Ok(Log(n)) => {
+            to_delete.push(cmd_log(ctx, bot.clone(), &message, *n).await?.id);
+        }
+        Err(CommandError::NotACommand) => (),
+    }
+    Ok(())
+}
+
+/// 操作日志中的执行结果，部分操作对象失败时记录失败的条目
+fn audit_result(result: &Result<()>, failures: &[String]) -> String {
+    match (result, failures) {
+        (Err(e), _) => e.to_string(),
+        (Ok(_), []) => "ok".to_owned(),
+        (Ok(_), failures) => format!("失败 {} 个：{}", failures.len(), failures.join("；")),
+    }
+}
+
+/// 记录管理操作，记录失败时不影响命令的执行结果
+fn audit(ctx: &AppContext, message: &Message, command: &str, target: Option<i32>, result: &str) {
+    let actor = message.from().map(|u| u.id.0 as i64).unwrap_or_default();
+    let args = message
+        .text()
+        .and_then(|s| s.split_once(|c: char| c.is_whitespace()))
+        .map(|(_, args)| args.trim())
+        .unwrap_or_default();
+    if let Err(e) = ctx
+        .db
+        .insert_audit_log(actor, command, target, args, result)
+    {
+        error!("记录操作日志失败：{}", e);
+    }
+}
+
+pub async fn message_handler(
+    message: Message,
+    bot: Bot,
+    ctx: Arc<AppContext>,
+    exloli: Arc<ExLoli>,
+) -> Result<()> {
+    use RuaCommand::*;
+
+    trace!("{:#?}", message);
+
+    // 如果是新本子上传的消息，则回复投票并取消置顶
+    if let Some(gallery) = new_gallery(&ctx, &message) {
+        on_new_gallery(&ctx, bot.clone(), &message, &gallery)
+            .await
+            .log_on_error()
+            .await;
     }
 
-    // 对 query 和 best 命令的调用保留
-    if matches!(cmd, Ok(Query(_))) {
+    // 其他命令
+    let mut to_delete = vec![message.id];
+    let cmd = RuaCommand::parse(&ctx, &message).await;
+    // 执行前记录操作对象，删除画廊后就查询不到了
+    let target = cmd
+        .as_ref()
+        .ok()
+        .filter(|c| c.is_audited())
+        .and_then(|c| c.target(&ctx, &message));
+    let mut failures = vec![];
+    let result = execute_command(
+        &ctx,
+        &exloli,
+        bot.clone(),
+        &message,
+        &cmd,
+        &mut to_delete,
+        &mut failures,
+    )
+    .await;
+    match &cmd {
+        Ok(c) if c.is_audited() => {
+            let result = audit_result(&result, &failures);
+            audit(&ctx, &message, c.name(), target, &result);
+        }
+        // 没有权限的尝试也要记录
+        Err(CommandError::Denied { def, .. }) if def.audit => {
+            audit(&ctx, &message, def.name, None, "权限不足");
+        }
+        _ => (),
+    }
+    result?;
+    if matches!(
+        cmd,
+        Err(CommandError::NotACommand) | Err(CommandError::Denied { mention: false, .. })
+    ) {
+        return Ok(());
+    }
+
+    // 对 query 和 search 命令的调用保留
+    if matches!(cmd, Ok(Query(_)) | Ok(Search(_))) {
         to_delete.clear();
     }
     // 没有直接回复画廊的 upload full update_tag 则保留
     if matches!(cmd, Ok(Upload(_)) | Ok(Full(_)) | Ok(UpdateTag(_)))
-        && message.reply_to_gallery().is_none()
+        && message.reply_to_gallery(ctx).is_none()
     {
         to_delete.clear();
     }
 
     // 定时删除群组内的 BOT 消息
-    if !to_delete.is_empty() && message.is_from_my_group() {
+    if !to_delete.is_empty() && message.is_from_my_group(&ctx.config) {
         let chat_id = message.chat.id;
         tokio::spawn(async move {
             sleep(time::Duration::from_secs(300)).await;
@@ -316,32 +709,67 @@ pub async fn message_handler(message: Message, bot: Bot) -> Result<()> {
     Ok(())
 }
 
-pub async fn poll_handler(poll: Poll, _bot: Bot) -> Result<()> {
+pub async fn poll_handler(poll: Poll, ctx: Arc<AppContext>) -> Result<()> {
     let options = poll.options;
     let votes = options.iter().map(|s| s.voter_count).collect::<Vec<_>>();
     let score = Vote::wilson_score(&votes);
     let votes = serde_json::to_string(&votes)?;
     info!("收到投票：{} -> {}", poll.id, score);
-    DB.update_score(&poll.id, score, votes)
+    ctx.db.update_score(&poll.id, score, votes)
+}
+
+/// 行内查询每页的结果数量
+const INLINE_PAGE_SIZE: i64 = 20;
+
+/// 生成行内查询的结果，附带跳转到频道消息的按钮
+fn inline_gallery(ctx: &AppContext, gallery: &Gallery) -> Result<InlineQueryResult> {
+    let content = cmd_query_rank(ctx, gallery)?;
+    let button = InlineKeyboardButton::url("查看消息", gallery.message_url(&ctx.config).parse()?);
+    let article = inline_article(&gallery.title, content)
+        .description(format!(
+            "评分：{:.2} 上传日期：{}",
+            gallery.score * 100.,
+            gallery.publish_date
+        ))
+        .reply_markup(InlineKeyboardMarkup::new(vec![vec![button]]));
+    Ok(InlineQueryResult::Article(article))
 }
 
-pub async fn inline_handler(query: InlineQuery, bot: Bot) -> Result<()> {
+pub async fn inline_handler(query: InlineQuery, bot: Bot, ctx: Arc<AppContext>) -> Result<()> {
     let text = query.query.trim();
-    info!("行内查询：{}", text);
+    info!("行内查询：{} {}", text, query.offset);
+    let offset = query.offset.parse::<i64>().unwrap_or(0);
     let mut answer = vec![];
+    let mut next_offset = String::new();
     if EXHENTAI_URL.is_match(text) {
-        if let Ok(v) = DB.query_gallery_by_url(&query.query) {
-            let content = cmd_query_rank(&v)?;
-            answer.push(InlineQueryResult::Article(inline_article(v.title, content)));
+        if let Ok(v) = ctx.db.query_gallery_by_url(text) {
+            answer.push(inline_gallery(&ctx, &v)?);
+        }
+    } else {
+        let search = SearchQuery::parse(text);
+        if !search.is_empty() {
+            // 多取一个用于判断是否还有下一页
+            let mut galleries = ctx
+                .db
+                .search_gallery(&search, INLINE_PAGE_SIZE + 1, offset)?;
+            if galleries.len() as i64 > INLINE_PAGE_SIZE {
+                galleries.pop();
+                next_offset = (offset + INLINE_PAGE_SIZE).to_string();
+            }
+            for g in &galleries {
+                answer.push(inline_gallery(&ctx, g)?);
+            }
         }
     }
-    if answer.is_empty() {
+    if answer.is_empty() && offset == 0 {
         answer.push(InlineQueryResult::Article(inline_article(
             "未找到",
             "没有找到",
         )));
     }
-    bot.answer_inline_query(query.id, answer).await?;
+    bot.answer_inline_query(query.id, answer)
+        .next_offset(next_offset)
+        .await?;
     Ok(())
 }
 
@@ -351,7 +779,13 @@ fn split_vec<T: FromStr>(s: &str) -> std::result::Result<Vec<T>, T::Err> {
         .collect::<std::result::Result<Vec<_>, _>>()
 }
 
-async fn callback_change_page(bot: Bot, message: &Message, cmd: &str, data: &str) -> Result<()> {
+async fn callback_change_page(
+    ctx: &AppContext,
+    bot: Bot,
+    message: &Message,
+    cmd: &str,
+    data: &str,
+) -> Result<()> {
     info!("翻页：{:?} {}", message.id, cmd);
     // vec![from, to, offset]
     let data = split_vec::<i64>(data)?;
@@ -366,7 +800,7 @@ async fn callback_change_page(bot: Bot, message: &Message, cmd: &str, data: &str
         "<<" => offset = 1,
         _ => (),
     };
-    let text = query_best_text(from, to, offset)?;
+    let text = query_best_text(ctx, from, to, offset)?;
     let reply = query_best_keyboard(from, to, offset);
     bot.edit_message_text(message.chat.id, message.id, &text)
         .parse_mode(ParseMode::Html)
@@ -375,14 +809,20 @@ async fn callback_change_page(bot: Bot, message: &Message, cmd: &str, data: &str
     Ok(())
 }
 
-async fn callback_poll(bot: Bot, message: &Message, user_id: u64, data: &str) -> Result<()> {
+async fn callback_poll(
+    ctx: &AppContext,
+    bot: Bot,
+    message: &Message,
+    user_id: u64,
+    data: &str,
+) -> Result<()> {
     let data = split_vec::<i32>(data)?;
     let [poll_id, option] = match TryInto::<[i32; 2]>::try_into(data) {
         Ok(v) => v,
         _ => return Ok(()),
     };
-    DB.insert_vote(user_id, poll_id, option)?;
-    let votes = Vote::new(DB.query_vote(poll_id)?);
+    ctx.db.insert_vote(user_id, poll_id, option)?;
+    let votes = Vote::new(ctx.db.query_vote(poll_id)?);
     let reply = poll_keyboard(poll_id, &votes);
     let score = votes.score();
     let ret = bot
@@ -394,12 +834,17 @@ async fn callback_poll(bot: Bot, message: &Message, user_id: u64, data: &str) ->
         Err(RequestError::Api(ApiError::MessageNotModified)) => Ok(()),
         _ => ret.map(|_| ()),
     }?;
-    DB.update_score(&poll_id.to_string(), score, serde_json::to_string(&*votes)?)?;
+    ctx.db
+        .update_score(&poll_id.to_string(), score, serde_json::to_string(&*votes)?)?;
     info!("收到投票：[{}] {} -> {}", user_id, poll_id, score);
     Ok(())
 }
 
-pub async fn callback_handler(callback: CallbackQuery, bot: Bot) -> Result<()> {
+pub async fn callback_handler(
+    callback: CallbackQuery,
+    bot: Bot,
+    ctx: Arc<AppContext>,
+) -> Result<()> {
     debug!("回调：{:?}", callback.data);
 
     if let Some(d) = LIMIT.insert(callback.from.id.0) {
@@ -437,13 +882,232 @@ pub async fn callback_handler(callback: CallbackQuery, bot: Bot) -> Result<()> {
 
     match cmd {
         "<<" | ">>" | "<" | ">" => {
-            callback_change_page(bot, &message, cmd, data).await?;
+            callback_change_page(&ctx, bot, &message, cmd, data).await?;
         }
         "vote" => {
-            callback_poll(bot, &message, callback.from.id.0, data).await?;
+            callback_poll(&ctx, bot, &message, callback.from.id.0, data).await?;
         }
         _ => warn!("未知指令：{}", cmd),
     };
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{insert_test_gallery, mock_exhentai, test_context, test_context_with};
+    use serde_json::json;
+
+    /// 构造讨论组中的文本消息，发送者为 from
+    fn group_message(id: i32, from: u64, text: &str) -> Message {
+        serde_json::from_value(json!({
+            "message_id": id,
+            "date": 0,
+            "chat": {"id": -1002, "type": "supergroup", "title": "test"},
+            "from": {"id": from, "is_bot": false, "first_name": "tester"},
+            "text": text,
+        }))
+        .unwrap()
+    }
+
+    async fn handle(ctx: &Arc<AppContext>, message: Message) -> Result<()> {
+        let exloli = Arc::new(ExLoli::new(ctx.clone()));
+        message_handler(message, ctx.bot.clone(), ctx.clone(), exloli).await
+    }
+
+    /// 频道消息 message_id 自动转发到讨论组的文本消息
+    fn auto_forward(id: i32, message_id: i32, text: &str) -> Message {
+        serde_json::from_value(json!({
+            "message_id": id,
+            "date": 0,
+            "chat": {"id": -1002, "type": "supergroup", "title": "test"},
+            "from": {"id": 777000, "is_bot": false, "first_name": "Telegram"},
+            "forward_from_chat": {
+                "id": -1001,
+                "type": "channel",
+                "title": "exloli",
+                "username": "exloli_test"
+            },
+            "forward_from_message_id": message_id,
+            "forward_date": 0,
+            "is_automatic_forward": true,
+            "text": text,
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_new_gallery_custom_template() {
+        let (server, ctx) = test_context();
+        mock_exhentai(&server);
+        insert_test_gallery(&ctx, &server, 100).await;
+        // 自定义模板的消息中没有“原始地址”
+        handle(&ctx, auto_forward(1009, 100, "自定义模板"))
+            .await
+            .unwrap();
+        // 频道中的其他消息不是画廊
+        handle(&ctx, auto_forward(1010, 101, "原始地址"))
+            .await
+            .unwrap();
+
+        let unpinned = server.calls("unpinChatMessage");
+        assert!(unpinned.iter().any(|b| b["message_id"] == 1009));
+        assert!(!unpinned.iter().any(|b| b["message_id"] == 1010));
+        let replies = server.calls("sendMessage");
+        assert!(replies.iter().any(|b| b["reply_to_message_id"] == 1009));
+        assert!(!replies.iter().any(|b| b["reply_to_message_id"] == 1010));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_new_photo_gallery() {
+        let (server, ctx) = test_context();
+        mock_exhentai(&server);
+        insert_test_gallery(&ctx, &server, 100).await;
+        // 以图片形式发布的画廊转发到讨论组后只有图片说明，没有文本
+        let message = serde_json::from_value(json!({
+            "message_id": 1008,
+            "date": 0,
+            "chat": {"id": -1002, "type": "supergroup", "title": "test"},
+            "from": {"id": 777000, "is_bot": false, "first_name": "Telegram"},
+            "forward_from_chat": {
+                "id": -1001,
+                "type": "channel",
+                "title": "exloli",
+                "username": "exloli_test"
+            },
+            "forward_from_message_id": 100,
+            "forward_date": 0,
+            "is_automatic_forward": true,
+            "photo": [{"file_id": "a", "file_unique_id": "b", "width": 1, "height": 1}],
+            "caption": "[Artist] Test Gallery One",
+        }))
+        .unwrap();
+        handle(&ctx, message).await.unwrap();
+
+        assert!(server
+            .calls("unpinChatMessage")
+            .iter()
+            .any(|b| b["message_id"] == 1008));
+        assert!(server
+            .calls("sendMessage")
+            .iter()
+            .any(|b| b["reply_to_message_id"] == 1008 && b["reply_markup"].is_object()));
+    }
+
+    #[tokio::test]
+    async fn test_profile_channel_admins() {
+        let other = Recipient::ChannelUsername("@exloli_other".to_owned());
+        let (server, ctx) = test_context_with(|c| c.search[0].channel_id = Some(other));
+        ctx.admins.get(ctx.bot.clone()).await;
+        let chats = server
+            .calls("getChatAdministrators")
+            .into_iter()
+            .map(|b| b["chat_id"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            chats,
+            [json!("@exloli_test"), json!("@exloli_other"), json!(-1002)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ping() {
+        let (server, ctx) = test_context();
+        handle(&ctx, group_message(1001, 1, "/ping")).await.unwrap();
+        assert!(server
+            .calls("sendMessage")
+            .iter()
+            .any(|b| b["reply_to_message_id"] == 1001 && b["text"] == "pong"));
+    }
+
+    #[tokio::test]
+    async fn test_grant() {
+        let (server, ctx) = test_context();
+        let message = group_message(1002, 1, "/grant 4242 uploader");
+        handle(&ctx, message).await.unwrap();
+
+        assert_eq!(ctx.db.query_role(4242).unwrap(), Some(Role::Uploader));
+        let log = ctx
+            .db
+            .query_audit_log(-1)
+            .unwrap()
+            .into_iter()
+            .find(|log| log.command == "grant" && log.args == "4242 uploader")
+            .unwrap();
+        assert_eq!(log.actor_id, 1);
+        assert_eq!(log.result, "ok");
+        assert!(server
+            .calls("sendMessage")
+            .iter()
+            .any(|b| b["reply_to_message_id"] == 1002
+                && b["text"] == "已授予用户 4242 uploader 权限"));
+    }
+
+    #[tokio::test]
+    async fn test_permission_denied() {
+        let (server, ctx) = test_context();
+        // 没有权限时和未知命令一样，直接删除发给 bot 的命令
+        let message = group_message(1003, 5, "/grant@test_bot 4243 admin");
+        handle(&ctx, message).await.unwrap();
+
+        assert_eq!(ctx.db.query_role(4243).unwrap(), None);
+        assert!(server
+            .calls("deleteMessage")
+            .iter()
+            .any(|b| b["message_id"] == 1003));
+        assert!(!server
+            .calls("sendMessage")
+            .iter()
+            .any(|b| b["reply_to_message_id"] == 1003));
+        let log = ctx.db.query_audit_log(-1).unwrap();
+        assert_eq!(log.len(), 1);
+        assert_eq!((log[0].actor_id, log[0].command.as_str()), (5, "grant"));
+        assert_eq!(log[0].result, "权限不足");
+    }
+
+    #[tokio::test]
+    async fn test_no_usage_for_viewer() {
+        let (server, ctx) = test_context();
+        // 参数错误的管理命令，对有权限的用户会回复用法提示
+        handle(&ctx, group_message(1005, 5, "/undelete"))
+            .await
+            .unwrap();
+        handle(&ctx, group_message(1006, 5, "/delete@test_bot"))
+            .await
+            .unwrap();
+        assert!(server.calls("sendMessage").is_empty());
+        let deleted = server.calls("deleteMessage");
+        assert!(!deleted.iter().any(|b| b["message_id"] == 1005));
+        assert!(deleted.iter().any(|b| b["message_id"] == 1006));
+
+        handle(&ctx, group_message(1007, 1, "/undelete"))
+            .await
+            .unwrap();
+        assert!(server
+            .calls("sendMessage")
+            .iter()
+            .any(|b| b["reply_to_message_id"] == 1007
+                && b["text"].as_str().unwrap().starts_with("用法：/undelete")));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_audit_failures() {
+        let (server, ctx) = test_context();
+        mock_exhentai(&server);
+        insert_test_gallery(&ctx, &server, 100).await;
+
+        // 画廊未被删除，命令本身执行成功，但需要记录失败的画廊
+        let text = "/undelete https://exhentai.org/g/1000/abcdef1234/";
+        handle(&ctx, group_message(1004, 1, text)).await.unwrap();
+        let log = ctx.db.query_audit_log(-1).unwrap();
+        assert_eq!(log[0].command, "undelete");
+        assert!(
+            log[0].result.starts_with("失败 1 个："),
+            "{}",
+            log[0].result
+        );
+        assert!(log[0].result.ends_with("未被删除"));
+        assert!(ctx.db.query_jobs(20).unwrap().is_empty());
+    }
+}
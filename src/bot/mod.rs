@@ -2,19 +2,55 @@ mod command;
 mod handler;
 mod utils;
 
+use crate::config::Config;
+use crate::context::AppContext;
+use crate::database::Role;
+use crate::exloli::ExLoli;
+use command::COMMANDS;
 use handler::*;
+use std::sync::Arc;
 use teloxide::prelude::*;
+use teloxide::types::{BotCommand, BotCommandScope, Recipient};
+use utils::MessageExt;
 
-pub async fn start_bot(bot: Bot) {
+pub use utils::{message_url_regex, AdminCache};
+
+/// 向 Telegram 注册命令列表，所有人可见普通命令，群组管理员可见全部命令
+async fn set_commands(bot: &Bot, config: &Config) -> anyhow::Result<()> {
+    let commands = |max: Role| {
+        COMMANDS
+            .iter()
+            .filter(|c| c.role <= max)
+            .map(|c| BotCommand::new(c.name, c.description))
+            .collect::<Vec<_>>()
+    };
+    bot.set_my_commands(commands(Role::Viewer)).await?;
+    bot.set_my_commands(commands(Role::Owner))
+        .scope(BotCommandScope::ChatAdministrators {
+            chat_id: Recipient::Id(config.telegram.group_id),
+        })
+        .await?;
+    Ok(())
+}
+
+pub async fn start_bot(ctx: Arc<AppContext>, exloli: Arc<ExLoli>) {
     info!("BOT 启动");
 
+    let bot = ctx.bot.clone();
+    if let Err(e) = set_commands(&bot, &ctx.config).await {
+        error!("注册命令列表失败：{}", e);
+    }
+
+    // 频道自动转发的消息可能是带说明的图片，也需要处理
+    let messages = dptree::filter(|m: Message| m.text().is_some() || m.is_auto_forward());
     let handler = dptree::entry()
-        .branch(Update::filter_message().branch(Message::filter_text().endpoint(message_handler)))
+        .branch(Update::filter_message().branch(messages.endpoint(message_handler)))
         .branch(Update::filter_poll().endpoint(poll_handler))
         .branch(Update::filter_inline_query().endpoint(inline_handler))
         .branch(Update::filter_callback_query().endpoint(callback_handler));
 
     Dispatcher::builder(bot, handler)
+        .dependencies(dptree::deps![ctx, exloli])
         .enable_ctrlc_handler()
         .build()
         .dispatch()
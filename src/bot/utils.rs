@@ -1,12 +1,14 @@
-use crate::database::Gallery;
-use crate::{CONFIG, DB};
+use crate::config::Config;
+use crate::context::AppContext;
+use crate::database::{Gallery, Role};
 use dashmap::DashMap;
 use once_cell::sync::Lazy;
 use regex::Regex;
-use std::collections::VecDeque;
+use std::collections::{HashSet, VecDeque};
 use std::fmt::Debug;
 use std::hash::Hash;
 use std::ops::Deref;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 use teloxide::prelude::*;
 use teloxide::types::*;
@@ -14,34 +16,37 @@ use uuid::Uuid;
 
 pub static EXHENTAI_URL: Lazy<Regex> =
     Lazy::new(|| Regex::new(r"https://e.hentai\.org/g/\d+/[0-9a-f]+/?(#\d+)?").unwrap());
-pub static MESSAGE_URL: Lazy<Regex> = Lazy::new(|| {
-    let channel_id = &CONFIG.telegram.channel_id;
+
+/// 匹配指定频道的消息直链
+pub fn message_url_regex(channel_id: &Recipient) -> Regex {
     Regex::new(
         &format!(r"https://t.me/{}/(\d+)", channel_id)
             .replace("/-100", "/")
             .replace('@', ""),
     )
     .unwrap()
-});
+}
+
+/// Telegram 官方账号，频道消息由它自动转发到讨论组
+pub const TELEGRAM_USER_ID: u64 = 777000;
 
 pub trait MessageExt {
-    fn is_from_my_group(&self) -> bool;
-    fn from_username(&self) -> Option<&String>;
+    fn is_from_my_group(&self, config: &Config) -> bool;
+    fn is_auto_forward(&self) -> bool;
     fn reply_to_user(&self) -> Option<&User>;
-    fn reply_to_gallery(&self) -> Option<Gallery>;
+    fn forward_gallery(&self, ctx: &AppContext) -> Option<Gallery>;
+    fn reply_to_gallery(&self, ctx: &AppContext) -> Option<Gallery>;
 }
 
 impl MessageExt for Message {
     // 判断消息来源是否是指定群组
-    fn is_from_my_group(&self) -> bool {
-        CONFIG.telegram.group_id == self.chat.id
+    fn is_from_my_group(&self, config: &Config) -> bool {
+        config.telegram.group_id == self.chat.id
     }
 
-    fn from_username(&self) -> Option<&String> {
-        if let Some(User { username, .. }) = self.from() {
-            return username.as_ref();
-        }
-        None
+    // 判断是否是频道消息自动转发到讨论组的消息
+    fn is_auto_forward(&self) -> bool {
+        self.from().map(|u| u.id.0) == Some(TELEGRAM_USER_ID)
     }
 
     fn reply_to_user(&self) -> Option<&User> {
@@ -51,50 +56,112 @@ impl MessageExt for Message {
         None
     }
 
-    fn reply_to_gallery(&self) -> Option<Gallery> {
+    // 转发自频道的消息所对应的画廊
+    fn forward_gallery(&self, ctx: &AppContext) -> Option<Gallery> {
+        let chat = self.forward_from_chat()?;
+        let message_id = self.forward_from_message_id()?;
+        ctx.db
+            .query_gallery_by_message(message_id, |g| {
+                is_same_chat(ctx.config.channel_id(&g.profile), chat)
+            })
+            .ok()
+    }
+
+    fn reply_to_gallery(&self, ctx: &AppContext) -> Option<Gallery> {
         self.reply_to_message()
-            .and_then(|message| message.forward_from_message_id())
-            .and_then(|mess_id| DB.query_gallery(mess_id).ok())
+            .and_then(|message| message.forward_gallery(ctx))
     }
 }
 
-// TODO: 缓存
-/// 获取管理员列表
-async fn get_admins(bot: Bot) -> Option<Vec<User>> {
-    let mut admins = bot
-        .get_chat_administrators(CONFIG.telegram.channel_id.clone())
-        .await
-        .ok()?;
-    admins.extend(
-        bot.get_chat_administrators(CONFIG.telegram.group_id)
-            .await
-            .ok()?,
-    );
-    Some(admins.into_iter().map(|member| member.user).collect())
+/// 判断配置中的频道是否为指定对话
+pub fn is_same_chat(channel_id: &Recipient, chat: &Chat) -> bool {
+    match channel_id {
+        Recipient::Id(id) => *id == chat.id,
+        Recipient::ChannelUsername(name) => chat.username() == Some(name.trim_start_matches('@')),
+    }
+}
+
+/// 管理员列表的缓存时间
+const ADMIN_CACHE_TTL: Duration = Duration::from_secs(600);
+
+/// 频道和讨论组管理员列表的缓存
+pub struct AdminCache {
+    ttl: Duration,
+    /// 需要获取管理员的对话
+    chats: Vec<Recipient>,
+    data: Mutex<Option<(Instant, Arc<HashSet<u64>>)>>,
+}
+
+impl AdminCache {
+    /// 包括所有发布频道和讨论组
+    pub fn new(config: &Config) -> Self {
+        let mut chats = config.channels().into_iter().cloned().collect::<Vec<_>>();
+        chats.push(Recipient::Id(config.telegram.group_id));
+        Self {
+            ttl: ADMIN_CACHE_TTL,
+            chats,
+            data: Mutex::new(None),
+        }
+    }
+
+    /// 获取管理员的 id，缓存过期后重新请求，请求失败时继续使用过期的缓存
+    pub async fn get(&self, bot: Bot) -> Arc<HashSet<u64>> {
+        let cached = self.data.lock().unwrap().clone();
+        if let Some((time, admins)) = &cached {
+            if time.elapsed() < self.ttl {
+                return admins.clone();
+            }
+        }
+        match get_admins(bot, &self.chats).await {
+            Ok(admins) => {
+                let admins = Arc::new(admins);
+                *self.data.lock().unwrap() = Some((Instant::now(), admins.clone()));
+                admins
+            }
+            Err(e) => {
+                warn!("获取管理员列表失败：{}", e);
+                cached.map(|(_, v)| v).unwrap_or_default()
+            }
+        }
+    }
 }
 
-// 检测是否是指定频道的管理员
-pub async fn check_is_channel_admin(bot: Bot, message: &Message) -> (bool, bool) {
-    // 先检测是否为匿名管理员
-    let from_user = message.from();
-    if from_user
-        .map(|u| u.username == Some("GroupAnonymousBot".into()))
-        .unwrap_or(false)
-        && message.is_from_my_group()
+/// 获取频道和讨论组的管理员列表
+async fn get_admins(bot: Bot, chats: &[Recipient]) -> anyhow::Result<HashSet<u64>> {
+    let mut admins = vec![];
+    for chat in chats {
+        admins.extend(bot.get_chat_administrators(chat.clone()).await?);
+    }
+    Ok(admins.into_iter().map(|member| member.user.id.0).collect())
+}
+
+/// 获取消息发送者的权限，取数据库中记录的权限和管理员身份中较高的一个
+pub async fn get_role(ctx: &AppContext, message: &Message) -> Role {
+    let user = match message.from() {
+        Some(v) => v,
+        None => return Role::Viewer,
+    };
+    // 讨论组的匿名管理员
+    if user.username.as_deref() == Some("GroupAnonymousBot")
+        && message.is_from_my_group(&ctx.config)
     {
-        return (true, true);
-    }
-    let admins = get_admins(bot).await.unwrap_or_default();
-    let is_admin = message
-        .from()
-        .map(|user| admins.iter().map(|admin| admin == user).any(|x| x))
-        .unwrap_or(false);
-    let trusted = is_admin
-        || CONFIG
-            .telegram
-            .trusted_users
-            .contains(message.from_username().unwrap_or(&String::new()));
-    (is_admin, trusted)
+        return Role::Admin;
+    }
+    if ctx.config.telegram.owners.contains(&user.id.0) {
+        return Role::Owner;
+    }
+    let stored = match ctx.db.query_role(user.id.0 as i64) {
+        Ok(v) => v.unwrap_or(Role::Viewer),
+        Err(e) => {
+            error!("查询用户权限失败：{}", e);
+            Role::Viewer
+        }
+    };
+    if ctx.admins.get(ctx.bot.clone()).await.contains(&user.id.0) {
+        stored.max(Role::Admin)
+    } else {
+        stored
+    }
 }
 
 pub fn inline_article<S1, S2>(title: S1, content: S2) -> InlineQueryResultArticle
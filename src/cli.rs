@@ -0,0 +1,174 @@
+//! 命令行参数解析
+use anyhow::Result;
+use getopts::Options;
+
+use std::env;
+
+const COMMANDS: &str = "
+Commands:
+    run                        启动 bot 并定时扫描，不指定命令时的默认行为
+    scan-once                  扫描一次，执行完队列中到期的任务后退出
+    upload URL...              上传指定画廊
+    update-tags URL|MSG...     同步指定画廊的 tag，可以使用画廊地址、频道消息地址或主频道的消息 id
+    db migrate                 执行数据库迁移
+    db export [FILE]           导出已发布的画廊，每行一条 JSON，默认输出到标准输出
+    audit export [FILE]        导出管理操作记录，每行一条 JSON，默认输出到标准输出
+    db stats [NS:TAG]          打印数据库统计信息，可以指定一个 tag 查看相关画廊
+    config check               检查配置文件、翻译数据库和模板
+    translate NS TAG           查询 tag 的翻译
+";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    Run,
+    ScanOnce,
+    Upload(Vec<String>),
+    UpdateTags(Vec<String>),
+    DbMigrate,
+    /// 导出画廊，导出路径，- 表示标准输出
+    DbExport(String),
+    /// 导出管理操作记录，导出路径，- 表示标准输出
+    AuditExport(String),
+    /// 可选的 (namespace, tag)
+    DbStats(Option<(String, String)>),
+    ConfigCheck,
+    Translate(String, String),
+    /// 帮助信息
+    Help(String),
+}
+
+#[derive(Debug)]
+pub struct Args {
+    /// 配置文件路径
+    pub config: String,
+    /// 调试模式，不自动爬本，只用于 run
+    pub debug: bool,
+    /// 试运行，只用于 run 和 scan-once
+    pub dry_run: bool,
+    pub command: Command,
+}
+
+fn options() -> Options {
+    let mut opts = Options::new();
+    opts.optopt(
+        "c",
+        "config",
+        "配置文件路径，默认读取环境变量 EXLOLI_CONFIG，未设置时为 config.toml",
+        "FILE",
+    );
+    opts.optflag("", "debug", "调试模式，不自动爬本，只用于 run");
+    opts.optflag(
+        "",
+        "dry-run",
+        "试运行，扫描一次并打印每个画廊计划执行的操作，不发布也不写入数据库",
+    );
+    opts.optflag("h", "help", "打印帮助");
+    opts
+}
+
+impl Args {
+    /// 解析命令行参数，第一个参数为程序名
+    pub fn parse(args: &[String]) -> Result<Self> {
+        let opts = options();
+        let matches = opts.parse(&args[1..])?;
+        let config = matches
+            .opt_str("config")
+            .or_else(|| env::var("EXLOLI_CONFIG").ok())
+            .unwrap_or_else(|| "config.toml".to_owned());
+        let command = match matches.opt_present("help") {
+            true => {
+                let brief = format!("Usage: {} [options] [command]", args[0]);
+                Command::Help(opts.usage(&brief) + COMMANDS)
+            }
+            false => parse_command(&matches.free)?,
+        };
+        Ok(Self {
+            config,
+            debug: matches.opt_present("debug"),
+            dry_run: matches.opt_present("dry-run"),
+            command,
+        })
+    }
+}
+
+fn parse_command(free: &[String]) -> Result<Command> {
+    let to_vec = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+    let args = free.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+    Ok(match args.as_slice() {
+        [] | ["run"] => Command::Run,
+        ["scan-once"] => Command::ScanOnce,
+        ["upload", urls @ ..] if !urls.is_empty() => Command::Upload(to_vec(urls)),
+        ["update-tags", targets @ ..] if !targets.is_empty() => {
+            Command::UpdateTags(to_vec(targets))
+        }
+        ["db", "migrate"] => Command::DbMigrate,
+        ["db", "export"] => Command::DbExport("-".to_owned()),
+        ["db", "export", path] => Command::DbExport(path.to_string()),
+        ["audit", "export"] => Command::AuditExport("-".to_owned()),
+        ["audit", "export", path] => Command::AuditExport(path.to_string()),
+        ["db", "stats"] => Command::DbStats(None),
+        ["db", "stats", tag] => {
+            let (namespace, tag) = tag
+                .split_once(':')
+                .ok_or_else(|| anyhow!("tag 格式应为 namespace:tag"))?;
+            Command::DbStats(Some((namespace.to_owned(), tag.to_owned())))
+        }
+        ["config", "check"] => Command::ConfigCheck,
+        ["translate", namespace, tag] => Command::Translate(namespace.to_string(), tag.to_string()),
+        _ => bail!("无法识别的命令：{}，使用 --help 查看用法", free.join(" ")),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args> {
+        let args = std::iter::once("exloli")
+            .chain(args.iter().cloned())
+            .map(String::from)
+            .collect::<Vec<_>>();
+        Args::parse(&args)
+    }
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(parse(&[]).unwrap().command, Command::Run);
+        assert_eq!(parse(&["--debug"]).unwrap().command, Command::Run);
+        assert!(parse(&["--debug"]).unwrap().debug);
+        assert!(parse(&["scan-once", "--dry-run"]).unwrap().dry_run);
+        assert_eq!(
+            parse(&["upload", "a", "b"]).unwrap().command,
+            Command::Upload(vec!["a".to_owned(), "b".to_owned()])
+        );
+        assert_eq!(
+            parse(&["db", "export"]).unwrap().command,
+            Command::DbExport("-".to_owned())
+        );
+        assert_eq!(
+            parse(&["audit", "export", "audit.jsonl"]).unwrap().command,
+            Command::AuditExport("audit.jsonl".to_owned())
+        );
+        assert_eq!(
+            parse(&["db", "stats", "female:lolicon"]).unwrap().command,
+            Command::DbStats(Some(("female".to_owned(), "lolicon".to_owned())))
+        );
+        assert_eq!(
+            parse(&["translate", "female", "lolicon"]).unwrap().command,
+            Command::Translate("female".to_owned(), "lolicon".to_owned())
+        );
+        assert!(parse(&["upload"]).is_err());
+        assert!(parse(&["db", "stats", "lolicon"]).is_err());
+        assert!(parse(&["db"]).is_err());
+    }
+
+    #[test]
+    fn parse_config() {
+        let args = parse(&["db", "migrate", "-c", "other.toml"]).unwrap();
+        assert_eq!(args.config, "other.toml");
+        assert_eq!(args.command, Command::DbMigrate);
+        let args = parse(&["--config=other.toml", "run"]).unwrap();
+        assert_eq!(args.config, "other.toml");
+        assert!(matches!(parse(&["-h"]).unwrap().command, Command::Help(_)));
+    }
+}
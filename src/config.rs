@@ -1,6 +1,11 @@
-use anyhow::Error;
+use crate::filter::FilterRule;
+use crate::template::Template;
+use anyhow::{Context, Error};
+use chrono::{NaiveDate, NaiveDateTime, Utc};
 use reqwest::{Client, Proxy};
 use serde::Deserialize;
+use std::path::PathBuf;
+use std::str::FromStr;
 use std::time::Duration;
 use std::{fs::File, io::Read, path::Path};
 use teloxide::types::{ChatId, Recipient};
@@ -10,11 +15,32 @@ use url::Url;
 pub struct Config {
     pub log_level: String,
     pub threads_num: usize,
+    /// 上传队列的并发数，默认为 1 以保证发布顺序
+    pub workers: Option<usize>,
     pub interval: u64,
     pub database_url: String,
+    /// tag 翻译数据库的路径，默认为当前目录下的 db.text.json
+    #[serde(default = "default_translation")]
+    pub translation: PathBuf,
     pub exhentai: ExHentai,
+    #[serde(default)]
+    pub search: Vec<SearchProfile>,
+    /// 过滤规则，只对自动扫描到的画廊生效
+    #[serde(default)]
+    pub filter: Vec<FilterRule>,
+    /// 已上传画廊的更新检查间隔
+    #[serde(default = "default_refresh")]
+    pub refresh: Vec<RefreshTier>,
+    /// 已发布的画廊出现新版本时的处理方式
+    #[serde(default)]
+    pub newer_version: VersionPolicy,
     pub telegraph: Telegraph,
     pub telegram: Telegram,
+    /// 图床配置，默认上传到 telegraph
+    #[serde(default)]
+    pub image_host: ImageHostConfig,
+    #[serde(default)]
+    pub template: TemplateConfig,
 }
 
 #[derive(Debug, Deserialize)]
@@ -23,19 +49,121 @@ pub struct ExHentai {
     pub password: String,
     pub cookie: Option<String>,
     pub search_url: Url,
+    /// 以下四项为旧版的搜索配置，仅在没有配置 [[search]] 时使用
+    pub search_params: Option<Vec<(String, String)>>,
+    pub max_pages: Option<i32>,
+    pub max_img_cnt: Option<usize>,
+    pub outdate: Option<i64>,
+    pub proxy: Option<String>,
+}
+
+/// 搜索配置
+#[derive(Debug, Deserialize)]
+pub struct SearchProfile {
+    /// 配置名称，会记录到数据库中
+    pub name: String,
     pub search_params: Vec<(String, String)>,
     pub max_pages: i32,
     pub max_img_cnt: usize,
     pub outdate: Option<i64>,
-    pub proxy: Option<String>,
+    /// 扫描间隔，默认使用全局配置
+    pub interval: Option<u64>,
+    /// 发布频道，默认使用 telegram.channel_id
+    pub channel_id: Option<Recipient>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize)]
 pub struct Telegraph {
     pub access_token: String,
     pub author_name: String,
     pub author_url: String,
     pub proxy: Option<String>,
+    /// 每篇文章最多包含的图片数量，超出后拆分为多篇，默认 200
+    pub page_size: Option<usize>,
+    /// API 地址，默认为 https://api.telegra.ph
+    pub api_url: Option<String>,
+    /// 图片上传地址，默认为 https://telegra.ph/upload
+    pub upload_url: Option<String>,
+}
+
+/// 画廊的更新检查间隔，按发布天数选择第一个满足条件的配置
+#[derive(Debug, Clone, Deserialize)]
+pub struct RefreshTier {
+    /// 适用于发布天数不超过该值的画廊，为空则适用于所有画廊
+    pub max_age: Option<i64>,
+    /// 检查间隔，单位为小时
+    pub interval: i64,
+}
+
+fn default_translation() -> PathBuf {
+    PathBuf::from("db.text.json")
+}
+
+fn default_refresh() -> Vec<RefreshTier> {
+    vec![
+        RefreshTier {
+            max_age: Some(2),
+            interval: 1,
+        },
+        RefreshTier {
+            max_age: Some(7),
+            interval: 4,
+        },
+        RefreshTier {
+            max_age: None,
+            interval: 24 * 7,
+        },
+    ]
+}
+
+/// 根据画廊的发布天数选择检查间隔，没有满足条件的配置时返回 None，即不再检查
+pub fn refresh_interval(tiers: &[RefreshTier], age: i64) -> Option<chrono::Duration> {
+    tiers
+        .iter()
+        .find(|tier| !matches!(tier.max_age, Some(v) if age > v))
+        .map(|tier| chrono::Duration::hours(tier.interval))
+}
+
+/// 消息和文章模板，未配置时使用内置模板
+#[derive(Debug, Default, Deserialize)]
+pub struct TemplateConfig {
+    /// Telegram 消息模板
+    pub message: Option<String>,
+    /// telegraph 文章模板，长画廊的每一篇文章都会单独渲染
+    pub article: Option<String>,
+}
+
+/// 图床配置，通过 type 字段选择
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ImageHostConfig {
+    Telegraph,
+    /// S3 兼容的对象存储，使用 path-style 地址
+    S3 {
+        endpoint: String,
+        bucket: String,
+        #[serde(default = "default_region")]
+        region: String,
+        access_key: String,
+        secret_key: String,
+        /// 图片的访问地址前缀，默认为 `{endpoint}/{bucket}`
+        public_url: Option<String>,
+    },
+    /// 保存到本地目录，由其他程序通过 base_url 对外提供访问
+    Local {
+        path: PathBuf,
+        base_url: String,
+    },
+}
+
+impl Default for ImageHostConfig {
+    fn default() -> Self {
+        Self::Telegraph
+    }
+}
+
+fn default_region() -> String {
+    "us-east-1".to_owned()
 }
 
 #[derive(Debug, Deserialize)]
@@ -43,8 +171,89 @@ pub struct Telegram {
     pub channel_id: Recipient,
     pub bot_id: String,
     pub token: String,
+    /// Bot API 地址，使用自建的 Bot API 服务器时设置
+    pub api_url: Option<Url>,
     pub group_id: ChatId,
-    pub trusted_users: Vec<String>,
+    /// 拥有所有权限的用户 id，其余用户的权限通过 /grant 命令授予
+    #[serde(default)]
+    pub owners: Vec<u64>,
+    /// 频道消息的发送方式
+    #[serde(default)]
+    pub post_mode: PostMode,
+    /// 以媒体组发送时包含的图片数量，默认 4
+    pub media_group_size: Option<usize>,
+    /// 画廊在 E 站被删除后，是否在频道消息中注明
+    #[serde(default)]
+    pub mark_expunged: bool,
+}
+
+/// 频道消息的发送方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PostMode {
+    /// 纯文本消息，依赖 telegram 生成文章预览
+    Text,
+    /// 发送封面，消息内容作为图片说明
+    Photo,
+    /// 以媒体组发送前几张图片，消息内容作为第一张图片的说明
+    MediaGroup,
+}
+
+impl PostMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Text => "text",
+            Self::Photo => "photo",
+            Self::MediaGroup => "media_group",
+        }
+    }
+}
+
+impl Default for PostMode {
+    fn default() -> Self {
+        Self::Text
+    }
+}
+
+impl FromStr for PostMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "text" => Ok(Self::Text),
+            "photo" => Ok(Self::Photo),
+            "media_group" => Ok(Self::MediaGroup),
+            _ => Err(anyhow!("未知的发送方式：{}", s)),
+        }
+    }
+}
+
+/// 已发布的画廊出现新版本时的处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VersionPolicy {
+    /// 使用新版本原地更新原消息
+    Update,
+    /// 将新版本作为新画廊重新发布
+    Republish,
+    /// 仅在群组中通知管理员
+    Notify,
+}
+
+impl VersionPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Update => "update",
+            Self::Republish => "republish",
+            Self::Notify => "notify",
+        }
+    }
+}
+
+impl Default for VersionPolicy {
+    fn default() -> Self {
+        Self::Notify
+    }
 }
 
 impl Config {
@@ -52,37 +261,142 @@ impl Config {
         let mut file = File::open(path)?;
         let mut str = String::new();
         file.read_to_string(&mut str)?;
-        Ok(toml::from_str(&str)?)
+        let mut config: Self = toml::from_str(&str)?;
+        config
+            .exhentai
+            .search_url
+            .host_str()
+            .context("search_url 中没有域名")?;
+        if config.search.is_empty() {
+            let exhentai = &config.exhentai;
+            config.search.push(SearchProfile {
+                name: "".to_owned(),
+                search_params: exhentai.search_params.clone().context("请配置搜索参数")?,
+                max_pages: exhentai.max_pages.context("请配置 max_pages")?,
+                max_img_cnt: exhentai.max_img_cnt.context("请配置 max_img_cnt")?,
+                outdate: exhentai.outdate,
+                interval: None,
+                channel_id: None,
+            });
+        }
+        if let Some(template) = &config.template.message {
+            Template::parse(template).context("消息模板解析失败")?;
+        }
+        if let Some(template) = &config.template.article {
+            Template::parse(template).context("文章模板解析失败")?;
+        }
+        for (i, profile) in config.search.iter().enumerate() {
+            if config.search[..i].iter().any(|p| p.name == profile.name) {
+                bail!("搜索配置名称重复：{}", profile.name);
+            }
+        }
+        Ok(config)
+    }
+
+    /// 默认搜索配置，手动上传的画廊使用该配置
+    pub fn default_profile(&self) -> &SearchProfile {
+        &self.search[0]
+    }
+
+    /// 根据名称获取搜索配置，找不到则返回默认配置
+    pub fn profile(&self, name: &str) -> &SearchProfile {
+        self.search
+            .iter()
+            .find(|p| p.name == name)
+            .unwrap_or_else(|| self.default_profile())
+    }
+
+    /// 指定搜索配置所发布的频道
+    pub fn channel_id(&self, profile: &str) -> &Recipient {
+        self.profile(profile)
+            .channel_id
+            .as_ref()
+            .unwrap_or(&self.telegram.channel_id)
+    }
+
+    /// 所有发布频道，不含重复项
+    pub fn channels(&self) -> Vec<&Recipient> {
+        let mut channels = vec![&self.telegram.channel_id];
+        for channel in self.search.iter().filter_map(|p| p.channel_id.as_ref()) {
+            if !channels.contains(&channel) {
+                channels.push(channel);
+            }
+        }
+        channels
+    }
+
+    /// 根据发布日期计算画廊下一次检查更新的时间
+    pub fn next_check_at(&self, publish_date: NaiveDate) -> Option<NaiveDateTime> {
+        let now = Utc::now().naive_utc();
+        let age = (now.date() - publish_date).num_days();
+        refresh_interval(&self.refresh, age).map(|d| now + d)
+    }
+
+    /// 指定搜索配置的扫描间隔
+    pub fn interval(&self, profile: &str) -> u64 {
+        self.profile(profile).interval.unwrap_or(self.interval)
     }
 
-    pub async fn init_telegraph(&self) -> Result<telegraph_rs::Telegraph, Error> {
+    pub fn init_telegraph(&self) -> Result<crate::telegraph::Telegraph, Error> {
         let telegraph = &self.telegraph;
         let mut client_builder = Client::builder().timeout(Duration::from_secs(30));
         if let Some(proxy) = &self.telegraph.proxy {
             client_builder = client_builder.proxy(Proxy::all(proxy)?);
         }
         let client = client_builder.build()?;
-        Ok(telegraph_rs::Telegraph::new(&telegraph.author_name)
-            .author_url(&telegraph.author_url)
-            .access_token(&telegraph.access_token)
-            .client(client)
-            .create()
-            .await?)
+        Ok(crate::telegraph::Telegraph::new(
+            client,
+            telegraph
+                .api_url
+                .as_deref()
+                .unwrap_or(crate::telegraph::DEFAULT_API_URL),
+            &telegraph.access_token,
+            &telegraph.author_name,
+            &telegraph.author_url,
+        ))
+    }
+
+    /// 根据配置创建 bot
+    pub fn init_bot(&self) -> teloxide::Bot {
+        let bot = teloxide::Bot::new(&self.telegram.token);
+        match &self.telegram.api_url {
+            Some(url) => bot.set_api_url(url.clone()),
+            None => bot,
+        }
     }
 
     pub async fn init_exhentai(&self) -> Result<crate::exhentai::ExHentai, Error> {
         if self.exhentai.cookie.is_some() {
-            crate::exhentai::ExHentai::from_cookie().await
+            crate::exhentai::ExHentai::from_cookie(&self.exhentai).await
         } else {
-            crate::exhentai::ExHentai::new().await
+            crate::exhentai::ExHentai::new(&self.exhentai).await
         }
     }
+
+    /// E 站的域名，用于拼接画廊地址
+    pub fn host(&self) -> &str {
+        self.exhentai
+            .search_url
+            .host_str()
+            .unwrap_or("exhentai.org")
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::config::Config;
 
+    #[test]
+    fn test_refresh_interval() {
+        let tiers = super::default_refresh();
+        let interval = |age| super::refresh_interval(&tiers, age).map(|d| d.num_hours());
+        assert_eq!(interval(0), Some(1));
+        assert_eq!(interval(2), Some(1));
+        assert_eq!(interval(3), Some(4));
+        assert_eq!(interval(30), Some(24 * 7));
+        assert_eq!(super::refresh_interval(&tiers[..2], 30), None);
+    }
+
     #[test]
     fn test() {
         let config = Config::new("config.toml");
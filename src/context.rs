@@ -0,0 +1,91 @@
+//! 程序运行所需的全部状态，由 main 创建后传递给各个模块
+use crate::bot::AdminCache;
+use crate::config::Config;
+use crate::database::DataBase;
+use crate::exhentai::ExHentai;
+use crate::host::{new_image_host, ImageHost};
+use crate::telegraph::Telegraph;
+use crate::template::{Template, DEFAULT_ARTICLE, DEFAULT_MESSAGE};
+use crate::trans;
+use anyhow::{Context, Result};
+use teloxide::Bot;
+
+use std::sync::Arc;
+
+pub struct AppContext {
+    pub config: Config,
+    pub db: DataBase,
+    pub bot: Bot,
+    pub exhentai: ExHentai,
+    /// tag 翻译数据库，与数据库共享
+    pub trans: Arc<trans::Database>,
+    pub telegraph: Telegraph,
+    pub image_host: Box<dyn ImageHost>,
+    pub message_template: Template,
+    pub article_template: Template,
+    /// 频道和讨论组管理员列表的缓存
+    pub admins: AdminCache,
+}
+
+impl AppContext {
+    /// 根据配置初始化，会登录 E 站
+    pub async fn new(config: Config) -> Result<Self> {
+        let exhentai = config.init_exhentai().await.context("登录失败")?;
+        Self::with_exhentai(config, exhentai)
+    }
+
+    /// 试运行使用的上下文，会登录 E 站，但以只读方式打开数据库
+    pub async fn read_only(config: Config) -> Result<Self> {
+        let exhentai = config.init_exhentai().await.context("登录失败")?;
+        let trans = Arc::new(trans::Database::load(&config.translation)?);
+        let db = DataBase::open_read_only(&config.database_url, trans)?;
+        Self::with_database(config, exhentai, db)
+    }
+
+    /// 使用已经登录的 E 站客户端初始化
+    pub fn with_exhentai(config: Config, exhentai: ExHentai) -> Result<Self> {
+        let trans = Arc::new(trans::Database::load(&config.translation)?);
+        let db = DataBase::init(&config.database_url, trans).context("数据库初始化失败")?;
+        let reasons = config
+            .filter
+            .iter()
+            .map(|r| r.reason.as_str())
+            .collect::<Vec<_>>();
+        let cleaned = db.clean_rejected(&reasons)?;
+        if cleaned > 0 {
+            info!("清理了 {} 条过期或规则已删除的过滤记录", cleaned);
+        }
+        Self::with_database(config, exhentai, db)
+    }
+
+    /// 使用已经登录的 E 站客户端和已经打开的数据库初始化
+    pub fn with_database(config: Config, exhentai: ExHentai, db: DataBase) -> Result<Self> {
+        let trans = db.trans();
+        let (message_template, article_template) = Self::load_templates(&config)?;
+        Ok(Self {
+            bot: config.init_bot(),
+            telegraph: config.init_telegraph()?,
+            image_host: new_image_host(&config.image_host, &config.telegraph)
+                .context("图床初始化失败")?,
+            admins: AdminCache::new(&config),
+            message_template,
+            article_template,
+            exhentai,
+            trans,
+            db,
+            config,
+        })
+    }
+
+    /// 解析配置中的消息模板和文章模板，未配置时使用默认模板
+    pub fn load_templates(config: &Config) -> Result<(Template, Template)> {
+        let template = &config.template;
+        let message_template =
+            Template::parse(template.message.as_deref().unwrap_or(DEFAULT_MESSAGE))
+                .context("消息模板解析失败")?;
+        let article_template =
+            Template::parse(template.article.as_deref().unwrap_or(DEFAULT_ARTICLE))
+                .context("文章模板解析失败")?;
+        Ok((message_template, article_template))
+    }
+}
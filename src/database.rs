@@ -1,19 +1,31 @@
+use crate::config::{Config, PostMode, VersionPolicy};
 use crate::exhentai::*;
+use crate::filter::Rejection;
+use crate::queue::{retry_delay, JobKind, JobState, MAX_ATTEMPTS};
 use crate::schema::*;
+use crate::trans;
 use crate::utils::*;
-use anyhow::{Context, Result};
+use anyhow::{Context, Error, Result};
 use chrono::prelude::*;
+use diesel::connection::SimpleConnection;
 use diesel::dsl::sql;
 use diesel::prelude::*;
-use diesel::r2d2::{ConnectionManager, Pool};
+use diesel::r2d2::{ConnectionManager, CustomizeConnection, Pool};
+use diesel::sql_types::{BigInt, Integer, Text};
 use diesel::sqlite::Sqlite;
-use std::env;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
 
 embed_migrations!("migrations");
 
-#[derive(Queryable, Insertable, PartialEq, Debug, Clone)]
+#[derive(Queryable, QueryableByName, PartialEq, Debug, Clone)]
 #[table_name = "gallery"]
 pub struct Gallery {
+    /// 自增 id，旧数据与消息 id 相同
+    pub id: i32,
+    /// 所在频道中的消息 id，不同频道的消息 id 可能相同
     pub message_id: i32,
     pub gallery_id: i32,
     pub token: String,
@@ -25,6 +37,66 @@ pub struct Gallery {
     pub poll_id: String,
     pub score: f32,
     pub votes: String,
+    pub profile: String,
+    /// 频道消息的发送方式
+    pub post_mode: String,
+    /// 下一次检查更新的时间，为空则不再检查
+    pub next_check_at: Option<NaiveDateTime>,
+    /// 画廊状态，见 `GalleryStatus`
+    pub status: String,
+    /// 删除画廊的管理员
+    pub deleted_by: Option<i64>,
+    pub deleted_at: Option<NaiveDateTime>,
+}
+
+/// 画廊状态
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GalleryStatus {
+    Active,
+    /// 被管理员通过 /delete 删除
+    DeletedByAdmin,
+    /// 已在 E 站被删除或隐藏
+    Expunged,
+    /// 已经发布了新消息取代
+    Replaced,
+}
+
+impl GalleryStatus {
+    /// 频道中仍然存在、可以出现在排行和搜索结果中的状态
+    pub const VISIBLE: [GalleryStatus; 3] = [Self::Active, Self::Expunged, Self::Replaced];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Active => "active",
+            Self::DeletedByAdmin => "deleted_by_admin",
+            Self::Expunged => "expunged",
+            Self::Replaced => "replaced",
+        }
+    }
+
+    fn visible() -> Vec<&'static str> {
+        Self::VISIBLE.iter().map(|s| s.as_str()).collect()
+    }
+}
+
+impl FromStr for GalleryStatus {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "active" => Ok(Self::Active),
+            "deleted_by_admin" => Ok(Self::DeletedByAdmin),
+            "expunged" => Ok(Self::Expunged),
+            "replaced" => Ok(Self::Replaced),
+            _ => Err(anyhow!("未知的画廊状态：{}", s)),
+        }
+    }
+}
+
+impl fmt::Display for GalleryStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
 }
 
 #[derive(Queryable, Insertable)]
@@ -38,31 +110,429 @@ pub struct Image {
 #[table_name = "image_hash"]
 pub struct ImageHash {
     pub hash: String,
+    /// 上传后的地址，JSON 数组，切分过的长图每一块为一项
     pub url: String,
+    /// 图片所在的图床
+    pub host: String,
+}
+
+/// 画廊中单张图片的上传结果，url 和 error 有且只有一个不为空
+#[derive(Queryable, Insertable)]
+#[table_name = "gallery_image"]
+pub struct GalleryImage {
+    pub gallery_id: i32,
+    pub page: i32,
+    /// 上传后的地址，JSON 数组，切分过的长图每一块为一项
+    pub url: Option<String>,
+    pub error: Option<String>,
+}
+
+impl GalleryImage {
+    /// 上传后的地址，无法上传的图片为 None
+    pub fn urls(&self) -> Result<Option<Vec<String>>> {
+        Ok(self.url.as_deref().map(serde_json::from_str).transpose()?)
+    }
+}
+
+/// 画廊对应的 telegraph 文章，长画廊会被拆分为多篇
+#[derive(Queryable, Insertable)]
+#[table_name = "telegraph_page"]
+pub struct TelegraphPage {
+    /// 画廊的 id
+    pub gallery_ref: i32,
+    pub part: i32,
+    pub path: String,
+}
+
+/// 已发布画廊的新版本，parent 为发现新版本时已发布的画廊
+#[derive(Queryable, Insertable, Debug, Clone)]
+#[table_name = "gallery_version"]
+pub struct GalleryVersion {
+    pub gallery_id: i32,
+    pub token: String,
+    pub parent_id: i32,
+    pub parent_token: String,
+    /// 已发布画廊的 id
+    pub parent_ref: i32,
+    /// 当时采取的处理方式
+    pub action: String,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Queryable, Insertable)]
+#[table_name = "rejected"]
+pub struct Rejected {
+    pub gallery_id: i32,
+    pub token: String,
+    pub title: String,
+    pub reason: String,
+    pub rejected_at: NaiveDateTime,
+    /// 过期后重新检查，为空时永久有效
+    pub expires_at: Option<NaiveDateTime>,
+}
+
+/// 管理操作记录
+#[derive(Queryable, Debug, Clone)]
+pub struct AuditLog {
+    pub id: i32,
+    /// 执行命令的用户 id
+    pub actor_id: i64,
+    pub command: String,
+    /// 操作的画廊的 id
+    pub target: Option<i32>,
+    pub args: String,
+    /// 执行结果，成功为 ok，否则为错误信息
+    pub result: String,
+    pub created_at: NaiveDateTime,
+}
+
+/// 用户权限，按权限从低到高排序
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    /// 默认权限，只能查询
+    Viewer,
+    /// 可以上传画廊、更新 tag
+    Uploader,
+    /// 可以删除和重新发布画廊，频道和讨论组的管理员自动拥有该权限
+    Admin,
+    /// 可以授予管理员权限
+    Owner,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Viewer => "viewer",
+            Self::Uploader => "uploader",
+            Self::Admin => "admin",
+            Self::Owner => "owner",
+        }
+    }
+
+    /// 是否可以授予或收回指定权限，只能管理比自己低的权限，owner 除外
+    pub fn can_manage(&self, role: Role) -> bool {
+        *self == Self::Owner || role < *self
+    }
+}
+
+impl FromStr for Role {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "viewer" => Ok(Self::Viewer),
+            "uploader" => Ok(Self::Uploader),
+            "admin" => Ok(Self::Admin),
+            "owner" => Ok(Self::Owner),
+            _ => Err(anyhow!("未知的权限：{}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Queryable, Debug, Clone)]
+pub struct UploadJob {
+    pub id: i32,
+    pub kind: String,
+    pub target: String,
+    pub profile: String,
+    pub state: String,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub next_retry_at: NaiveDateTime,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(QueryableByName)]
+struct FtsMatch {
+    #[sql_type = "Integer"]
+    rowid: i32,
+}
+
+/// 全文搜索时最多取出的结果数量
+const MAX_FTS_MATCHES: i64 = 500;
+
+/// 因评分或收藏数不足被过滤的画廊，多少天后重新检查
+const REJECTED_TTL_DAYS: i64 = 3;
+
+/// 画廊搜索条件
+///
+/// 由空格分隔的若干项组成，支持以下格式，其余内容作为关键词：
+/// - `tag:namespace:value` 或 `tag:value`：包含指定 tag，value 中的 `_` 视为空格
+/// - `score>80`、`score<20`：评分范围
+/// - `date:7d`：最近几天内发布，单位可以是 d、w、m、y
+#[derive(Debug, Default, PartialEq)]
+pub struct SearchQuery {
+    /// FTS5 查询语句
+    pub keywords: Option<String>,
+    pub tags: Vec<(Option<String>, String)>,
+    pub min_score: Option<f32>,
+    pub max_score: Option<f32>,
+    pub since: Option<NaiveDate>,
+}
+
+impl SearchQuery {
+    pub fn parse(s: &str) -> Self {
+        let mut query = Self::default();
+        let mut keywords = vec![];
+        for word in s.split_whitespace() {
+            if let Some(tag) = word.strip_prefix("tag:") {
+                let (namespace, tag) = match tag.split_once(':') {
+                    Some((ns, tag)) => (Some(ns.to_lowercase()), tag),
+                    None => (None, tag),
+                };
+                query
+                    .tags
+                    .push((namespace, tag.replace('_', " ").to_lowercase()));
+            } else if let Some(score) = word.strip_prefix("score>").and_then(parse_score) {
+                query.min_score = Some(score);
+            } else if let Some(score) = word.strip_prefix("score<").and_then(parse_score) {
+                query.max_score = Some(score);
+            } else if let Some(since) = word.strip_prefix("date:").and_then(parse_since) {
+                query.since = Some(since);
+            } else {
+                keywords.push(word);
+            }
+        }
+        query.keywords = fts_query(&keywords.join(" "));
+        query
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// 将百分制的评分转换为数据库中的评分
+fn parse_score(s: &str) -> Option<f32> {
+    s.parse::<f32>().ok().map(|v| v / 100.)
+}
+
+/// 按时间查询时最多往前查询的天数
+const MAX_DAYS: i64 = 36500;
+
+/// 解析形如 7d、2w 的时间长度，返回天数，超出范围时返回 None
+fn parse_days(s: &str) -> Option<i64> {
+    let unit = match s.chars().last()? {
+        'd' => 1,
+        'w' => 7,
+        'm' => 30,
+        'y' => 365,
+        _ => return None,
+    };
+    let days = s[..s.len() - 1].parse::<i64>().ok()?.checked_mul(unit)?;
+    (0..=MAX_DAYS).contains(&days).then_some(days)
+}
+
+/// 解析时间长度，返回对应的起始日期
+fn parse_since(s: &str) -> Option<NaiveDate> {
+    let days = parse_days(s)?;
+    Utc::today()
+        .naive_utc()
+        .checked_sub_signed(chrono::Duration::days(days))
+}
+
+/// 在事务中执行迁移后回滚，以此判断是否有尚未执行的迁移
+fn has_pending_migrations(conn: &SqliteConnection) -> Result<bool> {
+    let mut output = vec![];
+    let result = conn.transaction::<(), anyhow::Error, _>(|| {
+        embedded_migrations::run_with_output(conn, &mut output)?;
+        Err(diesel::result::Error::RollbackTransaction.into())
+    });
+    match result {
+        Err(e) => match e.downcast_ref::<diesel::result::Error>() {
+            Some(diesel::result::Error::RollbackTransaction) => Ok(!output.is_empty()),
+            _ => Err(e),
+        },
+        Ok(_) => unreachable!(),
+    }
+}
+
+/// 禁止连接写入数据库
+#[derive(Debug)]
+struct QueryOnly;
+
+impl CustomizeConnection<SqliteConnection, diesel::r2d2::Error> for QueryOnly {
+    fn on_acquire(&self, conn: &mut SqliteConnection) -> Result<(), diesel::r2d2::Error> {
+        conn.batch_execute("PRAGMA query_only = ON")
+            .map_err(diesel::r2d2::Error::QueryError)
+    }
 }
 
 pub struct DataBase {
     pool: Pool<ConnectionManager<SqliteConnection>>,
+    /// 建立全文索引时用于翻译 tag
+    trans: Arc<trans::Database>,
 }
 
 impl DataBase {
-    pub fn init() -> Result<Self> {
+    pub fn init(url: &str, trans: Arc<trans::Database>) -> Result<Self> {
         info!("数据库初始化中……");
-        let url = env::var("DATABASE_URL").expect("请设置 DATABASE_URL");
         let manager = ConnectionManager::new(url);
         let pool = Pool::builder()
             .max_size(16)
             .build(manager)
             .expect("连接池建立失败");
-        embedded_migrations::run_with_output(&pool.get()?, &mut std::io::stdout())?;
-        Ok(Self { pool })
+        // 迁移信息写入日志而不是标准输出，以免混入 db export - 等命令的输出
+        let mut output = vec![];
+        embedded_migrations::run_with_output(&pool.get()?, &mut output)?;
+        for line in String::from_utf8_lossy(&output).lines() {
+            info!("{}", line);
+        }
+        let db = Self { pool, trans };
+        db.rebuild_fts_if_empty()?;
+        Ok(db)
+    }
+
+    /// 以只读方式打开数据库，不执行迁移，有尚未执行的迁移时返回错误，用于试运行
+    pub fn open_read_only(url: &str, trans: Arc<trans::Database>) -> Result<Self> {
+        // 建立连接时 SQLite 会自动创建不存在的数据库文件
+        if !std::path::Path::new(url).is_file() {
+            bail!("数据库文件不存在：{}", url);
+        }
+        if has_pending_migrations(&SqliteConnection::establish(url)?)? {
+            bail!("数据库有尚未执行的迁移，请先运行 exloli db migrate");
+        }
+        let manager = ConnectionManager::new(url);
+        let pool = Pool::builder()
+            .max_size(4)
+            .connection_customizer(Box::new(QueryOnly))
+            .build(manager)
+            .expect("连接池建立失败");
+        Ok(Self { pool, trans })
+    }
+
+    /// 建立全文索引时使用的翻译数据库
+    pub fn trans(&self) -> Arc<trans::Database> {
+        self.trans.clone()
+    }
+
+    /// 全文索引为空时从 gallery 表重建，旧数据没有记录日文标题
+    fn rebuild_fts_if_empty(&self) -> Result<()> {
+        let conn = self.pool.get()?;
+        let indexed = diesel::select(sql::<BigInt>("(SELECT count(*) FROM gallery_fts)"))
+            .get_result::<i64>(&conn)?;
+        if indexed != 0 {
+            return Ok(());
+        }
+        let galleries = gallery::table.load::<Gallery>(&conn)?;
+        if galleries.is_empty() {
+            return Ok(());
+        }
+        info!("重建全文索引：{} 个画廊", galleries.len());
+        conn.transaction::<_, anyhow::Error, _>(|| {
+            for g in &galleries {
+                let tags =
+                    serde_json::from_str::<Vec<(String, Vec<String>)>>(&g.tags).unwrap_or_default();
+                self.replace_fts(&conn, g.id, &g.title, None, &tags)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// 更新画廊的全文索引
+    fn replace_fts(
+        &self,
+        conn: &SqliteConnection,
+        id: i32,
+        title: &str,
+        title_jp: Option<&str>,
+        tags: &[(String, Vec<String>)],
+    ) -> Result<()> {
+        diesel::sql_query("DELETE FROM gallery_fts WHERE rowid = ?")
+            .bind::<Integer, _>(id)
+            .execute(conn)?;
+        diesel::sql_query(
+            "INSERT INTO gallery_fts (rowid, title, title_jp, tags) VALUES (?, ?, ?, ?)",
+        )
+        .bind::<Integer, _>(id)
+        .bind::<Text, _>(fts_segment(title))
+        .bind::<Text, _>(fts_segment(title_jp.unwrap_or_default()))
+        .bind::<Text, _>(fts_tags(&self.trans, tags))
+        .execute(conn)?;
+        Ok(())
+    }
+
+    /// 搜索画廊，不包括已删除的画廊
+    ///
+    /// 有关键词时按相关度排序，且只在相关度最高的 MAX_FTS_MATCHES 个结果中筛选，否则按发布时间倒序排列
+    pub fn search_gallery(
+        &self,
+        query: &SearchQuery,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<Gallery>> {
+        let conn = self.pool.get()?;
+        let fts_ids = match &query.keywords {
+            Some(keywords) => {
+                let ids = diesel::sql_query(
+                    "SELECT rowid FROM gallery_fts WHERE gallery_fts MATCH ? ORDER BY rank LIMIT ?",
+                )
+                .bind::<Text, _>(keywords)
+                .bind::<BigInt, _>(MAX_FTS_MATCHES)
+                .load::<FtsMatch>(&conn)?;
+                Some(ids.into_iter().map(|m| m.rowid).collect::<Vec<_>>())
+            }
+            None => None,
+        };
+
+        let mut sql = gallery::table
+            .filter(gallery::status.eq_any(GalleryStatus::visible()))
+            .into_boxed();
+        if let Some(ids) = &fts_ids {
+            sql = sql.filter(gallery::id.eq_any(ids.clone()));
+        }
+        for (namespace, tag) in &query.tags {
+            let mut tagged = gallery_tag::table
+                .filter(gallery_tag::tag.eq(tag.clone()))
+                .select(gallery_tag::gallery_ref)
+                .into_boxed();
+            if let Some(namespace) = namespace {
+                tagged = tagged.filter(gallery_tag::namespace.eq(namespace.clone()));
+            }
+            sql = sql.filter(gallery::id.eq_any(tagged));
+        }
+        if let Some(score) = query.min_score {
+            sql = sql.filter(gallery::score.gt(score));
+        }
+        if let Some(score) = query.max_score {
+            sql = sql.filter(gallery::score.lt(score));
+        }
+        if let Some(date) = query.since {
+            sql = sql.filter(gallery::publish_date.ge(date));
+        }
+
+        match fts_ids {
+            // 按照全文索引给出的相关度排序
+            Some(ids) => {
+                let mut galleries = sql.load::<Gallery>(&conn)?;
+                galleries.sort_by_key(|g| ids.iter().position(|&id| id == g.id));
+                Ok(galleries
+                    .into_iter()
+                    .skip(offset as usize)
+                    .take(limit as usize)
+                    .collect())
+            }
+            None => Ok(sql
+                .order_by(gallery::publish_date.desc())
+                .offset(offset)
+                .limit(limit)
+                .load::<Gallery>(&conn)?),
+        }
     }
 
-    pub fn insert_image(&self, image_url: &str, uploaded_url: &str) -> Result<()> {
+    pub fn insert_image(&self, image_url: &str, uploaded: &[String], host: &str) -> Result<()> {
         let hash = get_hash_from_image(image_url).context("图片哈希提取失败")?;
         let img = ImageHash {
             hash: hash.to_owned(),
-            url: uploaded_url.to_owned(),
+            url: serde_json::to_string(uploaded)?,
+            host: host.to_owned(),
         };
         diesel::insert_or_ignore_into(image_hash::table)
             .values(&img)
@@ -70,12 +540,43 @@ impl DataBase {
         Ok(())
     }
 
-    pub fn query_image_by_hash(&self, image_url: &str) -> Result<String> {
+    /// 查询图片在指定图床上的缓存
+    pub fn query_image_by_hash(&self, image_url: &str, host: &str) -> Result<Vec<String>> {
         let hash = get_hash_from_image(image_url).context("无法提取图片 hash")?;
-        Ok(image_hash::table
+        let image = image_hash::table
             .filter(image_hash::hash.eq(hash))
-            .get_result::<ImageHash>(&self.pool.get()?)?
-            .url)
+            .filter(image_hash::host.eq(host))
+            .get_result::<ImageHash>(&self.pool.get()?)?;
+        Ok(serde_json::from_str(&image.url)?)
+    }
+
+    /// 查询画廊的图片上传进度，键为图片序号
+    pub fn query_image_progress(&self, gallery_id: i32) -> Result<HashMap<i32, GalleryImage>> {
+        Ok(gallery_image::table
+            .filter(gallery_image::gallery_id.eq(gallery_id))
+            .load::<GalleryImage>(&self.pool.get()?)?
+            .into_iter()
+            .map(|image| (image.page, image))
+            .collect())
+    }
+
+    pub fn insert_image_progress(
+        &self,
+        gallery_id: i32,
+        page: i32,
+        urls: Option<&[String]>,
+        error: Option<&str>,
+    ) -> Result<()> {
+        let image = GalleryImage {
+            gallery_id,
+            page,
+            url: urls.map(serde_json::to_string).transpose()?,
+            error: error.map(String::from),
+        };
+        diesel::replace_into(gallery_image::table)
+            .values(&image)
+            .execute(&self.pool.get()?)?;
+        Ok(())
     }
 
     pub fn query_image_by_fileindex(&self, image_url: &str) -> Result<String> {
@@ -86,72 +587,263 @@ impl DataBase {
             .url)
     }
 
+    /// 记录新发布的画廊，返回画廊的 id
     pub fn insert_gallery(
         &self,
         message_id: i32,
         info: &FullGalleryInfo,
         telegraph: String,
-    ) -> Result<()> {
+        post_mode: PostMode,
+        next_check_at: Option<NaiveDateTime>,
+    ) -> Result<i32> {
         debug!("添加新画廊");
         let (gallery_id, token) = get_id_from_gallery(&info.url);
-        let gallery = Gallery {
-            title: info.title.to_owned(),
-            tags: serde_json::to_string(&info.tags)?,
-            publish_date: Utc::today().naive_utc(),
-            score: 0.0,
-            votes: "[]".to_string(),
-            upload_images: info.get_image_lists().len() as i16,
-            poll_id: "".to_string(),
-            telegraph,
-            gallery_id,
-            token,
-            message_id,
-        };
-        diesel::insert_into(gallery::table)
-            .values(&gallery)
-            .execute(&self.pool.get()?)?;
-        Ok(())
+        let publish_date = Utc::today().naive_utc();
+        let conn = self.pool.get()?;
+        conn.transaction::<_, anyhow::Error, _>(|| {
+            diesel::insert_into(gallery::table)
+                .values((
+                    gallery::message_id.eq(message_id),
+                    gallery::gallery_id.eq(gallery_id),
+                    gallery::token.eq(token),
+                    gallery::title.eq(&info.title),
+                    gallery::tags.eq(serde_json::to_string(&info.tags)?),
+                    gallery::telegraph.eq(telegraph),
+                    gallery::upload_images.eq(info.get_image_lists().len() as i16),
+                    gallery::publish_date.eq(publish_date),
+                    gallery::poll_id.eq(""),
+                    gallery::score.eq(0.0),
+                    gallery::votes.eq("[]"),
+                    gallery::profile.eq(&info.profile.name),
+                    gallery::post_mode.eq(post_mode.as_str()),
+                    gallery::next_check_at.eq(next_check_at),
+                    gallery::status.eq(GalleryStatus::Active.as_str()),
+                ))
+                .execute(&conn)?;
+            let id = diesel::select(sql::<BigInt>("last_insert_rowid()"))
+                .get_result::<i64>(&conn)? as i32;
+            let title_jp = info.title_jp.as_deref();
+            self.replace_fts(&conn, id, &info.title, title_jp, &info.tags)?;
+            Self::replace_gallery_tags(&conn, id, &info.tags)?;
+            Ok(id)
+        })
     }
 
     // TODO: 根据 grep.app 上的代码优化一下自己的代码
     /// 更新旧画廊信息
     pub fn update_gallery(
         &self,
-        message_id: i32,
+        id: i32,
         info: &FullGalleryInfo,
         telegraph: &str,
         upload_images: usize,
     ) -> Result<()> {
         debug!("更新画廊数据");
         let (gallery_id, token) = get_id_from_gallery(&info.url);
+        let conn = self.pool.get()?;
+        conn.transaction::<_, anyhow::Error, _>(|| {
+            diesel::update(gallery::table)
+                .filter(gallery::id.eq(id))
+                .set((
+                    gallery::gallery_id.eq(gallery_id),
+                    gallery::title.eq(&info.title),
+                    gallery::token.eq(token),
+                    gallery::telegraph.eq(telegraph),
+                    gallery::tags.eq(serde_json::to_string(&info.tags)?),
+                    gallery::upload_images.eq(upload_images as i16),
+                ))
+                .execute(&conn)?;
+            let title_jp = info.title_jp.as_deref();
+            self.replace_fts(&conn, id, &info.title, title_jp, &info.tags)?;
+            Self::replace_gallery_tags(&conn, id, &info.tags)
+        })
+    }
+
+    /// 用新的 tag 替换 gallery_tag 表中画廊的 tag
+    fn replace_gallery_tags(
+        conn: &SqliteConnection,
+        id: i32,
+        tags: &[(String, Vec<String>)],
+    ) -> Result<()> {
+        let rows = tags
+            .iter()
+            .flat_map(|(ns, v)| {
+                v.iter().map(move |tag| {
+                    (
+                        gallery_tag::gallery_ref.eq(id),
+                        gallery_tag::namespace.eq(ns),
+                        gallery_tag::tag.eq(tag),
+                    )
+                })
+            })
+            .collect::<Vec<_>>();
+        diesel::delete(gallery_tag::table)
+            .filter(gallery_tag::gallery_ref.eq(id))
+            .execute(conn)?;
+        diesel::insert_or_ignore_into(gallery_tag::table)
+            .values(&rows)
+            .execute(conn)?;
+        Ok(())
+    }
+
+    /// 查询包含指定 tag 的画廊，按发布时间倒序排列，不包括已删除的画廊
+    pub fn query_gallery_by_tag(
+        &self,
+        namespace: &str,
+        tag: &str,
+        limit: i64,
+    ) -> Result<Vec<Gallery>> {
+        Ok(gallery::table
+            .inner_join(gallery_tag::table)
+            .filter(gallery_tag::namespace.eq(namespace))
+            .filter(gallery_tag::tag.eq(tag))
+            .filter(gallery::status.eq_any(GalleryStatus::visible()))
+            .select(gallery::all_columns)
+            .order_by(gallery::publish_date.desc())
+            .limit(limit)
+            .load::<Gallery>(&self.pool.get()?)?)
+    }
+
+    /// 统计包含指定 tag 的画廊数量，不包括已删除的画廊
+    pub fn count_gallery_by_tag(&self, namespace: &str, tag: &str) -> Result<i64> {
+        Ok(gallery::table
+            .inner_join(gallery_tag::table)
+            .filter(gallery_tag::namespace.eq(namespace))
+            .filter(gallery_tag::tag.eq(tag))
+            .filter(gallery::status.eq_any(GalleryStatus::visible()))
+            .count()
+            .get_result::<i64>(&self.pool.get()?)?)
+    }
+
+    /// 查询指定日期范围内出现次数最多的 n 个 tag，返回 (namespace, tag, 次数)
+    pub fn query_top_tags(
+        &self,
+        from: NaiveDate,
+        to: NaiveDate,
+        n: i64,
+    ) -> Result<Vec<(String, String, i64)>> {
+        Ok(gallery_tag::table
+            .inner_join(gallery::table)
+            .filter(gallery::publish_date.between(from, to))
+            .filter(gallery::status.eq_any(GalleryStatus::visible()))
+            .group_by((gallery_tag::namespace, gallery_tag::tag))
+            .select((
+                gallery_tag::namespace,
+                gallery_tag::tag,
+                sql::<BigInt>("count(*)"),
+            ))
+            .order_by(sql::<BigInt>("count(*) DESC"))
+            .limit(n)
+            .load::<(String, String, i64)>(&self.pool.get()?)?)
+    }
+
+    /// 按状态统计画廊数量，返回 (状态, 数量)
+    pub fn count_gallery_by_status(&self) -> Result<Vec<(String, i64)>> {
+        Ok(gallery::table
+            .group_by(gallery::status)
+            .select((gallery::status, sql::<BigInt>("count(*)")))
+            .order_by(gallery::status)
+            .load::<(String, i64)>(&self.pool.get()?)?)
+    }
+
+    /// 查询画廊的所有 telegraph 文章路径，旧数据只记录了第一篇文章
+    pub fn query_telegraph_pages(&self, gallery: &Gallery) -> Result<Vec<String>> {
+        let paths = telegraph_page::table
+            .filter(telegraph_page::gallery_ref.eq(gallery.id))
+            .order_by(telegraph_page::part)
+            .select(telegraph_page::path)
+            .load::<String>(&self.pool.get()?)?;
+        if paths.is_empty() {
+            return Ok(vec![extract_telegraph_path(&gallery.telegraph).to_owned()]);
+        }
+        Ok(paths)
+    }
+
+    /// 记录画廊的所有 telegraph 文章路径
+    pub fn update_telegraph_pages(&self, id: i32, paths: &[String]) -> Result<()> {
+        let pages = paths
+            .iter()
+            .enumerate()
+            .map(|(part, path)| TelegraphPage {
+                gallery_ref: id,
+                part: part as i32,
+                path: path.to_owned(),
+            })
+            .collect::<Vec<_>>();
+        let conn = self.pool.get()?;
+        conn.transaction::<_, anyhow::Error, _>(|| {
+            diesel::delete(telegraph_page::table)
+                .filter(telegraph_page::gallery_ref.eq(id))
+                .execute(&conn)?;
+            diesel::insert_into(telegraph_page::table)
+                .values(&pages)
+                .execute(&conn)?;
+            Ok(())
+        })
+    }
+
+    /// 查询需要检查更新的画廊，按检查时间排序
+    pub fn query_due_galleries(&self, limit: i64) -> Result<Vec<Gallery>> {
+        let now = Utc::now().naive_utc();
+        Ok(gallery::table
+            .filter(gallery::next_check_at.le(now))
+            .filter(gallery::status.eq(GalleryStatus::Active.as_str()))
+            .order_by(gallery::next_check_at)
+            .limit(limit)
+            .load::<Gallery>(&self.pool.get()?)?)
+    }
+
+    /// 设置画廊下一次检查更新的时间
+    pub fn update_next_check(&self, id: i32, next: Option<NaiveDateTime>) -> Result<()> {
         diesel::update(gallery::table)
-            .filter(gallery::message_id.eq(message_id))
+            .filter(gallery::id.eq(id))
+            .set(gallery::next_check_at.eq(next))
+            .execute(&self.pool.get()?)?;
+        Ok(())
+    }
+
+    /// 修改 active 画廊的状态，被删除等非 active 的画廊保持原状态，修改后不再检查更新
+    pub fn update_status(&self, id: i32, status: GalleryStatus) -> Result<()> {
+        diesel::update(gallery::table)
+            .filter(gallery::id.eq(id))
+            .filter(gallery::status.eq(GalleryStatus::Active.as_str()))
             .set((
-                gallery::gallery_id.eq(gallery_id),
-                gallery::title.eq(&info.title),
-                gallery::token.eq(token),
-                gallery::telegraph.eq(telegraph),
-                gallery::tags.eq(serde_json::to_string(&info.tags)?),
-                gallery::upload_images.eq(upload_images as i16),
+                gallery::status.eq(status.as_str()),
+                gallery::next_check_at.eq(None::<NaiveDateTime>),
             ))
             .execute(&self.pool.get()?)?;
         Ok(())
     }
 
-    /// 根据消息 id 删除画廊，并不会实际删除，否则又会在定时更新时被上传
-    pub fn delete_gallery(&self, message_id: i32) -> Result<()> {
+    /// 删除画廊，并不会实际删除，否则又会在定时更新时被上传
+    pub fn delete_gallery(&self, id: i32, user_id: i64) -> Result<()> {
         diesel::update(gallery::table)
-            .filter(gallery::message_id.eq(message_id))
-            .set(gallery::score.eq(-1.0))
+            .filter(gallery::id.eq(id))
+            .set((
+                gallery::status.eq(GalleryStatus::DeletedByAdmin.as_str()),
+                gallery::deleted_by.eq(user_id),
+                gallery::deleted_at.eq(Utc::now().naive_utc()),
+                gallery::next_check_at.eq(None::<NaiveDateTime>),
+            ))
             .execute(&self.pool.get()?)?;
         Ok(())
     }
 
-    /// 根据消息 id 删除画廊，这是真的删除
-    pub fn real_delete_gallery(&self, message_id: i32) -> Result<()> {
+    /// 删除画廊，这是真的删除
+    pub fn real_delete_gallery(&self, id: i32) -> Result<()> {
+        let conn = self.pool.get()?;
         diesel::delete(gallery::table)
-            .filter(gallery::message_id.eq(message_id))
-            .execute(&self.pool.get()?)?;
+            .filter(gallery::id.eq(id))
+            .execute(&conn)?;
+        diesel::delete(telegraph_page::table)
+            .filter(telegraph_page::gallery_ref.eq(id))
+            .execute(&conn)?;
+        diesel::delete(gallery_tag::table)
+            .filter(gallery_tag::gallery_ref.eq(id))
+            .execute(&conn)?;
+        diesel::sql_query("DELETE FROM gallery_fts WHERE rowid = ?")
+            .bind::<Integer, _>(id)
+            .execute(&conn)?;
         Ok(())
     }
 
@@ -175,7 +867,7 @@ impl DataBase {
                 gallery::publish_date
                     .ge(to)
                     .and(gallery::publish_date.le(from))
-                    .and(gallery::score.ne(-1.0))
+                    .and(gallery::status.eq_any(GalleryStatus::visible()))
                     .and(gallery::poll_id.ne("")),
             )
             .order_by((ordering, gallery::publish_date.desc()))
@@ -187,7 +879,8 @@ impl DataBase {
 
     pub fn get_rank(&self, score: f32) -> Result<f32> {
         Ok(gallery::table
-            .filter(gallery::poll_id.ne("").and(gallery::score.ge(0.0)))
+            .filter(gallery::poll_id.ne(""))
+            .filter(gallery::status.eq_any(GalleryStatus::visible()))
             .select(sql(&format!(
                 "sum(IIF(score >= {}, 1., 0.)) / count(*)",
                 score
@@ -195,21 +888,14 @@ impl DataBase {
             .get_result::<f32>(&self.pool.get()?)?)
     }
 
-    pub fn update_poll_id(&self, message_id: i32, poll_id: &str) -> Result<()> {
+    pub fn update_poll_id(&self, id: i32, poll_id: &str) -> Result<()> {
         diesel::update(gallery::table)
-            .filter(gallery::message_id.eq(message_id))
+            .filter(gallery::id.eq(id))
             .set(gallery::poll_id.eq(poll_id))
             .execute(&self.pool.get()?)?;
         Ok(())
     }
 
-    pub fn query_poll_id(&self, message_id: i32) -> Result<String> {
-        Ok(gallery::table
-            .filter(gallery::message_id.eq(message_id))
-            .select(gallery::poll_id)
-            .get_result::<String>(&self.pool.get()?)?)
-    }
-
     pub fn insert_vote(&self, user_id: u64, poll_id: i32, option: i32) -> Result<()> {
         diesel::replace_into(user_vote::table)
             .values(&vec![(
@@ -246,20 +932,617 @@ impl DataBase {
         let (id, _) = get_id_from_gallery(url);
         Ok(gallery::table
             .filter(gallery::gallery_id.eq(id))
-            .order_by(gallery::publish_date.desc())
+            .order_by((gallery::publish_date.desc(), gallery::id.desc()))
             .limit(1)
             .get_result::<Gallery>(&self.pool.get()?)?)
     }
 
-    pub fn query_gallery(&self, message_id: i32) -> Result<Gallery> {
+    /// 记录被过滤的画廊，避免每次扫描时重复获取，评分等会变化的原因只记录一段时间
+    pub fn insert_rejected(&self, info: &FullGalleryInfo, rejection: &Rejection) -> Result<()> {
+        let (gallery_id, token) = get_id_from_gallery(&info.url);
+        let now = Utc::now().naive_utc();
+        let rejected = Rejected {
+            gallery_id,
+            token,
+            title: info.title.to_owned(),
+            reason: rejection.reason.to_owned(),
+            rejected_at: now,
+            expires_at: rejection
+                .temporary
+                .then(|| now + chrono::Duration::days(REJECTED_TTL_DAYS)),
+        };
+        diesel::replace_into(rejected::table)
+            .values(&rejected)
+            .execute(&self.pool.get()?)?;
+        Ok(())
+    }
+
+    /// 查询未过期的过滤记录
+    pub fn query_rejected(&self, url: &str) -> Result<Rejected> {
+        let (id, _) = get_id_from_gallery(url);
+        let now = Utc::now().naive_utc();
+        Ok(rejected::table
+            .filter(rejected::gallery_id.eq(id))
+            .filter(
+                rejected::expires_at
+                    .is_null()
+                    .or(rejected::expires_at.gt(now)),
+            )
+            .get_result::<Rejected>(&self.pool.get()?)?)
+    }
+
+    /// 清理已过期的过滤记录，以及原因不在 reasons 中的记录（对应的规则已被删除），返回清理的数量
+    pub fn clean_rejected(&self, reasons: &[&str]) -> Result<usize> {
+        let now = Utc::now().naive_utc();
+        Ok(diesel::delete(
+            rejected::table.filter(
+                rejected::reason
+                    .ne_all(reasons.to_vec())
+                    .or(rejected::expires_at.le(now)),
+            ),
+        )
+        .execute(&self.pool.get()?)?)
+    }
+
+    /// 记录已发布画廊的新版本
+    pub fn insert_version(&self, url: &str, parent: &Gallery, action: VersionPolicy) -> Result<()> {
+        let (gallery_id, token) = get_id_from_gallery(url);
+        let version = GalleryVersion {
+            gallery_id,
+            token,
+            parent_id: parent.gallery_id,
+            parent_token: parent.token.clone(),
+            parent_ref: parent.id,
+            action: action.as_str().to_owned(),
+            created_at: Utc::now().naive_utc(),
+        };
+        diesel::replace_into(gallery_version::table)
+            .values(&version)
+            .execute(&self.pool.get()?)?;
+        Ok(())
+    }
+
+    pub fn query_version(&self, url: &str) -> Result<GalleryVersion> {
+        let (id, _) = get_id_from_gallery(url);
+        Ok(gallery_version::table
+            .find(id)
+            .get_result::<GalleryVersion>(&self.pool.get()?)?)
+    }
+
+    /// 查询画廊所在的版本链，按从旧到新的顺序返回每一次版本更新
+    pub fn query_version_chain(&self, gallery_id: i32) -> Result<Vec<GalleryVersion>> {
+        let conn = self.pool.get()?;
+        let mut chain: Vec<GalleryVersion> = vec![];
+        let seen = |chain: &[GalleryVersion], v: &GalleryVersion| {
+            chain.iter().any(|c| c.gallery_id == v.gallery_id)
+        };
+        // 向前查找旧版本
+        let mut id = gallery_id;
+        while let Some(v) = gallery_version::table
+            .find(id)
+            .get_result::<GalleryVersion>(&conn)
+            .optional()?
+        {
+            if seen(&chain, &v) {
+                break;
+            }
+            id = v.parent_id;
+            chain.insert(0, v);
+        }
+        // 向后查找新版本
+        let mut id = gallery_id;
+        while let Some(v) = gallery_version::table
+            .filter(gallery_version::parent_id.eq(id))
+            .order_by(gallery_version::created_at.desc())
+            .first::<GalleryVersion>(&conn)
+            .optional()?
+        {
+            if seen(&chain, &v) {
+                break;
+            }
+            id = v.gallery_id;
+            chain.push(v);
+        }
+        Ok(chain)
+    }
+
+    /// 添加上传任务，若已有相同的任务在等待或执行中，则直接返回其 id
+    pub fn insert_job(&self, kind: JobKind, target: &str, profile: &str) -> Result<i32> {
+        let conn = self.pool.get()?;
+        let active = [JobState::Pending.as_str(), JobState::Running.as_str()];
+        let exists = upload_job::table
+            .filter(upload_job::kind.eq(kind.as_str()))
+            .filter(upload_job::target.eq(target))
+            .filter(upload_job::state.eq_any(active))
+            .select(upload_job::id)
+            .first::<i32>(&conn)
+            .optional()?;
+        if let Some(id) = exists {
+            return Ok(id);
+        }
+        let now = Utc::now().naive_utc();
+        diesel::insert_into(upload_job::table)
+            .values((
+                upload_job::kind.eq(kind.as_str()),
+                upload_job::target.eq(target),
+                upload_job::profile.eq(profile),
+                upload_job::state.eq(JobState::Pending.as_str()),
+                upload_job::attempts.eq(0),
+                upload_job::next_retry_at.eq(now),
+                upload_job::created_at.eq(now),
+                upload_job::updated_at.eq(now),
+            ))
+            .execute(&conn)?;
+        let id = diesel::select(sql::<BigInt>("last_insert_rowid()")).get_result::<i64>(&conn)?;
+        Ok(id as i32)
+    }
+
+    /// 取出一个到期的任务并标记为执行中
+    pub fn take_job(&self) -> Result<Option<UploadJob>> {
+        let conn = self.pool.get()?;
+        conn.transaction::<_, anyhow::Error, _>(|| {
+            let now = Utc::now().naive_utc();
+            let job = upload_job::table
+                .filter(upload_job::state.eq(JobState::Pending.as_str()))
+                .filter(upload_job::next_retry_at.le(now))
+                .order_by(upload_job::id.asc())
+                .first::<UploadJob>(&conn)
+                .optional()?;
+            let job = match job {
+                Some(v) => v,
+                None => return Ok(None),
+            };
+            // 其他线程可能已经取走了该任务
+            let updated = diesel::update(upload_job::table)
+                .filter(upload_job::id.eq(job.id))
+                .filter(upload_job::state.eq(JobState::Pending.as_str()))
+                .set((
+                    upload_job::state.eq(JobState::Running.as_str()),
+                    upload_job::attempts.eq(job.attempts + 1),
+                    upload_job::updated_at.eq(now),
+                ))
+                .execute(&conn)?;
+            Ok((updated == 1).then_some(job))
+        })
+    }
+
+    pub fn finish_job(&self, id: i32) -> Result<()> {
+        diesel::update(upload_job::table)
+            .filter(upload_job::id.eq(id))
+            .set((
+                upload_job::state.eq(JobState::Done.as_str()),
+                upload_job::last_error.eq(None::<String>),
+                upload_job::updated_at.eq(Utc::now().naive_utc()),
+            ))
+            .execute(&self.pool.get()?)?;
+        Ok(())
+    }
+
+    /// 记录任务失败，未达到最大尝试次数的任务会在一段时间后重试
+    pub fn fail_job(&self, job: &UploadJob, error: &str) -> Result<()> {
+        let attempts = job.attempts + 1;
+        let now = Utc::now().naive_utc();
+        let state = match attempts >= MAX_ATTEMPTS {
+            true => JobState::Failed,
+            false => JobState::Pending,
+        };
+        diesel::update(upload_job::table)
+            .filter(upload_job::id.eq(job.id))
+            .set((
+                upload_job::state.eq(state.as_str()),
+                upload_job::last_error.eq(error),
+                upload_job::next_retry_at.eq(now + retry_delay(attempts)),
+                upload_job::updated_at.eq(now),
+            ))
+            .execute(&self.pool.get()?)?;
+        Ok(())
+    }
+
+    /// 将上次退出时仍在执行中的任务重新放回队列
+    pub fn reset_running_jobs(&self) -> Result<usize> {
+        Ok(diesel::update(upload_job::table)
+            .filter(upload_job::state.eq(JobState::Running.as_str()))
+            .set(upload_job::state.eq(JobState::Pending.as_str()))
+            .execute(&self.pool.get()?)?)
+    }
+
+    /// 按状态统计上传任务数量，返回 (状态, 数量)
+    pub fn count_jobs_by_state(&self) -> Result<Vec<(String, i64)>> {
+        Ok(upload_job::table
+            .group_by(upload_job::state)
+            .select((upload_job::state, sql::<BigInt>("count(*)")))
+            .order_by(upload_job::state)
+            .load::<(String, i64)>(&self.pool.get()?)?)
+    }
+
+    /// 查询最近的 n 个任务
+    pub fn query_jobs(&self, n: i64) -> Result<Vec<UploadJob>> {
+        Ok(upload_job::table
+            .order_by(upload_job::id.desc())
+            .limit(n)
+            .load::<UploadJob>(&self.pool.get()?)?)
+    }
+
+    /// 记录一次管理操作
+    pub fn insert_audit_log(
+        &self,
+        actor_id: i64,
+        command: &str,
+        target: Option<i32>,
+        args: &str,
+        result: &str,
+    ) -> Result<()> {
+        diesel::insert_into(audit_log::table)
+            .values((
+                audit_log::actor_id.eq(actor_id),
+                audit_log::command.eq(command),
+                audit_log::target.eq(target),
+                audit_log::args.eq(args),
+                audit_log::result.eq(result),
+                audit_log::created_at.eq(Utc::now().naive_utc()),
+            ))
+            .execute(&self.pool.get()?)?;
+        Ok(())
+    }
+
+    /// 查询最近的 n 条管理操作记录，n 为负数时返回全部记录
+    pub fn query_audit_log(&self, n: i64) -> Result<Vec<AuditLog>> {
+        Ok(audit_log::table
+            .order_by(audit_log::id.desc())
+            .limit(n)
+            .load::<AuditLog>(&self.pool.get()?)?)
+    }
+
+    /// 查询用户在数据库中记录的权限，没有记录时返回 None
+    pub fn query_role(&self, user_id: i64) -> Result<Option<Role>> {
+        let role = user_role::table
+            .find(user_id)
+            .select(user_role::role)
+            .get_result::<String>(&self.pool.get()?)
+            .optional()?;
+        role.map(|s| s.parse()).transpose()
+    }
+
+    pub fn grant_role(&self, user_id: i64, role: Role, granted_by: i64) -> Result<()> {
+        diesel::replace_into(user_role::table)
+            .values((
+                user_role::user_id.eq(user_id),
+                user_role::role.eq(role.as_str()),
+                user_role::granted_by.eq(granted_by),
+                user_role::granted_at.eq(Utc::now().naive_utc()),
+            ))
+            .execute(&self.pool.get()?)?;
+        Ok(())
+    }
+
+    pub fn revoke_role(&self, user_id: i64) -> Result<()> {
+        diesel::delete(user_role::table.find(user_id)).execute(&self.pool.get()?)?;
+        Ok(())
+    }
+
+    /// 查询所有画廊，按 id 排序
+    pub fn query_all_galleries(&self) -> Result<Vec<Gallery>> {
         Ok(gallery::table
-            .filter(gallery::message_id.eq(message_id))
+            .order_by(gallery::id)
+            .load::<Gallery>(&self.pool.get()?)?)
+    }
+
+    pub fn query_gallery(&self, id: i32) -> Result<Gallery> {
+        Ok(gallery::table
+            .find(id)
             .get_result::<Gallery>(&self.pool.get()?)?)
     }
+
+    /// 根据频道消息 id 查询画廊，in_channel 用于判断画廊是否发布在消息所在的频道
+    pub fn query_gallery_by_message(
+        &self,
+        message_id: i32,
+        in_channel: impl Fn(&Gallery) -> bool,
+    ) -> Result<Gallery> {
+        gallery::table
+            .filter(gallery::message_id.eq(message_id))
+            .order_by(gallery::id.desc())
+            .load::<Gallery>(&self.pool.get()?)?
+            .into_iter()
+            .find(|g| in_channel(g))
+            .ok_or_else(|| anyhow!("找不到消息 {} 对应的画廊", message_id))
+    }
+}
+
+impl UploadJob {
+    pub fn kind(&self) -> Result<JobKind> {
+        self.kind.parse()
+    }
 }
 
 impl Gallery {
-    pub fn get_url(&self) -> String {
-        format!("https://{}/g/{}/{}/", *HOST, self.gallery_id, self.token)
+    pub fn post_mode(&self) -> PostMode {
+        self.post_mode.parse().unwrap_or_default()
+    }
+
+    pub fn status(&self) -> GalleryStatus {
+        self.status.parse().unwrap_or(GalleryStatus::Active)
+    }
+
+    /// 画廊地址，host 为 E 站的域名
+    pub fn get_url(&self, host: &str) -> String {
+        format!("https://{}/g/{}/{}/", host, self.gallery_id, self.token)
+    }
+
+    /// 画廊所在频道的消息直链
+    pub fn message_url(&self, config: &Config) -> String {
+        get_message_url(config.channel_id(&self.profile), self.message_id)
+    }
+}
+
+impl GalleryVersion {
+    pub fn get_url(&self, host: &str) -> String {
+        format!("https://{}/g/{}/{}/", host, self.gallery_id, self.token)
+    }
+
+    pub fn parent_url(&self, host: &str) -> String {
+        format!(
+            "https://{}/g/{}/{}/",
+            host, self.parent_id, self.parent_token
+        )
+    }
+}
+
+/// 在中日韩字符两侧加上空格，使 unicode61 分词器逐字索引，从而支持标题中间的片段搜索
+fn fts_segment(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len());
+    for c in s.chars() {
+        if is_cjk(c) {
+            ret.push(' ');
+            ret.push(c);
+            ret.push(' ');
+        } else {
+            ret.push(c);
+        }
+    }
+    ret
+}
+
+fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{3040}'..='\u{30ff}' // 平假名、片假名
+        | '\u{3400}'..='\u{4dbf}'
+        | '\u{4e00}'..='\u{9fff}'
+        | '\u{f900}'..='\u{faff}'
+        | '\u{ac00}'..='\u{d7af}' // 谚文
+    )
+}
+
+/// 全文索引中的 tag，同时包含原文和翻译
+fn fts_tags(db: &trans::Database, tags: &[(String, Vec<String>)]) -> String {
+    tags.iter()
+        .flat_map(|(ns, v)| v.iter().map(move |tag| (ns, tag)))
+        .map(|(ns, tag)| {
+            let trans = db.trans(ns, tag);
+            match trans == tag.as_str() {
+                true => fts_segment(tag),
+                false => format!("{} {}", tag, fts_segment(trans)),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// 将用户输入转换为 FTS5 查询，每个关键词作为一个短语，关键词之间为 AND 关系
+fn fts_query(s: &str) -> Option<String> {
+    let words = s
+        .split_whitespace()
+        .filter(|w| w.chars().any(char::is_alphanumeric))
+        .map(|w| format!("\"{}\"", fts_segment(w).replace('"', "\"\"")))
+        .collect::<Vec<_>>();
+    (!words.is_empty()).then(|| words.join(" "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_role() {
+        assert!(Role::Viewer < Role::Uploader && Role::Admin < Role::Owner);
+        assert_eq!("admin".parse::<Role>().unwrap(), Role::Admin);
+        assert!(Role::Admin.can_manage(Role::Uploader));
+        assert!(!Role::Admin.can_manage(Role::Admin));
+        assert!(Role::Owner.can_manage(Role::Owner));
+    }
+
+    #[test]
+    fn test_open_read_only() {
+        let (dir, db) = temp_db();
+        let trans = db.trans();
+        // 不存在的数据库文件不会被创建
+        let missing = dir.path().join("missing.db");
+        assert!(DataBase::open_read_only(&missing.display().to_string(), trans.clone()).is_err());
+        assert!(!missing.exists());
+        // 空数据库有尚未执行的迁移
+        let empty = dir.path().join("empty.db");
+        std::fs::File::create(&empty).unwrap();
+        let err = DataBase::open_read_only(&empty.display().to_string(), trans.clone()).err();
+        assert!(err.unwrap().to_string().contains("迁移"));
+
+        let url = dir.path().join("exloli.db").display().to_string();
+        let db = DataBase::open_read_only(&url, trans).unwrap();
+        assert!(db.count_gallery_by_status().unwrap().is_empty());
+        assert!(db.insert_job(JobKind::Upload, "url", "default").is_err());
+    }
+
+    fn temp_db() -> (tempfile::TempDir, DataBase) {
+        let trans = Arc::new(
+            trans::Database::load(concat!(env!("CARGO_MANIFEST_DIR"), "/db.text.json")).unwrap(),
+        );
+        let dir = tempfile::tempdir().unwrap();
+        let url = dir.path().join("exloli.db").display().to_string();
+        let db = DataBase::init(&url, trans).unwrap();
+        (dir, db)
+    }
+
+    fn insert_test_gallery(db: &DataBase, message_id: i32, gallery_id: i32, profile: &str) -> i32 {
+        let conn = db.pool.get().unwrap();
+        diesel::insert_into(gallery::table)
+            .values((
+                gallery::message_id.eq(message_id),
+                gallery::gallery_id.eq(gallery_id),
+                gallery::token.eq("abcdef"),
+                gallery::title.eq("title"),
+                gallery::tags.eq("[]"),
+                gallery::telegraph.eq(""),
+                gallery::upload_images.eq(0),
+                gallery::publish_date.eq(Utc::today().naive_utc()),
+                gallery::poll_id.eq(""),
+                gallery::score.eq(0.0),
+                gallery::votes.eq("[]"),
+                gallery::profile.eq(profile),
+            ))
+            .execute(&conn)
+            .unwrap();
+        diesel::select(sql::<BigInt>("last_insert_rowid()"))
+            .get_result::<i64>(&conn)
+            .unwrap() as i32
+    }
+
+    #[test]
+    fn test_same_message_id() {
+        let (_dir, db) = temp_db();
+        // 两个频道中的消息 id 相同
+        insert_test_gallery(&db, 10, 1, "default");
+        insert_test_gallery(&db, 10, 2, "other");
+        let a = db
+            .query_gallery_by_message(10, |g| g.profile == "default")
+            .unwrap();
+        let b = db
+            .query_gallery_by_message(10, |g| g.profile == "other")
+            .unwrap();
+        assert_ne!(a.id, b.id);
+        assert_eq!((a.gallery_id, b.gallery_id), (1, 2));
+        assert!(db.query_gallery_by_message(10, |_| false).is_err());
+    }
+
+    #[test]
+    fn test_replace_deleted_gallery() {
+        let (_dir, db) = temp_db();
+        let id = insert_test_gallery(&db, 10, 1, "default");
+        db.delete_gallery(id, 1).unwrap();
+        // 恢复后重新发布，被删除的旧消息不应该变为 replaced 而重新出现在排行中
+        let new_id = insert_test_gallery(&db, 11, 1, "default");
+        db.update_status(id, GalleryStatus::Replaced).unwrap();
+        let old = db.query_gallery(id).unwrap();
+        assert_eq!(old.status(), GalleryStatus::DeletedByAdmin);
+        assert_eq!(
+            db.query_gallery_by_url("https://exhentai.org/g/1/abcdef/")
+                .unwrap()
+                .id,
+            new_id
+        );
+    }
+
+    #[test]
+    fn test_rejected() {
+        let (_dir, db) = temp_db();
+        let now = Utc::now().naive_utc();
+        let rows = [
+            (1, "ai", None),
+            (2, "rating", Some(now + chrono::Duration::days(1))),
+            (3, "rating", Some(now - chrono::Duration::days(1))),
+            (4, "removed", None),
+        ];
+        for (gallery_id, reason, expires_at) in rows {
+            diesel::insert_into(rejected::table)
+                .values(&Rejected {
+                    gallery_id,
+                    token: "abcdef".to_owned(),
+                    title: "title".to_owned(),
+                    reason: reason.to_owned(),
+                    rejected_at: now,
+                    expires_at,
+                })
+                .execute(&db.pool.get().unwrap())
+                .unwrap();
+        }
+        let url = |id| format!("https://exhentai.org/g/{}/abcdef/", id);
+        assert!(db.query_rejected(&url(2)).is_ok());
+        assert!(db.query_rejected(&url(3)).is_err());
+
+        assert_eq!(db.clean_rejected(&["ai", "rating"]).unwrap(), 2);
+        assert!(db.query_rejected(&url(1)).is_ok());
+        assert!(db.query_rejected(&url(4)).is_err());
+        assert_eq!(db.clean_rejected(&[]).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_image_hash_host() {
+        let (_dir, db) = temp_db();
+        let page = "https://exhentai.org/s/03af734602/1932743-1";
+        let tiles = vec!["/file/a.jpg".to_owned(), "/file/b.jpg".to_owned()];
+        db.insert_image(page, &tiles, "telegraph").unwrap();
+        assert_eq!(db.query_image_by_hash(page, "telegraph").unwrap(), tiles);
+        // 换用其他图床后不使用 telegraph 上的缓存，且可以记录新的缓存
+        assert!(db.query_image_by_hash(page, "s3").is_err());
+        let url = vec!["https://cdn.example.com/a.jpg".to_owned()];
+        db.insert_image(page, &url, "s3").unwrap();
+        assert_eq!(db.query_image_by_hash(page, "s3").unwrap(), url);
+    }
+
+    #[test]
+    fn test_gallery_status() {
+        for status in GalleryStatus::VISIBLE {
+            assert_eq!(status.as_str().parse::<GalleryStatus>().unwrap(), status);
+        }
+        assert_eq!(
+            "deleted_by_admin".parse::<GalleryStatus>().unwrap(),
+            GalleryStatus::DeletedByAdmin
+        );
+        assert!("deleted".parse::<GalleryStatus>().is_err());
+    }
+
+    #[test]
+    fn test_fts_query() {
+        assert_eq!(fts_segment("ab東方c"), "ab 東  方 c");
+        assert_eq!(
+            fts_query(" 東方 say\"hi\" -- "),
+            Some(r#"" 東  方 " "say""hi""""#.to_owned())
+        );
+        assert_eq!(fts_query("  "), None);
+    }
+
+    #[test]
+    fn test_search_query() {
+        let query = SearchQuery::parse("東方 tag:female:big_breasts tag:Lolicon score>80 date:2w");
+        assert_eq!(query.keywords.as_deref(), Some(r#"" 東  方 ""#));
+        assert_eq!(
+            query.tags,
+            vec![
+                (Some("female".to_owned()), "big breasts".to_owned()),
+                (None, "lolicon".to_owned())
+            ]
+        );
+        assert_eq!(query.min_score, Some(0.8));
+        assert_eq!(query.max_score, None);
+        assert_eq!(
+            query.since,
+            Some(Utc::today().naive_utc() - chrono::Duration::days(14))
+        );
+
+        let query = SearchQuery::parse("score>abc date:7x");
+        assert_eq!(query.keywords.as_deref(), Some(r#""score>abc" "date:7x""#));
+        assert!(SearchQuery::parse("  ").is_empty());
+    }
+
+    #[test]
+    fn test_parse_days_out_of_range() {
+        assert_eq!(parse_days("100y"), Some(36500));
+        assert_eq!(parse_days("101y"), None);
+        assert_eq!(parse_days("100000000d"), None);
+        assert_eq!(parse_days("9223372036854775807y"), None);
+        assert_eq!(parse_days("-1d"), None);
+        for word in ["date:100000000d", "date:9223372036854775807w", "date:-5d"] {
+            let query = SearchQuery::parse(word);
+            assert_eq!(query.since, None);
+            assert_eq!(query.keywords, fts_query(word));
+        }
     }
 }
@@ -1,24 +1,29 @@
-use crate::utils::{download_to_temp, HOST};
-use crate::xpath::parse_html;
-use crate::{CONFIG, DB};
+use crate::config::{self, SearchProfile};
+use crate::context::AppContext;
+use crate::preprocess::preprocess;
+use crate::utils::{download_to_temp, get_id_from_gallery, unescape_html};
+use crate::xpath::{parse_html, Node};
 use anyhow::{Context, Result};
 use futures::executor::block_on;
 use futures::prelude::*;
-use once_cell::sync::Lazy;
 use reqwest::header::{self, HeaderMap, HeaderValue};
-use reqwest::{redirect::Policy, Client, Proxy, Response};
-use telegraph_rs::Telegraph;
+use reqwest::{redirect::Policy, Client, ClientBuilder, Proxy, Response, StatusCode};
+use serde::{de, Deserialize, Deserializer};
+use serde_json::json;
 use tokio::task::block_in_place;
 use tokio::time::sleep;
+use url::Url;
 
+use std::collections::HashMap;
+use std::fmt;
 use std::sync::atomic::{AtomicU32, Ordering};
-use std::sync::Arc;
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 macro_rules! set_header {
     ($($k:ident => $v:expr), *) => {{
         let mut headers = HeaderMap::new();
-        $(headers.insert(header::$k, HeaderValue::from_static($v));)*
+        $(headers.insert(header::$k, HeaderValue::from_str($v)?);)*
         headers
     }};
 }
@@ -29,28 +34,113 @@ macro_rules! send {
     };
 }
 
-pub static EXHENTAI: Lazy<ExHentai> =
-    Lazy::new(|| block_on(CONFIG.init_exhentai()).expect("登陆失败"));
-static REFERER: Lazy<String> = Lazy::new(|| format!("https://{}/", *HOST));
-static HEADERS: Lazy<HeaderMap> = Lazy::new(|| {
-    set_header! {
+/// 表站的登录地址
+const LOGIN_URL: &str = "https://forums.e-hentai.org/index.php";
+
+/// 创建访问指定站点的 client，站点地址取自搜索地址
+fn client_builder(search_url: &Url, proxy: Option<&str>) -> Result<ClientBuilder> {
+    let host = search_url.host_str().context("搜索地址中没有域名")?;
+    let host = match search_url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_owned(),
+    };
+    let referer = format!("{}/", search_url.origin().ascii_serialization());
+    let headers = set_header! {
         ACCEPT => "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8",
         ACCEPT_ENCODING => "gzip, deflate, br",
         ACCEPT_LANGUAGE => "zh-CN,en-US;q=0.7,en;q=0.3",
         CACHE_CONTROL => "max-age=0",
         CONNECTION => "keep-alive",
-        HOST => *HOST,
-        REFERER => &*REFERER,
+        HOST => &host,
+        REFERER => &referer,
         UPGRADE_INSECURE_REQUESTS => "1",
         USER_AGENT => "Mozilla/5.0 (X11; Ubuntu; Linux x86_64; rv:67.0) Gecko/20100101 Firefox/67.0"
+    };
+    let mut client = Client::builder()
+        .cookie_store(true)
+        .timeout(Duration::from_secs(15))
+        .default_headers(headers);
+    if let Some(proxy) = proxy {
+        client = client.proxy(Proxy::all(proxy)?)
+    }
+    Ok(client)
+}
+
+/// 无法上传的图片，遇到此类错误时不会重试
+#[derive(Debug)]
+pub enum ImageError {
+    /// 图片体积过大
+    TooLarge(u64),
+    /// 图片长宽比不符合要求
+    BadAspectRatio(u32, u32),
+    /// 图片不存在
+    NotFound,
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooLarge(size) => write!(f, "图片体积过大：{:.2} MiB", *size as f64 / 1048576.),
+            Self::BadAspectRatio(w, h) => write!(f, "图片长宽比不符合要求：{}x{}", w, h),
+            Self::NotFound => write!(f, "图片不存在"),
+        }
+    }
+}
+
+impl std::error::Error for ImageError {}
+
+/// 将 404 错误转换为 `ImageError::NotFound`
+fn check_not_found<E: Into<anyhow::Error>>(e: E) -> anyhow::Error {
+    let e = e.into();
+    let status = e.downcast_ref::<reqwest::Error>().and_then(|e| e.status());
+    match status {
+        Some(StatusCode::NOT_FOUND) => ImageError::NotFound.into(),
+        _ => e,
     }
-});
+}
+
+/// 画廊已经无法访问，遇到此类错误时不再重试
+#[derive(Debug)]
+pub enum GalleryError {
+    /// gdata 接口标记为 expunged
+    Expunged,
+    /// 画廊已被删除或无法访问，附带页面上的说明
+    Unavailable(String),
+}
+
+impl fmt::Display for GalleryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Expunged => write!(f, "画廊已被隐藏"),
+            Self::Unavailable(reason) => write!(f, "画廊无法访问：{}", reason),
+        }
+    }
+}
+
+impl std::error::Error for GalleryError {}
+
+/// 检查画廊页面是否为无法访问的提示页面
+fn check_gallery_page(text: &str) -> Result<(), GalleryError> {
+    if text.contains("Key missing, or incorrect key provided") {
+        return Err(GalleryError::Unavailable("画廊地址错误".to_owned()));
+    }
+    if !text.contains("Gallery Not Available") {
+        return Ok(());
+    }
+    let reason = parse_html(text)
+        .and_then(|html| html.xpath_text(r#"//div[@class="d"]/p[1]/text()"#))
+        .ok()
+        .and_then(|v| v.into_iter().next())
+        .map(|s| s.trim().to_owned())
+        .unwrap_or_else(|| "Gallery Not Available".to_owned());
+    Err(GalleryError::Unavailable(reason))
+}
 
 // TODO： 通过调整搜索页面展示的信息将 tag 移到这里来
 /// 基本画廊信息
 #[derive(Debug, Clone)]
 pub struct BasicGalleryInfo<'a> {
-    client: &'a Client,
+    ex: &'a ExHentai,
     /// 画廊标题
     pub title: String,
     /// 画廊地址
@@ -59,50 +149,41 @@ pub struct BasicGalleryInfo<'a> {
     pub limit: bool,
     /// 封面图片序号
     pub cover_index: usize,
+    /// 所属的搜索配置
+    pub profile: &'a SearchProfile,
+    /// 是否应用过滤规则
+    pub filter: bool,
+    /// 是否总是发布新消息，而不是原地更新历史上传
+    pub republish: bool,
 }
 
 impl<'a> BasicGalleryInfo<'a> {
     /// 获取画廊的完整信息
     pub async fn into_full_info(self) -> Result<FullGalleryInfo<'a>> {
         debug!("获取画廊信息: {}", self.url);
-        let response = send!(self.client.get(&self.url))?;
-        let mut html = parse_html(response.text().await?)?;
-
-        // 英文标题和日文标题
-        let title = html.xpath_text(r#"//h1[@id="gn"]/text()"#)?.swap_remove(0);
-        let title_jp = html
-            .xpath_text(r#"//h1[@id="gj"]/text()"#)
-            .map(|mut n| n.swap_remove(0))
-            .ok();
-
-        // 父画廊
-        let parent = html
-            .xpath_text(r#"//tr[contains(./td[1]/text(), "Parent:")]/td[2]/a/@href"#)
-            .ok()
-            .map(|mut v| v.swap_remove(0));
+        let mut html = parse_html(self.ex.get_gallery_page(&self.url).await?)?;
 
+        // 标题、标签、评分等信息通过 gdata 接口获取，页面中只解析收藏数和图片列表
+        // 搜索时已经批量获取过的不再单独请求
+        let (gid, token) = get_id_from_gallery(&self.url);
+        let metadata = match self.ex.take_cached_metadata(gid) {
+            Some(v) => v,
+            None => self
+                .ex
+                .gallery_metadata(&[(gid, token)])
+                .await?
+                .pop()
+                .context("找不到画廊元数据")?,
+        };
+        let title = metadata.title();
+        let title_jp = metadata.title_jp();
+        let parent = metadata.parent_url(&self.ex.base);
         debug!("父画廊：{:?}", parent);
-
-        // 标签
-        let mut tags = vec![];
-        for ele in html
-            .xpath_elem(r#"//div[@id="taglist"]//tr"#)
-            .unwrap_or_default()
-        {
-            let tag_set_name = ele.xpath_text(r#"./td[1]/text()"#)?[0]
-                .trim_matches(':')
-                .to_owned();
-            let tag = ele.xpath_text(r#"./td[2]/div/a/text()"#)?;
-            tags.push((tag_set_name, tag));
-        }
+        let newer = newer_versions(&html);
+        debug!("新版本：{:?}", newer);
+        let tags = metadata.tags();
         debug!("tags: {:?}", tags);
-
-        // 评分
-        let rating = html.xpath_text(r#"//td[@id="rating_label"]/text()"#)?[0]
-            .split(' ')
-            .nth(1)
-            .context("找不到评分")?
-            .to_owned();
+        let rating = metadata.rating.clone();
         debug!("评分: {}", rating);
 
         // 收藏
@@ -121,7 +202,7 @@ impl<'a> BasicGalleryInfo<'a> {
             debug!("下一页: {:?}", next_page);
             // TODO: 干掉此处的 block_on
             let text = block_in_place(|| {
-                block_on(async { send!(self.client.get(&next_page[0]))?.text().await })
+                block_on(async { send!(self.ex.client.get(&next_page[0]))?.text().await })
             })?;
             html = parse_html(text)?;
             img_pages.extend(html.xpath_text(r#"//div[@id="gdt"]//a/@href"#)?);
@@ -129,10 +210,12 @@ impl<'a> BasicGalleryInfo<'a> {
         debug!("页数：{}", img_pages.len());
 
         Ok(FullGalleryInfo {
-            client: self.client,
+            ex: self.ex,
             url: self.url.clone(),
             limit: self.limit,
+            profile: self.profile,
             parent,
+            newer,
             title,
             title_jp,
             rating,
@@ -146,7 +229,7 @@ impl<'a> BasicGalleryInfo<'a> {
 /// 画廊信息
 #[derive(Debug)]
 pub struct FullGalleryInfo<'a> {
-    client: &'a Client,
+    ex: &'a ExHentai,
     /// 画廊标题
     pub title: String,
     /// 画廊日文标题
@@ -155,6 +238,8 @@ pub struct FullGalleryInfo<'a> {
     pub url: String,
     /// 父画廊地址
     pub parent: Option<String>,
+    /// 新版本画廊地址，按发布时间排序
+    pub newer: Vec<String>,
     /// 评分
     pub rating: String,
     /// 收藏次数
@@ -165,6 +250,8 @@ pub struct FullGalleryInfo<'a> {
     pub img_pages: Vec<String>,
     /// 是否限制图片数量
     pub limit: bool,
+    /// 所属的搜索配置
+    pub profile: &'a SearchProfile,
 }
 
 impl<'a> FullGalleryInfo<'a> {
@@ -173,14 +260,17 @@ impl<'a> FullGalleryInfo<'a> {
         if !self.limit {
             return &self.img_pages;
         }
-        let limit = CONFIG.exhentai.max_img_cnt;
+        let limit = self.profile.max_img_cnt;
         let img_cnt = self.img_pages.len().min(limit);
         info!("保留图片数量: {}", img_cnt);
         &self.img_pages[..img_cnt]
     }
 
-    /// 将画廊里的图片上传至 telegraph，返回上传后的图片链接
-    pub async fn upload_images_to_telegraph(&self) -> Result<Vec<String>> {
+    /// 将画廊里的图片上传至图床，返回上传后的图片链接，无法上传的图片为 None
+    ///
+    /// 每张图片的上传结果都会记录到数据库中，中途失败后再次上传时会跳过已经上传成功的图片，
+    /// 无法上传的图片会重新尝试，因为压缩、切分等处理可能已经能够解决原来的问题
+    pub async fn upload_images(&self, ctx: &AppContext) -> Result<Vec<Option<Vec<String>>>> {
         let img_pages = self.get_image_lists();
         let img_cnt = img_pages.len();
         let idx = Arc::new(AtomicU32::new(0));
@@ -191,90 +281,132 @@ impl<'a> FullGalleryInfo<'a> {
             info!("第 {} / {} 张图片", now + 1, img_cnt);
         };
 
-        let mut client_builder = Client::builder().timeout(Duration::from_secs(30));
-        if let Some(proxy) = &CONFIG.telegraph.proxy {
-            client_builder = client_builder.proxy(Proxy::custom(move |url| {
-                (url.host_str() == Some("api.telegra.ph")).then(|| proxy.clone())
-            }));
-        }
-        let client = client_builder.build()?;
+        let client = Client::builder().timeout(Duration::from_secs(30)).build()?;
         let client_ref = &client;
 
+        let (gallery_id, _) = get_id_from_gallery(&self.url);
+        let mut progress = ctx.db.query_image_progress(gallery_id)?;
+        progress.retain(|_, image| image.url.is_some());
+        if !progress.is_empty() {
+            info!("已上传过 {} 张图片，继续上传", progress.len());
+        }
+        let progress_ref = &progress;
+
         // TODO: 避免一次 clone？
-        let get_url = |url: String| async move {
+        let get_url = |page: usize, url: String| async move {
             update_progress();
-            let mut err = None;
-            for _ in 0..5i32 {
-                match self.upload_image(&url, client_ref).await {
-                    Ok(v) => return Ok(v),
-                    Err(e) => {
-                        error!("获取图片地址失败：{}", e);
-                        err = Some(e);
-                    }
+            match progress_ref.get(&(page as i32)) {
+                Some(image) => image.urls(),
+                None => {
+                    self.upload_page(ctx, gallery_id, page, &url, client_ref)
+                        .await
                 }
-                sleep(Duration::from_secs(10)).await;
             }
-            Err(err.unwrap().context("无法获取图片地址"))
         };
 
-        // TODO: 能不能 iter(img_pages)
-        let mut f = vec![];
-        for url in img_pages.iter() {
-            f.push(get_url(url.to_owned()));
-        }
-
-        let ret = futures::stream::iter(f)
-            .buffered(CONFIG.threads_num)
+        let ret = futures::stream::iter(img_pages.iter().enumerate())
+            .map(|(page, url)| get_url(page, url.to_owned()))
+            .buffered(ctx.config.threads_num)
             .try_collect::<Vec<_>>()
             .await?;
 
         Ok(ret)
     }
 
-    /// 上传指定的图片并返回上传后的地址
-    pub async fn upload_image(&self, page_url: &str, client: &Client) -> Result<String> {
+    /// 上传画廊中的第 page 张图片并记录结果，对于无法上传的图片返回 None
+    async fn upload_page(
+        &self,
+        ctx: &AppContext,
+        gallery_id: i32,
+        page: usize,
+        url: &str,
+        client: &Client,
+    ) -> Result<Option<Vec<String>>> {
+        let mut err = None;
+        for _ in 0..5i32 {
+            match self.upload_image(ctx, url, client).await {
+                Ok(v) => {
+                    ctx.db.insert_image_progress(
+                        gallery_id,
+                        page as i32,
+                        Some(v.as_slice()),
+                        None,
+                    )?;
+                    return Ok(Some(v));
+                }
+                Err(e) => match e.downcast_ref::<ImageError>() {
+                    Some(reason) => {
+                        warn!("第 {} 张图片无法上传：{}", page + 1, reason);
+                        let reason = reason.to_string();
+                        ctx.db.insert_image_progress(
+                            gallery_id,
+                            page as i32,
+                            None,
+                            Some(&reason),
+                        )?;
+                        return Ok(None);
+                    }
+                    None => {
+                        error!("获取图片地址失败：{}", e);
+                        err = Some(e);
+                    }
+                },
+            }
+            sleep(Duration::from_secs(10)).await;
+        }
+        Err(err.unwrap().context("无法获取图片地址"))
+    }
+
+    /// 上传指定的图片并返回上传后的地址，对于无法上传的图片返回 `ImageError`
+    ///
+    /// 长图会被切分为多张上传，此时返回多个地址
+    pub async fn upload_image(
+        &self,
+        ctx: &AppContext,
+        page_url: &str,
+        client: &Client,
+    ) -> Result<Vec<String>> {
         debug!("获取图片真实地址中：{}", page_url);
 
         // 第一次查询，查询 image_hash
-        if let Ok(url) = DB.query_image_by_hash(page_url) {
+        let host = ctx.image_host.name();
+        if let Ok(url) = ctx.db.query_image_by_hash(page_url, host) {
             trace!("找到缓存!");
             return Ok(url);
         }
 
-        let response = send!(self.client.get(page_url))?;
-        let url = parse_html(response.text().await?)?
-            .xpath_text(r#"//img[@id="img"]/@src"#)?
-            .swap_remove(0);
+        let url = self.ex.get_image_url(page_url).await?;
 
-        // 第二次查询，查询 images，此为历史遗留问题
+        // 第二次查询，查询 images，此为历史遗留问题，其中只有上传到 telegraph 的图片
         // 一段时间后应该可以移除 images 表
-        if let Ok(url) = DB.query_image_by_fileindex(&url) {
-            DB.insert_image(page_url, &url)?;
-            trace!("找到缓存!");
-            return Ok(url);
+        if host == "telegraph" {
+            if let Ok(url) = ctx.db.query_image_by_fileindex(&url) {
+                let urls = vec![url];
+                ctx.db.insert_image(page_url, &urls, host)?;
+                trace!("找到缓存!");
+                return Ok(urls);
+            }
         }
 
         debug!("下载图片中：{}", &url);
-        let file = download_to_temp(client, &url).await?;
-        let file = file.path();
+        let file = download_to_temp(client, &url)
+            .await
+            .map_err(check_not_found)?;
 
-        // telegraph 对图片的体积 & 大小有要求
-        if file.metadata()?.len() > 5 * 1024 * 1024 {
-            return Ok("".to_owned());
-        }
-        let (width, height) = image::io::Reader::open(file)?.into_dimensions()?;
-        if height * 10 <= width || width * 20 <= height {
-            return Ok("".to_owned());
-        }
+        // telegraph 对图片的体积 & 大小有要求，不满足时尝试压缩或切分
+        // 解码和压缩比较耗时，放到单独的线程中执行，以免阻塞 bot
+        let path = file.path().to_owned();
+        let processed = tokio::task::spawn_blocking(move || preprocess(&path)).await??;
+        let files = match &processed {
+            Some(tiles) => tiles.iter().map(|f| f.path()).collect::<Vec<_>>(),
+            None => vec![file.path()],
+        };
 
-        debug!("上传图片中...");
-        let mut result = Telegraph::upload_with(&[file], client)
-            .await
-            .context("上传 Telegraph  失败")?;
-        let ret = result.swap_remove(0).src;
+        debug!("上传图片到 {} 中...", host);
+        let ret = ctx.image_host.upload(&files).await?;
 
         debug!("记录缓存...");
-        DB.insert_image(page_url, &ret)?;
+        ctx.db.insert_image(page_url, &ret, host)?;
 
         Ok(ret)
     }
@@ -284,14 +416,167 @@ impl<'a> FullGalleryInfo<'a> {
     }
 }
 
+/// gdata 接口返回的画廊元数据
+#[derive(Debug, Clone, Deserialize)]
+pub struct GalleryMetadata {
+    /// 画廊 id
+    pub gid: i32,
+    /// 画廊 token
+    pub token: String,
+    /// 画廊标题，经过 HTML 转义
+    pub title: String,
+    /// 画廊日文标题，经过 HTML 转义，没有则为空字符串
+    pub title_jpn: String,
+    /// 分类
+    pub category: String,
+    /// 上传者
+    pub uploader: String,
+    /// 上传时间戳
+    #[serde(deserialize_with = "from_str_or_num")]
+    pub posted: i64,
+    /// 图片数量
+    #[serde(deserialize_with = "from_str_or_num")]
+    pub filecount: usize,
+    /// 评分
+    pub rating: String,
+    /// 是否已被删除
+    pub expunged: bool,
+    /// 标签，格式为 namespace:tag
+    pub tags: Vec<String>,
+    /// 父画廊 id
+    #[serde(default, deserialize_with = "option_from_str_or_num")]
+    pub parent_gid: Option<i32>,
+    /// 父画廊 token
+    #[serde(default)]
+    pub parent_key: Option<String>,
+    /// 最新版本的画廊 id，没有新版本时与 gid 相同
+    #[serde(default, deserialize_with = "option_from_str_or_num")]
+    pub current_gid: Option<i32>,
+    /// 最新版本的画廊 token
+    #[serde(default)]
+    pub current_key: Option<String>,
+}
+
+impl GalleryMetadata {
+    pub fn title(&self) -> String {
+        unescape_html(&self.title).into_owned()
+    }
+
+    pub fn title_jp(&self) -> Option<String> {
+        (!self.title_jpn.is_empty()).then(|| unescape_html(&self.title_jpn).into_owned())
+    }
+
+    /// 父画廊地址，base 为站点地址
+    pub fn parent_url(&self, base: &str) -> Option<String> {
+        match (self.parent_gid, &self.parent_key) {
+            (Some(gid), Some(key)) => Some(format!("{}/g/{}/{}/", base, gid, key)),
+            _ => None,
+        }
+    }
+
+    /// 最新版本的画廊地址，没有新版本时返回 None，base 为站点地址
+    pub fn current_url(&self, base: &str) -> Option<String> {
+        match (self.current_gid, &self.current_key) {
+            (Some(gid), Some(key)) if gid != self.gid => {
+                Some(format!("{}/g/{}/{}/", base, gid, key))
+            }
+            _ => None,
+        }
+    }
+
+    /// 将标签转换为与画廊页面相同的分组格式
+    pub fn tags(&self) -> Vec<(String, Vec<String>)> {
+        let mut ret: Vec<(String, Vec<String>)> = vec![];
+        for tag in &self.tags {
+            let (namespace, tag) = tag.split_once(':').unwrap_or(("misc", tag));
+            match ret.iter_mut().find(|(ns, _)| ns == namespace) {
+                Some((_, v)) => v.push(tag.to_owned()),
+                None => ret.push((namespace.to_owned(), vec![tag.to_owned()])),
+            }
+        }
+        ret
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum GdataEntry {
+    Ok(GalleryMetadata),
+    Err { gid: i32, error: String },
+}
+
+#[derive(Debug, Deserialize)]
+struct GdataResponse {
+    gmetadata: Vec<GdataEntry>,
+}
+
+/// gdata 接口中的数字有时会以字符串形式返回
+fn from_str_or_num<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::String(s) => s.parse().map_err(de::Error::custom),
+        serde_json::Value::Number(n) => n.to_string().parse().map_err(de::Error::custom),
+        v => Err(de::Error::custom(format!(
+            "expect string or number, found {}",
+            v
+        ))),
+    }
+}
+
+fn option_from_str_or_num<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::Null => Ok(None),
+        serde_json::Value::String(s) => s.parse().map(Some).map_err(de::Error::custom),
+        serde_json::Value::Number(n) => n.to_string().parse().map(Some).map_err(de::Error::custom),
+        v => Err(de::Error::custom(format!(
+            "expect string or number, found {}",
+            v
+        ))),
+    }
+}
+
+/// 解析画廊页面中的新版本列表，按发布时间排序，没有新版本时为空
+fn newer_versions(html: &Node) -> Vec<String> {
+    html.xpath_text(r#"//div[@id="gnd"]/a/@href"#)
+        .unwrap_or_default()
+}
+
 #[derive(Debug)]
 pub struct ExHentai {
     client: Client,
+    /// 站点地址，如 `https://exhentai.org`，末尾没有 `/`
+    base: String,
+    /// 搜索页面地址
+    search_url: Url,
+    /// 搜索时批量获取的画廊元数据及获取时间，获取画廊完整信息时取出
+    metadata_cache: Mutex<HashMap<i32, (Instant, GalleryMetadata)>>,
 }
 
+/// 搜索时获取的画廊元数据的有效期
+const METADATA_TTL: Duration = Duration::from_secs(3600);
+
 impl ExHentai {
+    /// 使用已经创建好的 client，站点地址取自搜索地址
+    pub fn with_client(client: Client, search_url: Url) -> Self {
+        Self {
+            client,
+            base: search_url.origin().ascii_serialization(),
+            search_url,
+            metadata_cache: Mutex::new(HashMap::new()),
+        }
+    }
+
     /// 登录 E-Hentai (能够访问 ExHentai 的前置条件
-    pub async fn new() -> Result<Self> {
+    pub async fn new(config: &config::ExHentai) -> Result<Self> {
         // 此处手动设置重定向, 因为 reqwest 的默认重定向处理策略会把相同 URL 直接判定为无限循环
         // 然而其实 COOKIE 变了, 所以不会无限循环
         let custom = Policy::custom(|attempt| {
@@ -302,72 +587,79 @@ impl ExHentai {
             }
         });
 
-        let mut client = Client::builder()
+        let search_url = &config.search_url;
+        let client = client_builder(search_url, config.proxy.as_deref())?
             .redirect(custom)
-            .cookie_store(true)
-            .timeout(Duration::from_secs(15))
-            .default_headers(HEADERS.clone());
-        if let Some(proxy) = &CONFIG.exhentai.proxy {
-            client = client.proxy(Proxy::all(proxy)?)
-        }
-        let client = client.build()?;
+            .build()?;
+        let ex = Self::with_client(client, search_url.clone());
+        ex.login(LOGIN_URL, &config.username, &config.password)
+            .await?;
+        Ok(ex)
+    }
 
+    /// 直接通过 cookie 登录
+    pub async fn from_cookie(config: &config::ExHentai) -> Result<Self> {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::COOKIE,
+            HeaderValue::from_str(config.cookie.as_deref().context("没有配置 cookie")?)?,
+        );
+
+        let search_url = &config.search_url;
+        let client = client_builder(search_url, config.proxy.as_deref())?
+            .default_headers(headers)
+            .build()?;
+        info!("Cookie 登陆中……");
+        let ex = Self::with_client(client, search_url.clone());
+        ex.load_settings().await?;
+        info!("登录成功!");
+
+        Ok(ex)
+    }
+
+    /// 登录表站并访问里站取得 cookie，login_url 为表站的登录地址
+    pub async fn login(&self, login_url: &str, username: &str, password: &str) -> Result<()> {
         info!("登录表站...");
         // 登录表站, 获得 cookie
-        let _response = send!(client
-            .post("https://forums.e-hentai.org/index.php")
+        let _response = send!(self
+            .client
+            .post(login_url)
             .query(&[("act", "Login"), ("CODE", "01")])
             .form(&[
                 ("CookieDate", "1"),
                 ("b", "d"),
                 ("bt", "1-6"),
-                ("UserName", &CONFIG.exhentai.username),
-                ("PassWord", &CONFIG.exhentai.password),
+                ("UserName", username),
+                ("PassWord", password),
                 ("ipb_login_submit", "Login!"),
             ]))?;
 
         info!("登录里站...");
         // 访问里站, 取得必要的 cookie
-        let _response = send!(client.get(format!("https://{}", *HOST)))?;
-        // 获得过滤设置相关的 cookie ?
-        let _response = send!(client.get(format!("https://{}/uconfig.php", *HOST)))?;
-        let _response = send!(client.get(format!("https://{}/mytags", *HOST)))?;
+        let _response = send!(self.client.get(&self.base))?;
+        self.load_settings().await?;
         info!("登录成功!");
-
-        Ok(Self { client })
+        Ok(())
     }
 
-    /// 直接通过 cookie 登录
-    pub async fn from_cookie() -> Result<Self> {
-        let mut headers = HEADERS.clone();
-        headers.insert(
-            header::COOKIE,
-            HeaderValue::from_str(CONFIG.exhentai.cookie.as_ref().unwrap())?,
-        );
-
-        let mut client = Client::builder()
-            .cookie_store(true)
-            .timeout(Duration::from_secs(15))
-            .default_headers(headers);
-        if let Some(proxy) = &CONFIG.exhentai.proxy {
-            client = client.proxy(Proxy::all(proxy)?)
-        }
-        let client = client.build()?;
-        info!("Cookie 登陆中……");
-        let _response = send!(client.get(format!("https://{}/uconfig.php", *HOST)))?;
-        let _response = send!(client.get(format!("https://{}/mytags", *HOST)))?;
-        info!("登录成功!");
-
-        Ok(Self { client })
+    /// 获得过滤设置相关的 cookie ?
+    async fn load_settings(&self) -> Result<()> {
+        let _response = send!(self.client.get(format!("{}/uconfig.php", self.base)))?;
+        let _response = send!(self.client.get(format!("{}/mytags", self.base)))?;
+        Ok(())
     }
 
-    /// 搜索指定关键字
-    pub async fn search(&self, page: i32) -> Result<Vec<BasicGalleryInfo>> {
+    /// 按照指定搜索配置搜索
+    pub async fn search<'a>(
+        &'a self,
+        profile: &'a SearchProfile,
+        page: i32,
+    ) -> Result<Vec<BasicGalleryInfo<'a>>> {
         debug!("搜索第 {} 页", page);
         let response = send!(self
             .client
-            .get(CONFIG.exhentai.search_url.clone())
-            .query(&CONFIG.exhentai.search_params)
+            .get(self.search_url.clone())
+            .query(&profile.search_params)
             .query(&[("page", &page.to_string())]))?;
         debug!("状态码: {}", response.status());
         let text = response.text().await?;
@@ -390,22 +682,77 @@ impl ExHentai {
             debug!("地址: {}", url);
 
             ret.push(BasicGalleryInfo {
-                client: &self.client,
+                ex: self,
                 title,
                 url,
                 limit: true,
                 cover_index: 0,
+                profile,
+                filter: true,
+                republish: false,
             })
         }
 
-        Ok(ret)
+        self.cache_metadata(ret).await
+    }
+
+    /// 批量获取搜索结果的元数据并缓存，同时去掉已被隐藏的画廊，获取失败时之后再逐个获取
+    async fn cache_metadata<'a>(
+        &self,
+        galleries: Vec<BasicGalleryInfo<'a>>,
+    ) -> Result<Vec<BasicGalleryInfo<'a>>> {
+        if galleries.is_empty() {
+            return Ok(galleries);
+        }
+        let ids = galleries
+            .iter()
+            .map(|g| get_id_from_gallery(&g.url))
+            .collect::<Vec<_>>();
+        let metadata = match self.gallery_metadata(&ids).await {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("批量获取画廊元数据失败：{}", e);
+                return Ok(galleries);
+            }
+        };
+        let expunged = metadata
+            .iter()
+            .filter(|m| m.expunged)
+            .map(|m| m.gid)
+            .collect::<Vec<_>>();
+        let now = Instant::now();
+        let mut cache = self.metadata_cache.lock().unwrap();
+        cache.retain(|_, (time, _)| time.elapsed() < METADATA_TTL);
+        for m in metadata.into_iter().filter(|m| !m.expunged) {
+            cache.insert(m.gid, (now, m));
+        }
+        Ok(galleries
+            .into_iter()
+            .filter(|g| {
+                let hidden = expunged.contains(&get_id_from_gallery(&g.url).0);
+                if hidden {
+                    info!("跳过已被隐藏的画廊：{}", g.url);
+                }
+                !hidden
+            })
+            .collect())
     }
 
-    pub async fn search_n_pages(&self, n: i32) -> Result<Vec<BasicGalleryInfo>> {
+    /// 取出搜索时缓存的画廊元数据，过期的不再使用
+    fn take_cached_metadata(&self, gid: i32) -> Option<GalleryMetadata> {
+        let (time, metadata) = self.metadata_cache.lock().unwrap().remove(&gid)?;
+        (time.elapsed() < METADATA_TTL).then_some(metadata)
+    }
+
+    pub async fn search_n_pages<'a>(
+        &'a self,
+        profile: &'a SearchProfile,
+    ) -> Result<Vec<BasicGalleryInfo<'a>>> {
+        let n = profile.max_pages;
         info!("搜索前 {} 页本子", n);
         let mut result = vec![];
         for page in 0..n {
-            match self.search(page).await {
+            match self.search(profile, page).await {
                 Ok(v) => result.extend(v),
                 Err(e) => error!("{}", e),
             }
@@ -414,24 +761,406 @@ impl ExHentai {
         Ok(result)
     }
 
-    pub async fn get_gallery_by_url<S: Into<String>>(&self, url: S) -> Result<BasicGalleryInfo> {
+    /// 调用 gdata 接口批量获取画廊元数据，每次请求最多包含 25 个画廊
+    pub async fn gallery_metadata(&self, ids: &[(i32, String)]) -> Result<Vec<GalleryMetadata>> {
+        let api_url = format!("{}/api.php", self.base);
+        let mut ret = vec![];
+        for chunk in ids.chunks(25) {
+            debug!("获取画廊元数据：{:?}", chunk);
+            let response = send!(self.client.post(&api_url).json(&json!({
+                "method": "gdata",
+                "gidlist": chunk,
+                "namespace": 1,
+            })))?;
+            let response = response.json::<GdataResponse>().await?;
+            for entry in response.gmetadata {
+                match entry {
+                    GdataEntry::Ok(v) => ret.push(v),
+                    GdataEntry::Err { gid, error } => {
+                        error!("获取画廊 {} 元数据失败：{}", gid, error)
+                    }
+                }
+            }
+        }
+        Ok(ret)
+    }
+
+    /// 获取画廊页面，页面上注明画廊无法访问时返回 `GalleryError`
+    async fn get_gallery_page(&self, url: &str) -> Result<String> {
+        let response = self.client.get(url).send().await?;
+        let status = response.status();
+        let text = response.text().await?;
+        // 单纯的 404 等错误可能只是暂时的，不能据此认为画廊已被删除
+        check_gallery_page(&text)?;
+        if !status.is_success() {
+            bail!("获取画廊页面失败：{} {}", status, url);
+        }
+        Ok(text)
+    }
+
+    /// 解析图片页面，获取图片的真实地址
+    pub async fn get_image_url(&self, page_url: &str) -> Result<String> {
+        let response = send!(self.client.get(page_url)).map_err(check_not_found)?;
+        Ok(parse_html(response.text().await?)?
+            .xpath_text(r#"//img[@id="img"]/@src"#)?
+            .swap_remove(0))
+    }
+
+    /// 获取画廊的基本信息，画廊归属于指定的搜索配置
+    pub async fn get_gallery_by_url<'a, S: Into<String>>(
+        &'a self,
+        url: S,
+        profile: &'a SearchProfile,
+    ) -> Result<BasicGalleryInfo<'a>> {
         let url = url.into();
         info!("获取本子信息: {}", url);
-        let response = send!(self.client.get(&url))?;
-        let html = parse_html(response.text().await?)?;
+        let html = parse_html(self.get_gallery_page(&url).await?)?;
         let title = html.xpath_text(r#"//h1[@id="gn"]/text()"#)?.swap_remove(0);
         Ok(BasicGalleryInfo {
-            client: &self.client,
+            ex: self,
             title,
             url,
             limit: true,
             cover_index: 0,
+            profile,
+            filter: false,
+            republish: false,
         })
     }
+
+    /// 根据 gdata 接口返回的元数据获取画廊最新版本的地址，无需请求画廊页面
+    pub fn newer_version(&self, metadata: &GalleryMetadata) -> Option<String> {
+        metadata.current_url(&self.base)
+    }
+
+    /// 获取画廊的新版本列表，按发布时间排序
+    pub async fn get_newer_versions(&self, url: &str) -> Result<Vec<String>> {
+        debug!("检查新版本：{}", url);
+        let html = parse_html(self.get_gallery_page(url).await?)?;
+        Ok(newer_versions(&html))
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use crate::mock::{fixture, mock_exhentai, test_context, MockServer};
+
+    fn profile() -> SearchProfile {
+        SearchProfile {
+            name: "test".to_owned(),
+            search_params: vec![("f_search".to_owned(), "chinese".to_owned())],
+            max_pages: 1,
+            max_img_cnt: 3,
+            outdate: None,
+            interval: None,
+            channel_id: None,
+        }
+    }
+
+    /// 启动提供 tests/fixtures/exhentai 中页面的测试服务器
+    fn mock_site() -> (MockServer, ExHentai) {
+        let server = MockServer::start();
+        mock_exhentai(&server);
+        let search_url = Url::parse(&server.url()).unwrap();
+        let client = client_builder(&search_url, None).unwrap().build().unwrap();
+        (server, ExHentai::with_client(client, search_url))
+    }
+
+    #[tokio::test]
+    async fn test_login() {
+        let (server, ex) = mock_site();
+        let login_url = format!("{}/index.php", server.url());
+        ex.login(&login_url, "tester", "secret").await.unwrap();
+
+        let requests = server.requests();
+        let paths = requests.iter().map(|r| r.path.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            vec![
+                "/index.php?act=Login&CODE=01",
+                "/",
+                "/uconfig.php",
+                "/mytags"
+            ]
+        );
+        assert_eq!(requests[0].method, "POST");
+        assert!(requests[0].body.contains("UserName=tester"));
+        assert!(requests[0].body.contains("PassWord=secret"));
+    }
+
+    #[tokio::test]
+    async fn test_search() {
+        let (server, ex) = mock_site();
+        let profile = profile();
+        let result = ex.search(&profile, 0).await.unwrap();
+        assert_eq!(result.len(), 2);
+        assert_eq!(result[0].title, "[Artist] Test Gallery One [Chinese]");
+        assert_eq!(
+            result[0].url,
+            format!("{}/g/1000/abcdef1234/", server.url())
+        );
+        assert_eq!(result[1].title, "Second & Gallery");
+        assert!(result[0].limit && result[0].filter && !result[0].republish);
+        let paths = server
+            .requests()
+            .into_iter()
+            .map(|r| r.path)
+            .collect::<Vec<_>>();
+        assert_eq!(paths, ["/?f_search=chinese&page=0", "/api.php"]);
+    }
+
+    #[tokio::test]
+    async fn test_search_expunged() {
+        let (server, ex) = mock_site();
+        let gdata = fixture("exhentai/gdata.json", &server.url());
+        let gdata = gdata.replace(r#""expunged":false"#, r#""expunged":true"#);
+        server.route("/api.php", 200, gdata);
+        let result = ex.search(&profile(), 0).await.unwrap();
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].title, "Second & Gallery");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_full_info() {
+        let (server, ex) = mock_site();
+        let base = server.url();
+        let profile = profile();
+        let gallery = ex.search(&profile, 0).await.unwrap().swap_remove(0);
+        let info = gallery.into_full_info().await.unwrap();
+
+        assert_eq!(info.title, "[Artist] Test Gallery One [Chinese]");
+        assert_eq!(info.title(), "[作者] テストギャラリー [中国翻訳]");
+        assert_eq!(info.parent, Some(format!("{}/g/900/9999999999/", base)));
+        assert_eq!(info.newer, vec![format!("{}/g/1001/bbbbbbbbbb/", base)]);
+        assert_eq!(info.rating, "4.56");
+        assert_eq!(info.fav_cnt, "123");
+        assert_eq!(
+            info.tags,
+            vec![
+                (
+                    "language".to_owned(),
+                    vec!["chinese".to_owned(), "translated".to_owned()]
+                ),
+                ("female".to_owned(), vec!["sole female".to_owned()]),
+                ("other".to_owned(), vec!["full color".to_owned()]),
+            ]
+        );
+        // 图片列表分布在两页中
+        let pages = [
+            "aaaaaaaaaa",
+            "bbbbbbbbbb",
+            "cccccccccc",
+            "dddddddddd",
+            "eeeeeeeeee",
+        ]
+        .iter()
+        .enumerate()
+        .map(|(i, hash)| format!("{}/s/{}/1000-{}", base, hash, i + 1))
+        .collect::<Vec<_>>();
+        assert_eq!(info.img_pages, pages);
+        assert_eq!(info.get_image_lists(), &pages[..3]);
+
+        // 元数据在搜索时为整页画廊一次性获取
+        let gdata = server
+            .requests()
+            .into_iter()
+            .filter(|r| r.path == "/api.php")
+            .collect::<Vec<_>>();
+        assert_eq!(gdata.len(), 1);
+        assert_eq!(gdata[0].method, "POST");
+        assert!(gdata[0]
+            .body
+            .contains(r#""gidlist":[[1000,"abcdef1234"],[2000,"0123456789"]]"#));
+    }
+
+    #[tokio::test]
+    async fn test_newer_version_from_metadata() {
+        let (server, ex) = mock_site();
+        let base = server.url();
+        let metadata = ex
+            .gallery_metadata(&[(1000, "abcdef1234".to_owned())])
+            .await
+            .unwrap();
+        assert_eq!(
+            ex.newer_version(&metadata[0]),
+            Some(format!("{}/g/1001/bbbbbbbbbb/", base))
+        );
+        // 不需要请求画廊页面
+        assert!(server.requests().iter().all(|r| r.path == "/api.php"));
+
+        let latest = GalleryMetadata {
+            current_gid: Some(1000),
+            ..metadata[0].clone()
+        };
+        assert_eq!(ex.newer_version(&latest), None);
+    }
+
+    #[tokio::test]
+    async fn test_image_url() {
+        let (server, ex) = mock_site();
+        let base = server.url();
+        let url = ex
+            .get_image_url(&format!("{}/s/aaaaaaaaaa/1000-1", base))
+            .await
+            .unwrap();
+        assert_eq!(
+            url,
+            format!(
+                "{}/h/0123abcd-1000-1280-1800-jpg/keystamp=1;fileindex=555;xres=1280/01.jpg",
+                base
+            )
+        );
+        let err = ex
+            .get_image_url(&format!("{}/s/ffffffffff/1000-9", base))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ImageError>(),
+            Some(ImageError::NotFound)
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_resume_upload() {
+        let (server, ctx) = test_context();
+        mock_exhentai(&server);
+        let gallery = ctx
+            .exhentai
+            .get_gallery_by_url(
+                format!("{}/g/1000/abcdef1234/", server.url()),
+                ctx.config.default_profile(),
+            )
+            .and_then(|g| g.into_full_info())
+            .await
+            .unwrap();
+        // 上次上传时第一张成功，第二张因为体积过大而失败，之后中断
+        let cached = vec!["/file/cached.jpg".to_owned()];
+        ctx.db
+            .insert_image_progress(1000, 0, Some(cached.as_slice()), None)
+            .unwrap();
+        let reason = ImageError::TooLarge(6 * 1024 * 1024).to_string();
+        ctx.db
+            .insert_image_progress(1000, 1, None, Some(&reason))
+            .unwrap();
+
+        let urls = gallery.upload_images(&ctx).await.unwrap();
+        let uploaded = Some(vec!["/file/test.jpg".to_owned()]);
+        assert_eq!(urls.len(), 5);
+        assert_eq!(urls[0], Some(cached));
+        assert!(urls[1..].iter().all(|u| *u == uploaded));
+        let uploads = server.requests();
+        assert_eq!(uploads.iter().filter(|r| r.path == "/upload").count(), 4);
+
+        let progress = ctx.db.query_image_progress(1000).unwrap();
+        assert_eq!(progress.len(), 5);
+        assert!(progress.values().all(|image| image.error.is_none()));
+        assert_eq!(progress[&1].urls().unwrap(), uploaded);
+    }
+
+    #[tokio::test]
+    async fn test_unavailable_gallery() {
+        let (server, ex) = mock_site();
+        let base = server.url();
+        let newer = ex
+            .get_newer_versions(&format!("{}/g/1000/abcdef1234/", base))
+            .await
+            .unwrap();
+        assert_eq!(newer, vec![format!("{}/g/1001/bbbbbbbbbb/", base)]);
+
+        let err = ex
+            .get_newer_versions(&format!("{}/g/2000/0123456789/", base))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<GalleryError>(),
+            Some(GalleryError::Unavailable(s)) if s == "This gallery has been removed or is unavailable."
+        ));
+        let err = ex
+            .get_newer_versions(&format!("{}/g/3000/0000000000/", base))
+            .await
+            .unwrap_err();
+        // 页面上没有注明画廊无法访问的 404 不应视为画廊已被删除
+        assert!(err.downcast_ref::<GalleryError>().is_none());
+
+        server.route(
+            "/g/4000/0000000000/",
+            404,
+            fixture("exhentai/not_available.html", &base),
+        );
+        let err = ex
+            .get_newer_versions(&format!("{}/g/4000/0000000000/", base))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<GalleryError>(),
+            Some(GalleryError::Unavailable(_))
+        ));
+    }
+
     #[test]
-    fn test_login() {}
+    fn test_newer_versions() {
+        let html = parse_html(
+            r#"<html><body><div id="gnd">There are newer versions of this gallery available:<br>
+            <a href="https://exhentai.org/g/2/bbb/">Title v2</a>, added 2021-01-01 00:00<br>
+            <a href="https://exhentai.org/g/3/ccc/">Title v3</a>, added 2021-02-01 00:00</div></body></html>"#,
+        )
+        .unwrap();
+        assert_eq!(
+            newer_versions(&html),
+            vec![
+                "https://exhentai.org/g/2/bbb/",
+                "https://exhentai.org/g/3/ccc/"
+            ]
+        );
+        let html = parse_html("<html><body><div id=\"gd1\"></div></body></html>").unwrap();
+        assert!(newer_versions(&html).is_empty());
+    }
+
+    #[test]
+    fn test_gallery_page() {
+        assert!(check_gallery_page("<html><div id=\"gdt\"></div></html>").is_ok());
+        let text = r#"<html><head><title>Gallery Not Available - ExHentai.org</title></head>
+            <body><div class="d"><p>This gallery has been removed or is unavailable.</p></div></body></html>"#;
+        assert!(matches!(
+            check_gallery_page(text),
+            Err(GalleryError::Unavailable(s)) if s == "This gallery has been removed or is unavailable."
+        ));
+        assert!(check_gallery_page("Key missing, or incorrect key provided.").is_err());
+    }
+
+    #[test]
+    fn test_gdata() {
+        let text = r#"{"gmetadata":[{"gid":618395,"token":"0439fa3666","archiver_key":"403565--d887c6dfe8aae79ed0071551aa1bafeb4a5ee361","title":"(Kouroumu 8) [Handful&amp;#9825;Happiness! (Fuyuki Nanahara)] TOUHOU GUNMANIA A2","title_jpn":"","category":"Non-H","thumb":"https://ehgt.org/14/63/1463dfbc16847c9ebef92c46a90e21ca881b2a12-1729712-4271-6032-jpg_l.jpg","uploader":"avexotsukaai","posted":"1376143500","filecount":"20","filesize":51210504,"expunged":false,"rating":"4.43","torrentcount":"0","torrents":[],"tags":["parody:touhou project","group:handful happiness","artist:nanahara fuyuki","full color","artbook"]},{"gid":1,"error":"Key missing, or incorrect key provided."}]}"#;
+        let response = serde_json::from_str::<GdataResponse>(text).unwrap();
+        assert_eq!(response.gmetadata.len(), 2);
+        let metadata = match &response.gmetadata[0] {
+            GdataEntry::Ok(v) => v,
+            _ => panic!("failed to parse gdata"),
+        };
+        assert_eq!(metadata.posted, 1376143500);
+        assert_eq!(metadata.filecount, 20);
+        assert_eq!(metadata.parent_gid, None);
+        assert_eq!(metadata.title_jp(), None);
+        assert_eq!(
+            metadata.title(),
+            "(Kouroumu 8) [Handful&#9825;Happiness! (Fuyuki Nanahara)] TOUHOU GUNMANIA A2"
+        );
+        assert_eq!(
+            metadata.tags(),
+            vec![
+                ("parody".to_owned(), vec!["touhou project".to_owned()]),
+                ("group".to_owned(), vec!["handful happiness".to_owned()]),
+                ("artist".to_owned(), vec!["nanahara fuyuki".to_owned()]),
+                (
+                    "misc".to_owned(),
+                    vec!["full color".to_owned(), "artbook".to_owned()]
+                ),
+            ]
+        );
+        assert!(matches!(
+            response.gmetadata[1],
+            GdataEntry::Err { gid: 1, .. }
+        ));
+    }
 }
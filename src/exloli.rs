@@ -1,92 +1,468 @@
-use crate::database::Gallery;
+use crate::config::{PostMode, SearchProfile, VersionPolicy};
+use crate::context::AppContext;
+use crate::database::{Gallery, GalleryStatus, UploadJob};
 use crate::exhentai::*;
+use crate::filter::{self, Rejection};
+use crate::queue::JobKind;
+use crate::telegraph::Page;
+use crate::template::{Context as TemplateContext, Template};
 use crate::utils::*;
-use crate::{BOT, CONFIG, DB};
-use anyhow::Result;
-use chrono::{Datelike, Duration, Timelike, Utc};
+use anyhow::{Context, Result};
+use chrono::{Duration, Utc};
 use futures::TryFutureExt;
-use telegraph_rs::{html_to_node, Page, Telegraph};
+use telegraph_rs::html_to_node;
 use teloxide::prelude::*;
-use teloxide::types::{MessageId, ParseMode};
+use teloxide::types::{InputFile, InputMedia, InputMediaPhoto, MessageId, ParseMode};
+use url::Url;
 use v_htmlescape::escape;
 
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{self, Instant};
+
+/// 图片说明最长 1024 个字符
+const MAX_CAPTION_LEN: usize = 1024;
+
 pub struct ExLoli {
-    telegraph: Telegraph,
+    ctx: Arc<AppContext>,
+    /// 各个搜索配置上次扫描的时间
+    last_scan: Mutex<HashMap<String, Instant>>,
+}
+
+/// 对单个画廊计划执行的操作
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// 作为新画廊上传
+    Upload,
+    /// 在原消息的基础上更新，附带原消息 id
+    Update(i32),
+    /// 作为新消息重新发布并取代原消息，附带原消息 id
+    Republish(i32),
+    /// 被过滤规则拒绝，附带原因
+    Filter(Rejection),
+    /// 不做处理，附带原因
+    Skip(String),
+}
+
+impl Action {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Upload => "新上传",
+            Self::Update(_) => "原地更新",
+            Self::Republish(_) => "重新发布",
+            Self::Filter(_) => "过滤",
+            Self::Skip(_) => "跳过",
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Upload => write!(f, "{}", self.name()),
+            Self::Update(id) | Self::Republish(id) => write!(f, "{} #{}", self.name(), id),
+            Self::Filter(r) => write!(f, "{}：{}", self.name(), r.reason),
+            Self::Skip(reason) => write!(f, "{}：{}", self.name(), reason),
+        }
+    }
+}
+
+/// 试运行报告中的一项
+#[derive(Debug)]
+pub struct Plan {
+    pub profile: String,
+    pub url: String,
+    pub title: String,
+    pub action: Action,
 }
 
 impl ExLoli {
-    pub async fn new() -> Result<Self> {
-        let telegraph = CONFIG.init_telegraph().await?;
-        Ok(ExLoli { telegraph })
+    pub fn new(ctx: Arc<AppContext>) -> Self {
+        ExLoli {
+            ctx,
+            last_scan: Mutex::new(HashMap::new()),
+        }
     }
 
-    /// 根据配置文件自动扫描并上传本子
+    /// 根据配置文件自动扫描并上传本子，只扫描已经到达扫描间隔的搜索配置
     pub async fn scan_and_upload(&self) -> Result<()> {
-        // 筛选最新本子
-        let page_limit = CONFIG.exhentai.max_pages;
-        let galleries = EXHENTAI.search_n_pages(page_limit).await?;
-
-        // 从后往前爬, 保持顺序
-        for gallery in galleries.into_iter().rev() {
-            info!("检测中：{}", gallery.url);
-            match DB.query_gallery_by_url(&gallery.url) {
-                Ok(g) => {
-                    self.update_gallery_tag(g, gallery)
-                        .await
-                        .log_on_error()
-                        .await;
+        for profile in &self.ctx.config.search {
+            let interval = time::Duration::from_secs(self.ctx.config.interval(&profile.name));
+            let last_scan = self.last_scan.lock().unwrap().get(&profile.name).cloned();
+            if matches!(last_scan, Some(t) if t.elapsed() < interval) {
+                debug!("跳过搜索配置：{}", profile.name);
+                continue;
+            }
+            self.last_scan
+                .lock()
+                .unwrap()
+                .insert(profile.name.clone(), Instant::now());
+            info!("扫描搜索配置：{}", profile.name);
+            if let Err(e) = self.scan_profile(profile).await {
+                error!("扫描 {} 出错：{}", profile.name, e);
+            }
+        }
+        if let Err(e) = self.refresh_galleries().await {
+            error!("检查画廊更新出错：{}", e);
+        }
+        Ok(())
+    }
+
+    /// 试运行：扫描所有搜索配置并检查到期的画廊，只计算将要执行的操作，
+    /// 不发布到 telegraph 和 telegram，也不写入数据库
+    pub async fn dry_run(&self) -> Result<Vec<Plan>> {
+        let mut plans = vec![];
+        for profile in &self.ctx.config.search {
+            info!("扫描搜索配置：{}", profile.name);
+            if let Err(e) = self.plan_profile(profile, &mut plans).await {
+                error!("扫描 {} 出错：{}", profile.name, e);
+            }
+        }
+        if let Err(e) = self.plan_refresh(&mut plans).await {
+            error!("检查画廊更新出错：{}", e);
+        }
+        Ok(plans)
+    }
+
+    /// 计算指定搜索配置扫描到的画廊将如何处理，顺序与上传队列一致
+    async fn plan_profile(&self, profile: &SearchProfile, plans: &mut Vec<Plan>) -> Result<()> {
+        let galleries = self.ctx.exhentai.search_n_pages(profile).await?;
+        for mut gallery in galleries.into_iter().rev() {
+            let action = if let Ok(r) = self.ctx.db.query_rejected(&gallery.url) {
+                Action::Skip(format!("已被过滤：{}", r.reason))
+            } else if let Ok(g) = self.ctx.db.query_gallery_by_url(&gallery.url) {
+                Action::Skip(format!("已上传 #{}，由定时检查更新", g.message_id))
+            } else {
+                gallery.filter = true;
+                match gallery.clone().into_full_info().await {
+                    Ok(mut full) => self.plan_upload(&gallery, &mut full).await.0,
+                    Err(e) => Action::Skip(format!("获取画廊信息失败：{}", e)),
                 }
-                _ => {
-                    self.upload_gallery(gallery).await.log_on_error().await;
+            };
+            plans.push(Plan {
+                profile: profile.name.clone(),
+                url: gallery.url.clone(),
+                title: gallery.title.clone(),
+                action,
+            });
+        }
+        Ok(())
+    }
+
+    /// 计算到达检查时间的画廊将如何处理
+    async fn plan_refresh(&self, plans: &mut Vec<Plan>) -> Result<()> {
+        let galleries = self.ctx.db.query_due_galleries(200)?;
+        if galleries.is_empty() {
+            return Ok(());
+        }
+        let ids = galleries
+            .iter()
+            .map(|g| (g.gallery_id, g.token.clone()))
+            .collect::<Vec<_>>();
+        let metadata = self.ctx.exhentai.gallery_metadata(&ids).await?;
+        for g in &galleries {
+            let info = metadata.iter().find(|m| m.gid == g.gallery_id);
+            let action = match self.plan_refresh_gallery(g, info).await {
+                Ok(v) => v,
+                Err(e) => match e.downcast_ref::<GalleryError>() {
+                    Some(reason) => Action::Skip(format!("{}，将停止检查更新", reason)),
+                    None => Action::Skip(format!("检查更新出错：{}", e)),
+                },
+            };
+            plans.push(Plan {
+                profile: g.profile.clone(),
+                url: g.get_url(self.ctx.config.host()),
+                title: g.title.clone(),
+                action,
+            });
+        }
+        Ok(())
+    }
+
+    /// 计算单个已发布画廊的检查结果，与 refresh_gallery 的逻辑一致
+    async fn plan_refresh_gallery(
+        &self,
+        g: &Gallery,
+        info: Option<&GalleryMetadata>,
+    ) -> Result<Action> {
+        let mut tags_changed = false;
+        if let Some(info) = info {
+            if info.expunged {
+                return Err(GalleryError::Expunged.into());
+            }
+            tags_changed = Self::tags_changed(g, info)?;
+        }
+        if let Some(url) = self.find_newer_version(g, info).await? {
+            return Ok(match self.ctx.config.newer_version {
+                VersionPolicy::Update => Action::Update(g.message_id),
+                VersionPolicy::Republish => Action::Republish(g.message_id),
+                VersionPolicy::Notify => Action::Skip(format!("发现新版本 {}，仅通知讨论组", url)),
+            });
+        }
+        Ok(match tags_changed {
+            true => Action::Update(g.message_id),
+            false => Action::Skip("没有更新".to_owned()),
+        })
+    }
+
+    /// 按照指定搜索配置扫描并上传本子
+    async fn scan_profile(&self, profile: &SearchProfile) -> Result<()> {
+        // 筛选最新本子
+        let galleries = self.ctx.exhentai.search_n_pages(profile).await?;
+
+        // 已上传过的本子由 refresh_galleries 按计划检查更新
+        let mut new = vec![];
+        for gallery in galleries {
+            if self.ctx.db.query_rejected(&gallery.url).is_ok() {
+                debug!("跳过已过滤的画廊：{}", gallery.url);
+                continue;
+            }
+            if self.ctx.db.query_gallery_by_url(&gallery.url).is_ok() {
+                debug!("跳过已上传的画廊：{}", gallery.url);
+                continue;
+            }
+            new.push(gallery);
+        }
+
+        // 从后往前加入队列, 保持顺序
+        for gallery in new.into_iter().rev() {
+            info!("加入上传队列：{}", gallery.url);
+            self.ctx
+                .db
+                .insert_job(JobKind::Scan, &gallery.url, &profile.name)?;
+        }
+        Ok(())
+    }
+
+    /// 执行上传队列中的任务
+    pub async fn run_job(&self, job: &UploadJob) -> Result<()> {
+        let kind = job.kind()?;
+        match kind {
+            JobKind::Scan => {
+                let profile = self.ctx.config.profile(&job.profile);
+                let mut gallery = self
+                    .ctx
+                    .exhentai
+                    .get_gallery_by_url(&job.target, profile)
+                    .await?;
+                gallery.filter = true;
+                self.upload_gallery(gallery).await
+            }
+            JobKind::Upload => self.upload_gallery_by_url(&job.target).await,
+            JobKind::Republish => {
+                let profile = self.ctx.config.profile(&job.profile);
+                let mut gallery = self
+                    .ctx
+                    .exhentai
+                    .get_gallery_by_url(&job.target, profile)
+                    .await?;
+                gallery.republish = true;
+                self.upload_gallery(gallery).await
+            }
+            JobKind::Full | JobKind::ReUpload => {
+                let gallery = self.ctx.db.query_gallery(job.target.parse()?)?;
+                self.update_gallery(&gallery, None, kind == JobKind::ReUpload)
+                    .await
+            }
+        }
+    }
+
+    /// 检查所有到达检查时间的画廊，无论其是否还出现在搜索结果中
+    async fn refresh_galleries(&self) -> Result<()> {
+        let galleries = self.ctx.db.query_due_galleries(200)?;
+        if galleries.is_empty() {
+            return Ok(());
+        }
+        info!("检查 {} 个画廊的更新", galleries.len());
+        self.update_gallery_tag(galleries).await
+    }
+
+    /// 批量检查画廊的 tag 是否有更新，有则同步，并安排下一次检查
+    async fn update_gallery_tag(&self, galleries: Vec<Gallery>) -> Result<()> {
+        let ids = galleries
+            .iter()
+            .map(|g| (g.gallery_id, g.token.clone()))
+            .collect::<Vec<_>>();
+        let metadata = self.ctx.exhentai.gallery_metadata(&ids).await?;
+
+        for g in &galleries {
+            let info = metadata.iter().find(|m| m.gid == g.gallery_id);
+            if let Err(e) = self.refresh_gallery(g, info).await {
+                match e.downcast_ref::<GalleryError>() {
+                    Some(reason) => {
+                        self.mark_expunged(g, reason).await.log_on_error().await;
+                        continue;
+                    }
+                    None => error!(
+                        "检查画廊更新出错：{} {}",
+                        g.get_url(self.ctx.config.host()),
+                        e
+                    ),
                 }
             }
+            let next = self.ctx.config.next_check_at(g.publish_date);
+            debug!("下次检查：{} {:?}", g.get_url(self.ctx.config.host()), next);
+            self.ctx.db.update_next_check(g.id, next)?;
         }
         Ok(())
     }
 
-    /// 更新画廊信息
-    async fn update_gallery_tag<'a>(
-        &'a self,
-        g: Gallery,
-        gallery: BasicGalleryInfo<'a>,
-    ) -> Result<()> {
-        let now = Utc::now();
-        let duration = Utc::today().naive_utc() - g.publish_date;
-        // 已删除画廊不更新
-        if (g.score - -1.0).abs() < f32::EPSILON
-            // 7 天前的本子，如果是同一 weekday 发的则更新
-            || (duration.num_days() > 7 && !(now.weekday() == g.publish_date.weekday() && now.hour() % 8 == 0))
-            // 两天前的本子，每 4 小时更新一次
-            || (duration.num_days() > 2 && !(now.hour() % 4 == 0))
+    /// 检查单个画廊的 tag 更新以及新版本
+    async fn refresh_gallery(&self, g: &Gallery, info: Option<&GalleryMetadata>) -> Result<()> {
+        if let Some(info) = info {
+            if info.expunged {
+                return Err(GalleryError::Expunged.into());
+            }
+            // 检测是否需要更新 tag
+            if Self::tags_changed(g, info)? {
+                info!("tag 有更新，同步中...");
+                info!("画廊名称: {}", info.title());
+                info!("画廊地址: {}", g.get_url(self.ctx.config.host()));
+                self.update_tag(g, None).await?;
+            }
+        }
+        self.check_newer_version(g, info).await
+    }
+
+    /// 画廊的 tag 是否与 E 站上的不同
+    fn tags_changed(g: &Gallery, info: &GalleryMetadata) -> Result<bool> {
+        let old_tags = serde_json::from_str::<Vec<(String, Vec<String>)>>(&g.tags)?;
+        Ok(!same_tags(&old_tags, &info.tags()))
+    }
+
+    /// 获取画廊尚未处理过的最新版本，优先使用 gdata 接口返回的元数据，没有时才请求画廊页面
+    async fn find_newer_version(
+        &self,
+        g: &Gallery,
+        info: Option<&GalleryMetadata>,
+    ) -> Result<Option<String>> {
+        let newer = match info {
+            Some(info) => self.ctx.exhentai.newer_version(info),
+            None => self
+                .ctx
+                .exhentai
+                .get_newer_versions(&g.get_url(self.ctx.config.host()))
+                .await?
+                .pop(),
+        };
+        let url = match newer {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        if self.ctx.db.query_version(&url).is_ok() || self.ctx.db.query_gallery_by_url(&url).is_ok()
         {
-            debug!("跳过更新：{}", g.publish_date);
-            return Ok(());
+            debug!("新版本已处理过：{}", url);
+            return Ok(None);
         }
+        Ok(Some(url))
+    }
 
-        // 检测是否需要更新 tag
-        // TODO: 将 tags 塞到 BasicInfo 里
-        let info = gallery.into_full_info().await?;
-        let new_tags = serde_json::to_string(&info.tags)?;
-        if new_tags != g.tags {
-            info!("tag 有更新，同步中...");
-            info!("画廊名称: {}", info.title);
-            info!("画廊地址: {}", info.url);
-            self.update_tag(&g, Some(&info)).await?;
+    /// 将画廊标记为已被删除并停止检查更新，按配置在频道消息中注明
+    async fn mark_expunged(&self, g: &Gallery, reason: &GalleryError) -> Result<()> {
+        warn!("{}：{}", reason, g.get_url(self.ctx.config.host()));
+        self.ctx.db.update_status(g.id, GalleryStatus::Expunged)?;
+        if !self.ctx.config.telegram.mark_expunged {
+            return Ok(());
+        }
+        let text = format!("{}\n<b>已被删除</b>", self.stored_message_string(g)?);
+        let chat_id = self.ctx.config.channel_id(&g.profile).clone();
+        match g.post_mode() {
+            PostMode::Text => {
+                self.ctx
+                    .bot
+                    .edit_message_text(chat_id, MessageId(g.message_id), text)
+                    .parse_mode(ParseMode::Html)
+                    .await?;
+            }
+            PostMode::Photo | PostMode::MediaGroup => {
+                let text = match Self::caption_too_long(&text) {
+                    true => format!(
+                        "{}\n<b>已被删除</b>",
+                        Self::short_caption(&g.title, &g.telegraph)
+                    ),
+                    false => text,
+                };
+                self.ctx
+                    .bot
+                    .edit_message_caption(chat_id, MessageId(g.message_id))
+                    .caption(text)
+                    .parse_mode(ParseMode::Html)
+                    .await?;
+            }
         }
         Ok(())
     }
 
+    /// 检查已发布的画廊是否有新版本，有则按照配置处理，每个新版本只处理一次
+    async fn check_newer_version(&self, g: &Gallery, info: Option<&GalleryMetadata>) -> Result<()> {
+        let url = match self.find_newer_version(g, info).await? {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+
+        let policy = self.ctx.config.newer_version;
+        info!(
+            "发现新版本：{} -> {}（{}）",
+            g.get_url(self.ctx.config.host()),
+            url,
+            policy.as_str()
+        );
+        match policy {
+            VersionPolicy::Update => {
+                let profile = self.ctx.config.profile(&g.profile);
+                let gallery = self
+                    .ctx
+                    .exhentai
+                    .get_gallery_by_url(&url, profile)
+                    .and_then(|g| g.into_full_info())
+                    .await;
+                // 新版本无法访问时不能当作旧画廊被删除，下次检查时再重试
+                let mut gallery = match gallery {
+                    Ok(v) => v,
+                    Err(e) => {
+                        warn!("获取新版本失败：{} {}", url, e);
+                        return Ok(());
+                    }
+                };
+                // 曾经上传过完整版的，继续上传完整版
+                gallery.limit = g.upload_images as usize <= gallery.profile.max_img_cnt;
+                self.update_gallery(g, Some(gallery), false).await?;
+            }
+            VersionPolicy::Republish => {
+                self.ctx
+                    .db
+                    .insert_job(JobKind::Republish, &url, &g.profile)?;
+            }
+            VersionPolicy::Notify => {
+                let text = format!(
+                    "画廊有新版本：\n{}\n旧版本：{}\n新版本：{}",
+                    g.title,
+                    g.message_url(&self.ctx.config),
+                    url
+                );
+                self.ctx
+                    .bot
+                    .send_message(self.ctx.config.telegram.group_id, text)
+                    .disable_web_page_preview(true)
+                    .await?;
+            }
+        }
+        self.ctx.db.insert_version(&url, g, policy)
+    }
+
     /// 上传指定 URL 的画廊
     pub async fn upload_gallery_by_url(&self, url: &str) -> Result<()> {
+        let profile = self.ctx.config.default_profile();
         let gallery = match url.split_once('#') {
             Some((url, cover_index)) => {
-                let mut gallery = EXHENTAI.get_gallery_by_url(url).await?;
+                let mut gallery = self.ctx.exhentai.get_gallery_by_url(url, profile).await?;
                 gallery.limit = false;
                 gallery.cover_index = cover_index.parse()?;
                 gallery
             }
             _ => {
-                let mut gallery = EXHENTAI.get_gallery_by_url(url).await?;
+                let mut gallery = self.ctx.exhentai.get_gallery_by_url(url, profile).await?;
                 gallery.limit = false;
                 gallery
             }
@@ -94,67 +470,121 @@ impl ExLoli {
         self.upload_gallery(gallery).await
     }
 
+    /// 根据过滤规则和历史上传决定如何处理画廊，同时返回历史上传，会按需修改 gallery.limit
+    async fn plan_upload<'a>(
+        &self,
+        basic_info: &BasicGalleryInfo<'a>,
+        gallery: &mut FullGalleryInfo<'a>,
+    ) -> (Action, Result<Gallery>) {
+        if basic_info.filter {
+            if let Some(rejection) = filter::check(&self.ctx.config.filter, gallery) {
+                info!("画廊被过滤：{}", rejection.reason);
+                return (Action::Filter(rejection), Err(anyhow!("画廊被过滤")));
+            }
+        }
+
+        // 判断是否上传过历史版本
+        let old_gallery = Self::get_history_upload(&self.ctx, gallery).await;
+        let g = match &old_gallery {
+            Ok(g) => g,
+            Err(e) => {
+                warn!("没有找到历史上传：{}", e);
+                return (Action::Upload, old_gallery);
+            }
+        };
+        // 上传量已经达到限制的，不做更新
+        if g.upload_images as usize == gallery.profile.max_img_cnt
+            && gallery.limit
+            && !basic_info.republish
+        {
+            info!("上传数量已达到限制，无需更新：{}", g.message_id);
+            let action = Action::Skip(format!("上传数量已达到限制，无需更新 #{}", g.message_id));
+            return (action, old_gallery);
+        }
+        // outdate 天以内上传过的，不重复发，在原消息的基础上更新
+        // 没有图片增删的，也不重复发送
+        let outdate = gallery.profile.outdate.unwrap_or(7);
+        let not_outdated = g.publish_date + Duration::days(outdate) > Utc::today().naive_utc();
+        let not_bigupdate = gallery.img_pages.len() == g.upload_images as usize;
+
+        // FIXME: 当前判断方法可能会误判，而且修改最大图片数量以后会失效
+        // 如果曾经更新过完整版，则继续上传完整版
+        if g.upload_images as usize > gallery.profile.max_img_cnt {
+            gallery.limit = false;
+        }
+
+        // 如果没有过期或者没有图片修改，则直接更新历史消息
+        let action = if basic_info.republish {
+            info!("作为新版本重新发布：{}", g.message_id);
+            Action::Republish(g.message_id)
+        } else if not_outdated || not_bigupdate {
+            info!("找到历史上传：{}", g.message_id);
+            Action::Update(g.message_id)
+        } else {
+            info!("历史上传已过期：{}", g.message_id);
+            Action::Republish(g.message_id)
+        };
+        (action, old_gallery)
+    }
+
     /// 将画廊上传到 telegram
     async fn upload_gallery<'a>(&'a self, basic_info: BasicGalleryInfo<'a>) -> Result<()> {
         info!("上传中，画廊名称: {}", basic_info.title);
 
         let mut gallery = basic_info.clone().into_full_info().await?;
 
-        // 判断是否上传过历史版本
-        let old_gallery = Self::get_history_upload(&gallery).await;
-        match &old_gallery {
-            Ok(g) => {
-                // 上传量已经达到限制的，不做更新
-                if g.upload_images as usize == CONFIG.exhentai.max_img_cnt && gallery.limit {
-                    return Err(anyhow::anyhow!("NoNeedToUpdate"));
-                }
-                // outdate 天以内上传过的，不重复发，在原消息的基础上更新
-                // 没有图片增删的，也不重复发送
-                let outdate = CONFIG.exhentai.outdate.unwrap_or(7);
-                let not_outdated =
-                    g.publish_date + Duration::days(outdate) > Utc::today().naive_utc();
-                let not_bigupdate = gallery.img_pages.len() == g.upload_images as usize;
-
-                // FIXME: 当前判断方法可能会误判，而且修改最大图片数量以后会失效
-                // 如果曾经更新过完整版，则继续上传完整版
-                if g.upload_images as usize > CONFIG.exhentai.max_img_cnt {
-                    gallery.limit = false;
-                }
-
-                // 如果没有过期或者没有图片修改，则直接更新历史消息
-                if not_outdated || not_bigupdate {
-                    info!("找到历史上传：{}", g.message_id);
-                    return self.update_gallery(g, Some(gallery), false).await;
-                } else {
-                    info!("历史上传已过期：{}", g.message_id);
-                }
+        let (action, old_gallery) = self.plan_upload(&basic_info, &mut gallery).await;
+        match (&action, &old_gallery) {
+            (Action::Filter(rejection), _) => {
+                return self.ctx.db.insert_rejected(&gallery, rejection)
             }
-            Err(e) => warn!("没有找到历史上传：{}", e),
+            (Action::Skip(_), _) => return Ok(()),
+            (Action::Update(_), Ok(g)) => {
+                return self.update_gallery(g, Some(gallery), false).await
+            }
+            _ => (),
         }
 
-        let mut img_urls = gallery.upload_images_to_telegraph().await?;
+        let mut img_urls = gallery.upload_images(&self.ctx).await?;
         img_urls.swap(0, basic_info.cover_index);
 
         // 上传到 telegraph
         let title = gallery.title();
-        let content = Self::get_article_string(
+        let parts = Self::get_article_parts(
+            &self.ctx.article_template,
+            &self.gallery_context(&gallery),
             &img_urls,
             gallery.img_pages.len(),
             old_gallery.as_ref().ok().map(|g| g.upload_images as usize),
+            self.ctx.config.telegraph.page_size.unwrap_or(200),
         );
-        let page = self.publish_to_telegraph(title, &content).await?;
-        info!("文章地址: {}", page.url);
+        let pages = self.publish_article(title, &parts, &[]).await?;
+        let url = pages[0].url.clone();
+        info!("文章地址: {}", url);
 
         // 不需要原地更新的旧本子，发布新消息
-        let message = self.publish_to_telegram(&gallery, &page.url).await?;
+        let (message, post_mode) = self.publish_to_telegram(&gallery, &url, &img_urls).await?;
 
         // 生成 poll_id，仅当旧画廊使用的新格式 poll_id 的情况下才会继承
         let poll_id = old_gallery
-            .and_then(|g| g.poll_id.parse::<i32>().map_err(anyhow::Error::new))
+            .as_ref()
+            .ok()
+            .and_then(|g| g.poll_id.parse::<i32>().ok())
             .unwrap_or(message.id.0);
 
-        DB.insert_gallery(message.id.0, &gallery, page.url)?;
-        DB.update_poll_id(message.id.0, &poll_id.to_string())
+        let next_check = self.ctx.config.next_check_at(Utc::today().naive_utc());
+        let id = self
+            .ctx
+            .db
+            .insert_gallery(message.id.0, &gallery, url, post_mode, next_check)?;
+        // 旧消息已被新消息取代，不再检查更新
+        if let Ok(g) = &old_gallery {
+            self.ctx.db.update_status(g.id, GalleryStatus::Replaced)?;
+        }
+        self.ctx
+            .db
+            .update_telegraph_pages(id, &Self::page_paths(&pages))?;
+        self.ctx.db.update_poll_id(id, &poll_id.to_string())
     }
 
     /// 原地更新画廊，若 gallery 为 None 则原地更新为原画廊的完整版
@@ -164,12 +594,15 @@ impl ExLoli {
         gallery: Option<FullGalleryInfo<'a>>,
         republish: bool,
     ) -> Result<()> {
-        info!("更新画廊：{}", ogallery.get_url());
+        info!("更新画廊：{}", ogallery.get_url(self.ctx.config.host()));
         let gallery = match gallery {
             Some(v) => v,
             None => {
-                let mut gallery: FullGalleryInfo = EXHENTAI
-                    .get_gallery_by_url(ogallery.get_url())
+                let profile = self.ctx.config.profile(&ogallery.profile);
+                let mut gallery: FullGalleryInfo = self
+                    .ctx
+                    .exhentai
+                    .get_gallery_by_url(ogallery.get_url(self.ctx.config.host()), profile)
                     .and_then(|g| g.into_full_info())
                     .await?;
                 gallery.limit = false;
@@ -177,24 +610,30 @@ impl ExLoli {
             }
         };
 
-        let img_urls = gallery.upload_images_to_telegraph().await?;
+        let img_urls = gallery.upload_images(&self.ctx).await?;
 
         let title = gallery.title();
-        let content = Self::get_article_string(
+        let parts = Self::get_article_parts(
+            &self.ctx.article_template,
+            &self.gallery_context(&gallery),
             &img_urls,
             gallery.img_pages.len(),
             (ogallery.upload_images != 0).then_some(ogallery.upload_images as usize),
+            self.ctx.config.telegraph.page_size.unwrap_or(200),
         );
 
-        let page = if republish {
-            self.publish_to_telegraph(title, &content).await?
+        let old_paths = if republish {
+            vec![]
         } else {
-            self.edit_telegraph(extract_telegraph_path(&ogallery.telegraph), title, &content)
-                .await?
+            self.ctx.db.query_telegraph_pages(ogallery)?
         };
+        let pages = self.publish_article(title, &parts, &old_paths).await?;
+        self.ctx
+            .db
+            .update_telegraph_pages(ogallery.id, &Self::page_paths(&pages))?;
 
-        let url = format!("{}?_={}", page.url, get_timestamp());
-        self.update_message(ogallery.message_id, &gallery, &url, img_urls.len())
+        let url = format!("{}?_={}", pages[0].url, get_timestamp());
+        self.update_message(ogallery, &gallery, &url, img_urls.len(), Some(&img_urls))
             .await
     }
 
@@ -204,15 +643,16 @@ impl ExLoli {
         old_gallery: &Gallery,
         new_gallery: Option<&FullGalleryInfo<'a>>,
     ) -> Result<()> {
-        let url = old_gallery.get_url();
+        let url = old_gallery.get_url(self.ctx.config.host());
         let mut _g = None;
         let new_gallery = match new_gallery {
             Some(v) => v,
             None => {
                 // fuck lifetime
                 _g = Some(
-                    EXHENTAI
-                        .get_gallery_by_url(&url)
+                    self.ctx
+                        .exhentai
+                        .get_gallery_by_url(&url, self.ctx.config.default_profile())
                         .and_then(|g| g.into_full_info())
                         .await?,
                 );
@@ -220,43 +660,193 @@ impl ExLoli {
             }
         };
 
-        // 更新 telegraph
-        let path = extract_telegraph_path(&old_gallery.telegraph);
-        let old_page = Telegraph::get_page(path, true).await?;
-        let new_page = self
-            .telegraph
-            .edit_page(
-                &old_page.path,
-                new_gallery.title_jp.as_ref().unwrap_or(&new_gallery.title),
-                &serde_json::to_string(&old_page.content)?,
-                false,
-            )
-            .await?;
+        // 更新 telegraph，每一篇文章都需要更新标题
+        let paths = self.ctx.db.query_telegraph_pages(old_gallery)?;
+        let title = new_gallery.title_jp.as_ref().unwrap_or(&new_gallery.title);
+        let mut article = None;
+        for (i, path) in paths.iter().enumerate() {
+            let old_page = self.ctx.telegraph.get_page(path, true).await?;
+            let new_page = self
+                .ctx
+                .telegraph
+                .edit_page(
+                    &old_page.path,
+                    &Self::part_title(title, i, paths.len()),
+                    &serde_json::to_string(&old_page.content)?,
+                    false,
+                )
+                .await?;
+            article.get_or_insert(new_page.url);
+        }
+        let article = article.context("找不到 telegraph 文章")?;
 
         let upload_images = old_gallery.upload_images as usize;
-        let message_id = old_gallery.message_id;
-        self.update_message(message_id, new_gallery, &new_page.url, upload_images)
+        self.update_message(old_gallery, new_gallery, &article, upload_images, None)
             .await
     }
 
     /// 更新旧消息并同时更新数据库
+    ///
+    /// 以图片发送的消息只更新图片说明，重新上传过图片时（images 不为 None）同时更新封面；
+    /// 媒体组只更新带有说明的第一条消息，其余图片保持不变。
+    /// 图片消息无法改为文本消息，说明过长时改用只包含标题和文章链接的简短说明
     async fn update_message<'a>(
         &self,
-        message_id: i32,
+        ogallery: &Gallery,
         gallery: &FullGalleryInfo<'a>,
         article: &str,
         upload_images: usize,
+        images: Option<&[Option<Vec<String>>]>,
     ) -> Result<()> {
         info!("更新 Telegram 频道消息");
-        let text = Self::get_message_string(gallery, article);
-        BOT.edit_message_text(
-            CONFIG.telegram.channel_id.clone(),
-            MessageId(message_id),
-            &text,
+        let text = self.get_message_string(gallery, article, upload_images);
+        let message_id = ogallery.message_id;
+        let chat_id = self.ctx.config.channel_id(&ogallery.profile).clone();
+        match ogallery.post_mode() {
+            PostMode::Text => {
+                self.ctx
+                    .bot
+                    .edit_message_text(chat_id, MessageId(message_id), &text)
+                    .parse_mode(ParseMode::Html)
+                    .await?;
+            }
+            PostMode::Photo | PostMode::MediaGroup => {
+                let text = match Self::caption_too_long(&text) {
+                    true => {
+                        warn!("图片说明过长，改为只包含标题和文章链接");
+                        Self::short_caption(gallery.title(), article)
+                    }
+                    false => text,
+                };
+                let cover = images.map(|v| Self::photo_urls(v, 1)).transpose()?;
+                match cover.and_then(|v| v.into_iter().next()) {
+                    Some(url) => {
+                        let photo = InputMediaPhoto::new(InputFile::url(url))
+                            .caption(&text)
+                            .parse_mode(ParseMode::Html);
+                        self.ctx
+                            .bot
+                            .edit_message_media(
+                                chat_id,
+                                MessageId(message_id),
+                                InputMedia::Photo(photo),
+                            )
+                            .await?;
+                    }
+                    None => {
+                        self.ctx
+                            .bot
+                            .edit_message_caption(chat_id, MessageId(message_id))
+                            .caption(&text)
+                            .parse_mode(ParseMode::Html)
+                            .await?;
+                    }
+                }
+            }
+        }
+        self.ctx
+            .db
+            .update_gallery(ogallery.id, gallery, article, upload_images)
+    }
+
+    /// 文本是否超过图片说明的长度限制
+    fn caption_too_long(text: &str) -> bool {
+        text.chars().count() > MAX_CAPTION_LEN
+    }
+
+    /// 只包含标题和文章链接的图片说明
+    fn short_caption(title: &str, article: &str) -> String {
+        format!(r#"<a href="{}">{}</a>"#, article, escape(title))
+    }
+
+    /// 取前 n 张图片的完整地址，切分过的长图每一块算作一张
+    fn photo_urls(images: &[Option<Vec<String>>], n: usize) -> Result<Vec<Url>> {
+        Ok(images
+            .iter()
+            .flatten()
+            .flatten()
+            .take(n)
+            .map(|s| Url::parse(&absolute_image_url(s)))
+            .collect::<Result<Vec<_>, _>>()?)
+    }
+
+    /// 发布或更新分为多篇的 telegraph 文章，各篇文章之间互相链接
+    ///
+    /// 优先复用 old_paths 中的文章，不够时再创建新文章
+    async fn publish_article(
+        &self,
+        title: &str,
+        parts: &[String],
+        old_paths: &[String],
+    ) -> Result<Vec<Page>> {
+        let count = parts.len();
+        let mut paths = old_paths.iter().take(count).cloned().collect::<Vec<_>>();
+        let mut created = vec![];
+        // 先创建缺少的文章，得到所有文章的路径后才能生成链接
+        while paths.len() < count {
+            let i = paths.len();
+            let page = self
+                .publish_to_telegraph(&Self::part_title(title, i, count), &parts[i])
+                .await?;
+            paths.push(page.path.clone());
+            created.push(page);
+        }
+        if count == 1 && old_paths.is_empty() {
+            return Ok(created);
+        }
+
+        let mut pages = vec![];
+        for (i, part) in parts.iter().enumerate() {
+            let content = format!("{}{}", part, Self::get_nav_string(&paths, i));
+            let title = Self::part_title(title, i, count);
+            pages.push(self.edit_telegraph(&paths[i], &title, &content).await?);
+        }
+        // 篇数比原来少时，多余的旧文章改为指向第一篇的链接，避免留下过期的内容
+        for path in old_paths.iter().skip(count) {
+            let content = Self::moved_string(&paths[0]);
+            self.edit_telegraph(path, title, &content).await?;
+        }
+        Ok(pages)
+    }
+
+    fn page_paths(pages: &[Page]) -> Vec<String> {
+        pages.iter().map(|p| p.path.clone()).collect()
+    }
+
+    /// 分篇文章的标题
+    fn part_title(title: &str, i: usize, count: usize) -> String {
+        if count == 1 {
+            title.to_owned()
+        } else {
+            format!("{} ({}/{})", title, i + 1, count)
+        }
+    }
+
+    /// 已不再使用的旧文章的内容
+    fn moved_string(first: &str) -> String {
+        format!(
+            r#"<p>文章已重新排版，请前往 <a href="https://telegra.ph/{}">第一篇</a> 查看</p>"#,
+            first
         )
-        .parse_mode(ParseMode::Html)
-        .await?;
-        DB.update_gallery(message_id, gallery, article, upload_images)
+    }
+
+    /// 生成分篇文章之间的导航链接
+    fn get_nav_string(paths: &[String], i: usize) -> String {
+        if paths.len() <= 1 {
+            return String::new();
+        }
+        let link = |path: &str, text: &str| {
+            format!(r#"<a href="https://telegra.ph/{}">{}</a>"#, path, text)
+        };
+        let mut nav = vec![];
+        if i > 0 {
+            nav.push(link(&paths[i - 1], "上一篇"));
+        }
+        nav.push(format!("第 {}/{} 篇", i + 1, paths.len()));
+        if i + 1 < paths.len() {
+            nav.push(link(&paths[i + 1], "下一篇"));
+        }
+        format!("<p>{}</p>", nav.join(" | "))
     }
 
     /// 将画廊内容上传至 telegraph
@@ -264,7 +854,7 @@ impl ExLoli {
         info!("上传到 Telegraph");
         let text = html_to_node(content);
         trace!("{}", text);
-        Ok(self.telegraph.create_page(title, &text, false).await?)
+        Ok(self.ctx.telegraph.create_page(title, &text, false).await?)
     }
 
     /// 修改已有的 telegraph 文章
@@ -272,66 +862,204 @@ impl ExLoli {
         info!("更新 Telegraph: {}", path);
         let text = html_to_node(content);
         trace!("{}", text);
-        Ok(self.telegraph.edit_page(path, title, &text, false).await?)
+        Ok(self
+            .ctx
+            .telegraph
+            .edit_page(path, title, &text, false)
+            .await?)
     }
 
-    /// 将画廊内容上传至 telegraph
+    /// 将画廊发布到 telegram 频道，返回发送的消息（媒体组为第一条消息）以及实际的发送方式
     async fn publish_to_telegram<'a>(
         &self,
         gallery: &FullGalleryInfo<'a>,
         article: &str,
-    ) -> Result<Message> {
+        images: &[Option<Vec<String>>],
+    ) -> Result<(Message, PostMode)> {
         info!("发布到 Telegram 频道");
-        let text = Self::get_message_string(gallery, article);
-        Ok(BOT
-            .send_message(CONFIG.telegram.channel_id.clone(), &text)
-            .parse_mode(ParseMode::Html)
-            .await?)
+        let text = self.get_message_string(gallery, article, images.len());
+        let chat_id = self.ctx.config.channel_id(&gallery.profile.name).clone();
+        let mut mode = self.ctx.config.telegram.post_mode;
+        let photos = match mode {
+            PostMode::Text => vec![],
+            PostMode::Photo => Self::photo_urls(images, 1)?,
+            PostMode::MediaGroup => {
+                let size = self.ctx.config.telegram.media_group_size.unwrap_or(4);
+                Self::photo_urls(images, size.clamp(2, 10))?
+            }
+        };
+        if mode != PostMode::Text && (photos.is_empty() || Self::caption_too_long(&text)) {
+            warn!("无法以图片形式发送，改为发送文本消息");
+            mode = PostMode::Text;
+        }
+
+        let message = match mode {
+            PostMode::Text => {
+                self.ctx
+                    .bot
+                    .send_message(chat_id, &text)
+                    .parse_mode(ParseMode::Html)
+                    .await?
+            }
+            PostMode::Photo => {
+                self.ctx
+                    .bot
+                    .send_photo(chat_id, InputFile::url(photos[0].clone()))
+                    .caption(&text)
+                    .parse_mode(ParseMode::Html)
+                    .await?
+            }
+            PostMode::MediaGroup => {
+                let media = photos.into_iter().enumerate().map(|(i, url)| {
+                    let photo = InputMediaPhoto::new(InputFile::url(url));
+                    InputMedia::Photo(match i {
+                        0 => photo.caption(&text).parse_mode(ParseMode::Html),
+                        _ => photo,
+                    })
+                });
+                self.ctx
+                    .bot
+                    .send_media_group(chat_id, media)
+                    .await?
+                    .swap_remove(0)
+            }
+        };
+        Ok((message, mode))
     }
 
-    /// 生成用于发送消息的字符串
-    fn get_message_string<'a>(gallery: &FullGalleryInfo<'a>, article: &str) -> String {
-        let mut tags = tags_to_string(&gallery.tags);
-        tags.push_str(&format!(
-            "\n<code>  预览</code>: <a href=\"{}\">{}</a>",
-            article,
-            escape(&gallery.title)
-        ));
-        tags.push_str(&format!("\n<code>原始地址</code>: {} ", gallery.url));
-        tags
+    /// 生成模板中可以使用的画廊信息
+    fn gallery_context<'a>(&self, gallery: &FullGalleryInfo<'a>) -> TemplateContext {
+        let title_jp = gallery.title_jp.as_deref();
+        let mut ctx = self.base_context(&gallery.title, title_jp, &gallery.url, &gallery.tags);
+        ctx.insert("rating".into(), gallery.rating.as_str().into());
+        ctx.insert("fav_cnt".into(), gallery.fav_cnt.as_str().into());
+        ctx.insert("pages".into(), gallery.img_pages.len().into());
+        ctx
     }
 
-    /// 生成 telegraph 文章内容
-    fn get_article_string(
-        image_urls: &[String],
-        total_image: usize,
-        last_uploaded: Option<usize>,
+    /// 生成模板中画廊的标题、地址和 tag 信息
+    fn base_context(
+        &self,
+        title: &str,
+        title_jp: Option<&str>,
+        url: &str,
+        tags: &[(String, Vec<String>)],
+    ) -> TemplateContext {
+        let tag_rows = tags
+            .iter()
+            .zip(translate_tags(&self.ctx.trans, tags))
+            .map(|((ns, raw), (trans_ns, trans))| {
+                let mut row = TemplateContext::new();
+                row.insert("namespace".into(), trans_ns.into());
+                row.insert("namespace_raw".into(), ns.as_str().into());
+                row.insert("tags".into(), trans.join(" ").into());
+                row.insert("tags_raw".into(), raw.join(", ").into());
+                row
+            })
+            .collect::<Vec<_>>();
+        let mut ctx = TemplateContext::new();
+        ctx.insert("title".into(), title.into());
+        ctx.insert("title_jp".into(), title_jp.unwrap_or_default().into());
+        ctx.insert("url".into(), url.into());
+        ctx.insert("tags".into(), tags_to_string(&self.ctx.trans, tags).into());
+        ctx.insert("tag_rows".into(), tag_rows.into());
+        ctx
+    }
+
+    /// 根据数据库中保存的信息生成消息，用于画廊已经无法访问的情况
+    ///
+    /// 数据库中没有保存的变量（如评分、收藏数）渲染为空
+    fn stored_message_string(&self, g: &Gallery) -> Result<String> {
+        let tags = serde_json::from_str::<Vec<(String, Vec<String>)>>(&g.tags)?;
+        let url = g.get_url(self.ctx.config.host());
+        let mut ctx = self.base_context(&g.title, None, &url, &tags);
+        ctx.insert("article_url".into(), g.telegraph.as_str().into());
+        ctx.insert("uploaded".into(), (g.upload_images as usize).into());
+        Ok(self.ctx.message_template.render(&ctx))
+    }
+
+    /// 生成用于发送消息的字符串
+    fn get_message_string<'a>(
+        &self,
+        gallery: &FullGalleryInfo<'a>,
+        article: &str,
+        upload_images: usize,
     ) -> String {
-        let mut content = img_urls_to_html(image_urls);
-        if last_uploaded.is_some() || image_urls.len() != total_image {
-            content.push_str("<p>");
-            content.push_str(&format!("已上传 {}/{}", image_urls.len(), total_image,));
-            if let Some(v) = last_uploaded {
-                content.push_str(&format!("，上次上传到 {}", v));
-            }
-            if image_urls.len() != total_image {
-                content.push_str("，完整版请前往 E 站观看");
+        let mut ctx = self.gallery_context(gallery);
+        ctx.insert("article_url".into(), article.into());
+        ctx.insert("uploaded".into(), upload_images.into());
+        self.ctx.message_template.render(&ctx)
+    }
+
+    /// 按图片数量切分文章，切分过的长图每一块算作一张，同一张图片的各块不会被分到两篇文章中
+    fn chunk_images(
+        image_urls: &[Option<Vec<String>>],
+        page_size: usize,
+    ) -> Vec<&[Option<Vec<String>>]> {
+        let mut chunks = vec![];
+        let (mut start, mut tiles) = (0, 0);
+        for (i, url) in image_urls.iter().enumerate() {
+            let n = url.as_ref().map_or(0, Vec::len);
+            if tiles > 0 && tiles + n > page_size {
+                chunks.push(&image_urls[start..i]);
+                start = i;
+                tiles = 0;
             }
-            content.push_str("</p>");
+            tiles += n;
+        }
+        if start < image_urls.len() || chunks.is_empty() {
+            chunks.push(&image_urls[start..]);
         }
-        content
+        chunks
+    }
+
+    /// 生成 telegraph 文章内容，每篇文章最多包含 page_size 张图片
+    fn get_article_parts(
+        template: &Template,
+        ctx: &TemplateContext,
+        image_urls: &[Option<Vec<String>>],
+        total_image: usize,
+        last_uploaded: Option<usize>,
+        page_size: usize,
+    ) -> Vec<String> {
+        let chunks = Self::chunk_images(image_urls, page_size.max(1));
+        let count = chunks.len();
+        let partial = image_urls.len() != total_image;
+        let show_progress = last_uploaded.is_some() || partial;
+        let mut ctx = ctx.clone();
+        ctx.insert("parts".into(), count.into());
+        ctx.insert("uploaded".into(), image_urls.len().into());
+        ctx.insert("total".into(), total_image.into());
+        let last_uploaded = last_uploaded.map(|v| v.to_string()).unwrap_or_default();
+        ctx.insert("last_uploaded".into(), last_uploaded.into());
+        ctx.insert("partial".into(), partial.into());
+        ctx.insert("show_progress".into(), show_progress.into());
+        chunks
+            .into_iter()
+            .enumerate()
+            .map(|(i, chunk)| {
+                ctx.insert("images".into(), img_urls_to_html(chunk).into());
+                ctx.insert("part".into(), (i + 1).into());
+                ctx.insert("is_last".into(), (i + 1 == count).into());
+                template.render(&ctx)
+            })
+            .collect()
     }
 }
 
 impl ExLoli {
     /// 获取画廊的历史上传
-    pub async fn get_history_upload<'a>(gallery: &FullGalleryInfo<'a>) -> Result<Gallery> {
+    pub async fn get_history_upload<'a>(
+        ctx: &AppContext,
+        gallery: &FullGalleryInfo<'a>,
+    ) -> Result<Gallery> {
         let mut gallery_url = Some(gallery.url.clone());
         while let Some(url) = &gallery_url {
-            match DB.query_gallery_by_url(url) {
+            match ctx.db.query_gallery_by_url(url) {
                 Ok(v) => return Ok(v),
                 _ => {
-                    let gallery = EXHENTAI.get_gallery_by_url(url).await?;
+                    let profile = ctx.config.default_profile();
+                    let gallery = ctx.exhentai.get_gallery_by_url(url, profile).await?;
                     let parent = gallery.into_full_info().await?;
                     gallery_url = parent.parent;
                 }
@@ -340,3 +1068,173 @@ impl ExLoli {
         Err(anyhow!("Not Found"))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{
+        fixture, insert_test_gallery, mock_exhentai, read_only_context, test_context,
+        test_context_with,
+    };
+    use crate::template::DEFAULT_ARTICLE;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_dry_run() {
+        let (server, ctx) = test_context();
+        mock_exhentai(&server);
+        let base = server.url();
+        let before = std::fs::read(&ctx.config.database_url).unwrap();
+
+        let ctx = read_only_context(&server, &ctx);
+        let plans = ExLoli::new(ctx.clone()).dry_run().await.unwrap();
+        let action = |url: &str| {
+            plans
+                .iter()
+                .find(|p| p.url == format!("{}{}", base, url))
+                .map(|p| p.action.clone())
+        };
+        assert_eq!(plans.len(), 2);
+        assert_eq!(action("/g/1000/abcdef1234/"), Some(Action::Upload));
+        assert!(matches!(
+            action("/g/2000/0123456789/"),
+            Some(Action::Skip(_))
+        ));
+
+        for method in [
+            "sendMessage",
+            "sendPhoto",
+            "sendMediaGroup",
+            "editMessageText",
+            "createPage",
+            "upload",
+        ] {
+            assert!(server.calls(method).is_empty(), "{} 被调用", method);
+        }
+        assert_eq!(std::fs::read(&ctx.config.database_url).unwrap(), before);
+        assert!(ctx.db.count_jobs_by_state().unwrap().is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_upload_gallery() {
+        let (server, ctx) = test_context();
+        mock_exhentai(&server);
+        let url = format!("{}/g/1000/abcdef1234/", server.url());
+        ExLoli::new(ctx.clone())
+            .upload_gallery_by_url(&url)
+            .await
+            .unwrap();
+
+        // 两页共 5 张图片，每张上传一次
+        let uploads = server.requests();
+        let uploads = uploads.iter().filter(|r| r.path == "/upload").count();
+        assert_eq!(uploads, 5);
+        let pages = server.calls("createPage");
+        assert_eq!(pages.len(), 1);
+        assert!(pages[0]["content"]
+            .as_str()
+            .unwrap()
+            .contains("/file/test.jpg"));
+        assert!(server.calls("editPage").is_empty());
+        let messages = server.calls("sendMessage");
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0]["chat_id"], "@exloli_test");
+        assert!(messages[0]["text"]
+            .as_str()
+            .unwrap()
+            .contains("https://telegra.ph/Test-01-01"));
+
+        let g = ctx.db.query_gallery_by_url(&url).unwrap();
+        assert_eq!((g.message_id, g.upload_images), (100, 5));
+        assert_eq!(g.telegraph, "https://telegra.ph/Test-01-01");
+        assert_eq!(g.poll_id, "100");
+        assert_eq!(g.status(), GalleryStatus::Active);
+        assert_eq!(ctx.db.query_telegraph_pages(&g).unwrap(), ["Test-01-01"]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_newer_version_unavailable() {
+        let (server, ctx) = test_context_with(|c| c.newer_version = VersionPolicy::Update);
+        mock_exhentai(&server);
+        let newer = format!("{}/g/1001/bbbbbbbbbb/", server.url());
+        let body = fixture("exhentai/not_available.html", &server.url());
+        server.route("/g/1001/bbbbbbbbbb/", 200, body);
+        let g = insert_test_gallery(&ctx, &server, 100).await;
+
+        ExLoli::new(ctx.clone())
+            .update_gallery_tag(vec![g.clone()])
+            .await
+            .unwrap();
+        // 新版本无法访问不影响旧画廊，也不记录为已处理
+        let g = ctx.db.query_gallery(g.id).unwrap();
+        assert_eq!(g.status(), GalleryStatus::Active);
+        assert!(ctx.db.query_version(&newer).is_err());
+    }
+
+    #[test]
+    fn test_action_display() {
+        assert_eq!(Action::Upload.to_string(), "新上传");
+        assert_eq!(Action::Update(3).to_string(), "原地更新 #3");
+        let rejection = Rejection {
+            reason: "ai".to_owned(),
+            temporary: false,
+        };
+        assert_eq!(Action::Filter(rejection).to_string(), "过滤：ai");
+    }
+
+    #[test]
+    fn test_short_caption() {
+        assert!(ExLoli::caption_too_long(&"啊".repeat(MAX_CAPTION_LEN + 1)));
+        assert!(!ExLoli::caption_too_long(&"啊".repeat(MAX_CAPTION_LEN)));
+        assert_eq!(
+            ExLoli::short_caption("[A] B & C", "https://telegra.ph/a"),
+            r#"<a href="https://telegra.ph/a">[A] B &amp; C</a>"#
+        );
+    }
+
+    #[test]
+    fn test_article_parts() {
+        let urls = (0..5)
+            .map(|i| Some(vec![format!("/file/{}.jpg", i)]))
+            .collect::<Vec<_>>();
+        let template = Template::parse(DEFAULT_ARTICLE).unwrap();
+        let ctx = TemplateContext::new();
+        let parts = ExLoli::get_article_parts(&template, &ctx, &urls, 5, None, 2);
+        assert_eq!(parts.len(), 3);
+        assert_eq!(
+            parts[0],
+            r#"<img src="/file/0.jpg"><img src="/file/1.jpg">"#
+        );
+        assert_eq!(parts[2], r#"<img src="/file/4.jpg">"#);
+
+        let parts = ExLoli::get_article_parts(&template, &ctx, &urls[..4], 10, None, 200);
+        assert_eq!(parts.len(), 1);
+        assert!(parts[0].ends_with("<p>已上传 4/10，完整版请前往 E 站观看</p>"));
+    }
+
+    #[test]
+    fn test_chunk_images() {
+        let urls = vec![
+            Some(vec!["a1".to_owned(), "a2".to_owned(), "a3".to_owned()]),
+            None,
+            Some(vec!["b".to_owned()]),
+            Some(vec!["c1".to_owned(), "c2".to_owned()]),
+            Some(vec!["d".to_owned()]),
+        ];
+        let chunks = ExLoli::chunk_images(&urls, 3);
+        assert_eq!(chunks, vec![&urls[..2], &urls[2..4], &urls[4..]]);
+        // 切块数量超过 page_size 的长图单独成篇
+        assert_eq!(ExLoli::chunk_images(&urls[..1], 2), vec![&urls[..1]]);
+        assert_eq!(ExLoli::chunk_images(&[], 2).len(), 1);
+    }
+
+    #[test]
+    fn test_nav_string() {
+        let paths = vec!["a".to_owned(), "b".to_owned(), "c".to_owned()];
+        assert_eq!(
+            ExLoli::get_nav_string(&paths, 1),
+            r#"<p><a href="https://telegra.ph/a">上一篇</a> | 第 2/3 篇 | <a href="https://telegra.ph/c">下一篇</a></p>"#
+        );
+        assert!(ExLoli::get_nav_string(&paths, 0).starts_with("<p>第 1/3 篇"));
+        assert_eq!(ExLoli::get_nav_string(&paths[..1], 0), "");
+    }
+}
@@ -0,0 +1,205 @@
+use crate::exhentai::FullGalleryInfo;
+use serde::Deserialize;
+
+/// 过滤规则，画廊不满足其中任意一个条件时就会被过滤
+#[derive(Debug, Deserialize)]
+pub struct FilterRule {
+    /// 过滤原因
+    pub reason: String,
+    /// 必须包含的 tag，每一项都至少要匹配一个 tag
+    #[serde(default)]
+    pub require: Vec<String>,
+    /// 不能包含的 tag，匹配任意一项即被过滤
+    #[serde(default)]
+    pub forbid: Vec<String>,
+    /// 最低评分
+    pub min_rating: Option<f32>,
+    /// 最低收藏数
+    pub min_favorites: Option<i32>,
+    /// 最大页数
+    pub max_pages: Option<usize>,
+}
+
+impl FilterRule {
+    /// 判断画廊是否应当被过滤
+    pub fn reject(
+        &self,
+        tags: &[(String, Vec<String>)],
+        rating: f32,
+        fav_cnt: i32,
+        pages: usize,
+    ) -> bool {
+        self.reject_permanently(tags, pages)
+            || matches!(self.min_rating, Some(v) if rating < v)
+            || matches!(self.min_favorites, Some(v) if fav_cnt < v)
+    }
+
+    /// 只根据 tag 和页数判断画廊是否应当被过滤，这两项一般不会再变化
+    pub fn reject_permanently(&self, tags: &[(String, Vec<String>)], pages: usize) -> bool {
+        let has_tag = |pattern: &str| {
+            tags.iter()
+                .flat_map(|(ns, v)| v.iter().map(move |tag| (ns, tag)))
+                .any(|(ns, tag)| match_tag(pattern, ns, tag))
+        };
+        !self.require.iter().all(|p| has_tag(p.as_str()))
+            || self.forbid.iter().any(|p| has_tag(p.as_str()))
+            || matches!(self.max_pages, Some(v) if pages > v)
+    }
+}
+
+/// 画廊被过滤的原因
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rejection {
+    pub reason: String,
+    /// 只因评分或收藏数不足被过滤，这两项会随时间增长，需要过一段时间后重新检查
+    pub temporary: bool,
+}
+
+/// 根据配置中的规则检查画廊，被过滤时返回原因，优先返回不会变化的原因
+pub fn check(rules: &[FilterRule], gallery: &FullGalleryInfo) -> Option<Rejection> {
+    let rating = gallery.rating.parse::<f32>().unwrap_or(0.);
+    let fav_cnt = parse_fav_cnt(&gallery.fav_cnt);
+    let pages = gallery.img_pages.len();
+    let permanent = rules
+        .iter()
+        .find(|rule| rule.reject_permanently(&gallery.tags, pages));
+    let temporary = || {
+        rules
+            .iter()
+            .find(|rule| rule.reject(&gallery.tags, rating, fav_cnt, pages))
+    };
+    match permanent {
+        Some(rule) => Some(Rejection {
+            reason: rule.reason.clone(),
+            temporary: false,
+        }),
+        None => temporary().map(|rule| Rejection {
+            reason: rule.reason.clone(),
+            temporary: true,
+        }),
+    }
+}
+
+/// 收藏数在页面上可能显示为 Never 或 Once
+fn parse_fav_cnt(s: &str) -> i32 {
+    match s {
+        "Never" => 0,
+        "Once" => 1,
+        s => s.parse().unwrap_or(0),
+    }
+}
+
+/// 匹配形如 `namespace:tag` 的规则，两部分均支持 `*` 通配符，省略 namespace 时匹配任意 namespace
+fn match_tag(pattern: &str, namespace: &str, tag: &str) -> bool {
+    let pattern = pattern.to_lowercase();
+    match pattern.split_once(':') {
+        Some((ns, p)) => wildcard_match(ns, namespace) && wildcard_match(p, tag),
+        None => wildcard_match(&pattern, tag),
+    }
+}
+
+/// 简单的通配符匹配，`*` 匹配任意个字符
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let (p, t) = (pattern.as_bytes(), text.as_bytes());
+    let (mut pi, mut ti) = (0, 0);
+    // 上一个 `*` 的位置，以及它当时匹配到的文本位置
+    let mut star = None;
+    while ti < t.len() {
+        if pi < p.len() && p[pi] == b'*' {
+            star = Some((pi, ti));
+            pi += 1;
+        } else if pi < p.len() && p[pi] == t[ti] {
+            pi += 1;
+            ti += 1;
+        } else if let Some((sp, st)) = star {
+            pi = sp + 1;
+            ti = st + 1;
+            star = Some((sp, st + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags() -> Vec<(String, Vec<String>)> {
+        vec![
+            ("language".to_owned(), vec!["chinese".to_owned()]),
+            (
+                "other".to_owned(),
+                vec!["full color".to_owned(), "ai generated".to_owned()],
+            ),
+        ]
+    }
+
+    fn rule() -> FilterRule {
+        FilterRule {
+            reason: "test".to_owned(),
+            require: vec![],
+            forbid: vec![],
+            min_rating: None,
+            min_favorites: None,
+            max_pages: None,
+        }
+    }
+
+    #[test]
+    fn test_wildcard() {
+        assert!(wildcard_match("*ai generated*", "ai generated"));
+        assert!(wildcard_match("*gen*", "ai generated"));
+        assert!(wildcard_match("a*d", "ai generated"));
+        assert!(!wildcard_match("a*x", "ai generated"));
+        assert!(wildcard_match("*", ""));
+        assert!(!wildcard_match("", "a"));
+        assert!(match_tag("Other:*AI*", "other", "ai generated"));
+        assert!(match_tag("*:chinese", "language", "chinese"));
+        assert!(match_tag("chinese", "language", "chinese"));
+        assert!(!match_tag("female:chinese", "language", "chinese"));
+    }
+
+    #[test]
+    fn test_reject() {
+        let tags = tags();
+        let forbid = FilterRule {
+            forbid: vec!["other:*ai generated*".to_owned()],
+            ..rule()
+        };
+        assert!(forbid.reject(&tags, 4.5, 100, 20));
+
+        let require = FilterRule {
+            require: vec!["language:chinese".to_owned(), "full color".to_owned()],
+            ..rule()
+        };
+        assert!(!require.reject(&tags, 4.5, 100, 20));
+        let require = FilterRule {
+            require: vec!["language:english".to_owned()],
+            ..rule()
+        };
+        assert!(require.reject(&tags, 4.5, 100, 20));
+
+        let limit = FilterRule {
+            min_rating: Some(4.0),
+            min_favorites: Some(10),
+            max_pages: Some(100),
+            ..rule()
+        };
+        assert!(!limit.reject(&tags, 4.5, 100, 20));
+        assert!(limit.reject(&tags, 3.5, 100, 20));
+        assert!(limit.reject(&tags, 4.5, 5, 20));
+        assert!(limit.reject(&tags, 4.5, 100, 200));
+        assert!(!limit.reject_permanently(&tags, 20));
+        assert!(limit.reject_permanently(&tags, 200));
+        assert!(forbid.reject_permanently(&tags, 20));
+    }
+
+    #[test]
+    fn test_parse_fav_cnt() {
+        assert_eq!(parse_fav_cnt("Never"), 0);
+        assert_eq!(parse_fav_cnt("Once"), 1);
+        assert_eq!(parse_fav_cnt("42"), 42);
+    }
+}
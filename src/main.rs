@@ -7,98 +7,356 @@ extern crate diesel_migrations;
 #[macro_use]
 extern crate anyhow;
 
+use crate::cli::{Args, Command};
 use crate::config::Config;
-use crate::database::DataBase;
+use crate::context::AppContext;
+use crate::database::{DataBase, Gallery};
 use crate::exloli::ExLoli;
 
 use anyhow::Error;
-use futures::executor::block_on;
-use once_cell::sync::Lazy;
-use teloxide::prelude::*;
+use chrono::Utc;
 use tokio::time::sleep;
 
+use std::collections::BTreeMap;
 use std::env;
+use std::fs::File;
+use std::io::Write;
+use std::process;
 use std::str::FromStr;
+use std::sync::Arc;
 use std::time;
 
 mod bot;
+mod cli;
 mod config;
+mod context;
 mod database;
 //mod ehentai;
 mod exhentai;
 mod exloli;
+mod filter;
+mod host;
+#[cfg(test)]
+mod mock;
+mod preprocess;
+mod queue;
 mod schema;
+mod telegraph;
+mod template;
 mod trans;
 mod utils;
 mod xpath;
 
-static CONFIG: Lazy<Config> = Lazy::new(|| {
-    let config_file = std::env::var("EXLOLI_CONFIG");
-    let config_file = config_file.as_deref().unwrap_or("config.toml");
-    Config::new(config_file).expect("配置文件解析失败")
-});
-static BOT: Lazy<Bot> = Lazy::new(|| teloxide::Bot::new(&CONFIG.telegram.token));
-static DB: Lazy<DataBase> = Lazy::new(|| DataBase::init().expect("数据库初始化失败"));
-static EXLOLI: Lazy<ExLoli> = Lazy::new(|| block_on(ExLoli::new()).expect("登录失败"));
-
 #[tokio::main]
 async fn main() {
+    let args = env::args().collect::<Vec<_>>();
+    let args = match Args::parse(&args) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(2);
+        }
+    };
+    if let Command::Help(usage) = &args.command {
+        print!("{}", usage);
+        return;
+    }
+    let config = match Config::new(&args.config) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("配置文件 {} 解析失败：{:#}", args.config, e);
+            process::exit(1);
+        }
+    };
+
     env_logger::builder()
         .format_timestamp_secs()
         .write_style(env_logger::WriteStyle::Auto)
         .filter(Some("teloxide"), log::LevelFilter::Error)
         .filter(
             Some("exloli"),
-            log::LevelFilter::from_str(&CONFIG.log_level).expect("LOG 等级设置错误"),
+            log::LevelFilter::from_str(&config.log_level).expect("LOG 等级设置错误"),
         )
         .init();
-    env::set_var("DATABASE_URL", &CONFIG.database_url);
 
-    if let Err(e) = run().await {
-        error!("{}", e);
+    if let Err(e) = run(config, args).await {
+        error!("{:#}", e);
+        process::exit(1);
     }
 }
 
-fn init_args() -> getopts::Matches {
-    let args = env::args().collect::<Vec<_>>();
-    let mut opts = getopts::Options::new();
-    opts.optflag("", "debug", "调试模式，不自动爬本");
-    opts.optflag("h", "help", "打印帮助");
-    let matches = match opts.parse(&args[1..]) {
-        Ok(v) => v,
-        Err(e) => panic!("{}", e),
-    };
-    if matches.opt_present("h") {
-        let brief = format!("Usage: {} [options]", args[0]);
-        print!("{}", opts.usage(&brief));
-        std::process::exit(0);
+async fn run(config: Config, args: Args) -> Result<(), Error> {
+    if args.dry_run {
+        return match args.command {
+            Command::Run | Command::ScanOnce => dry_run(config).await,
+            _ => Err(anyhow!("--dry-run 只能用于 run 和 scan-once")),
+        };
+    }
+    if args.debug && args.command != Command::Run {
+        bail!("--debug 只能用于 run");
+    }
+    match args.command {
+        Command::Run => run_bot(config, args.debug).await,
+        Command::ScanOnce => {
+            let ctx = Arc::new(AppContext::new(config).await?);
+            let exloli = ExLoli::new(ctx.clone());
+            exloli.scan_and_upload().await?;
+            queue::run_pending(&ctx, &exloli).await
+        }
+        Command::Upload(urls) => {
+            let exloli = ExLoli::new(Arc::new(AppContext::new(config).await?));
+            let mut failed = 0;
+            for url in &urls {
+                if let Err(e) = exloli.upload_gallery_by_url(url).await {
+                    error!("上传 {} 失败：{}", url, e);
+                    failed += 1;
+                }
+            }
+            match failed {
+                0 => Ok(()),
+                _ => Err(anyhow!("{} 个画廊上传失败", failed)),
+            }
+        }
+        Command::UpdateTags(targets) => {
+            let ctx = Arc::new(AppContext::new(config).await?);
+            let exloli = ExLoli::new(ctx.clone());
+            let mut failed = 0;
+            for target in &targets {
+                let result = match find_gallery(&ctx, target) {
+                    Ok(g) => exloli.update_tag(&g, None).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = result {
+                    error!("更新 {} 的 tag 失败：{}", target, e);
+                    failed += 1;
+                }
+            }
+            match failed {
+                0 => Ok(()),
+                _ => Err(anyhow!("{} 个画廊更新失败", failed)),
+            }
+        }
+        Command::DbMigrate => {
+            open_db(&config)?;
+            info!("数据库迁移完成");
+            Ok(())
+        }
+        Command::DbExport(path) => export_galleries(&config, &open_db(&config)?, &path),
+        Command::AuditExport(path) => export_audit_log(&open_db(&config)?, &path),
+        Command::DbStats(tag) => print_stats(&config, &open_db(&config)?, tag),
+        Command::ConfigCheck => check_config(&config),
+        Command::Translate(namespace, tag) => {
+            let trans = trans::Database::load(&config.translation)?;
+            println!("{}", trans.trans(&namespace, &tag));
+            Ok(())
+        }
+        Command::Help(_) => Ok(()),
     }
-    matches
 }
 
-async fn run() -> Result<(), Error> {
-    let matches = init_args();
+/// 启动 bot 和上传队列，并定时扫描
+async fn run_bot(config: Config, debug_mode: bool) -> Result<(), Error> {
+    let ctx = Arc::new(AppContext::new(config).await?);
+    let exloli = Arc::new(ExLoli::new(ctx.clone()));
 
-    env::var("DATABASE_URL").expect("请设置 DATABASE_URL");
-
-    let debug_mode = matches.opt_present("debug");
-
-    tokio::spawn(async move {
-        sleep(time::Duration::from_secs(10)).await;
-        bot::start_bot(BOT.clone()).await
+    tokio::spawn({
+        let (ctx, exloli) = (ctx.clone(), exloli.clone());
+        async move {
+            sleep(time::Duration::from_secs(10)).await;
+            bot::start_bot(ctx, exloli).await
+        }
     });
 
+    let reset = ctx.db.reset_running_jobs()?;
+    if reset > 0 {
+        info!("{} 个未完成的上传任务已重新加入队列", reset);
+    }
+    for id in 0..ctx.config.workers.unwrap_or(1) {
+        tokio::spawn(queue::run_worker(id, ctx.clone(), exloli.clone()));
+    }
+
     loop {
         if !debug_mode {
             info!("定时更新开始");
-            let result = EXLOLI.scan_and_upload().await;
+            let result = exloli.scan_and_upload().await;
             if let Err(e) = result {
                 error!("定时更新出错：{}", e);
             } else {
                 info!("定时更新完成");
             }
         }
-        info!("休眠中，预计 {} 分钟后开始工作", CONFIG.interval / 60);
-        sleep(time::Duration::from_secs(CONFIG.interval)).await;
+        let config = &ctx.config;
+        let interval = config
+            .search
+            .iter()
+            .map(|p| config.interval(&p.name))
+            .min()
+            .unwrap_or(config.interval);
+        info!("休眠中，预计 {} 分钟后开始工作", interval / 60);
+        sleep(time::Duration::from_secs(interval)).await;
+    }
+}
+
+/// 试运行一次扫描，打印每个画廊计划执行的操作
+async fn dry_run(config: Config) -> Result<(), Error> {
+    let exloli = ExLoli::new(Arc::new(AppContext::read_only(config).await?));
+    let plans = exloli.dry_run().await?;
+    let mut summary = BTreeMap::new();
+    for plan in &plans {
+        println!("[{}] {}", plan.profile, plan.action);
+        println!("    {} {}", plan.url, plan.title);
+        *summary.entry(plan.action.name()).or_insert(0) += 1;
+    }
+    let summary = summary
+        .iter()
+        .map(|(name, count)| format!("{} {}", name, count))
+        .collect::<Vec<_>>();
+    println!("共 {} 个画廊：{}", plans.len(), summary.join("，"));
+    Ok(())
+}
+
+/// 打开数据库，不登录 E 站也不启动 bot，会自动执行数据库迁移
+fn open_db(config: &Config) -> Result<DataBase, Error> {
+    let trans = Arc::new(trans::Database::load(&config.translation)?);
+    DataBase::init(&config.database_url, trans)
+}
+
+/// 检查配置文件引用的翻译数据库和模板能否正常加载
+fn check_config(config: &Config) -> Result<(), Error> {
+    trans::Database::load(&config.translation)?;
+    AppContext::load_templates(config)?;
+    for profile in &config.search {
+        println!(
+            "搜索配置 {}：最多 {} 页，间隔 {} 秒",
+            profile.name,
+            profile.max_pages,
+            config.interval(&profile.name)
+        );
+    }
+    println!("配置文件检查通过");
+    Ok(())
+}
+
+/// 打印画廊和上传队列的统计信息，指定 tag 时列出最近的相关画廊
+fn print_stats(config: &Config, db: &DataBase, tag: Option<(String, String)>) -> Result<(), Error> {
+    println!("画廊：");
+    for (status, count) in db.count_gallery_by_status()? {
+        println!("  {:<10} {}", status, count);
+    }
+    println!("上传队列：");
+    for (state, count) in db.count_jobs_by_state()? {
+        println!("  {:<10} {}", state, count);
+    }
+    let to = Utc::today().naive_utc();
+    let from = to - chrono::Duration::days(30);
+    println!("最近 30 天最常见的 tag：");
+    for (namespace, tag, count) in db.query_top_tags(from, to, 20)? {
+        println!("  {:<30} {}", format!("{}:{}", namespace, tag), count);
+    }
+    if let Some((namespace, tag)) = tag {
+        let count = db.count_gallery_by_tag(&namespace, &tag)?;
+        println!("{}:{} 共 {} 个画廊，最近的画廊：", namespace, tag, count);
+        for g in db.query_gallery_by_tag(&namespace, &tag, 10)? {
+            println!("  {} {} {}", g.publish_date, g.message_url(config), g.title);
+        }
+    }
+    Ok(())
+}
+
+/// 根据主频道的消息 id、频道消息地址或画廊地址查找画廊
+fn find_gallery(ctx: &AppContext, target: &str) -> Result<Gallery, Error> {
+    let config = &ctx.config;
+    if let Ok(id) = target.parse::<i32>() {
+        let main = &config.telegram.channel_id;
+        return ctx
+            .db
+            .query_gallery_by_message(id, |g| config.channel_id(&g.profile) == main);
+    }
+    for channel in config.channels() {
+        let id = bot::message_url_regex(channel)
+            .captures(target)
+            .and_then(|c| c.get(1)?.as_str().parse::<i32>().ok());
+        if let Some(id) = id {
+            return ctx
+                .db
+                .query_gallery_by_message(id, |g| config.channel_id(&g.profile) == channel);
+        }
+    }
+    ctx.db
+        .query_gallery_by_url(target)
+        .map_err(|_| anyhow!("找不到画廊：{}", target))
+}
+
+/// 打开导出文件，- 表示标准输出
+fn export_output(path: &str) -> Result<Box<dyn Write>, Error> {
+    Ok(match path {
+        "-" => Box::new(std::io::stdout()),
+        _ => Box::new(File::create(path)?),
+    })
+}
+
+/// 导出全部画廊
+fn export_galleries(config: &Config, db: &DataBase, path: &str) -> Result<(), Error> {
+    let mut out = export_output(path)?;
+    let galleries = db.query_all_galleries()?;
+    for g in &galleries {
+        let tags = serde_json::from_str::<serde_json::Value>(&g.tags)?;
+        let line = serde_json::json!({
+            "id": g.id,
+            "url": g.get_url(config.host()),
+            "message_url": g.message_url(config),
+            "title": g.title,
+            "tags": tags,
+            "telegraph": g.telegraph,
+            "upload_images": g.upload_images,
+            "publish_date": g.publish_date.to_string(),
+            "score": g.score,
+            "profile": g.profile,
+            "status": g.status,
+        });
+        writeln!(out, "{}", line)?;
+    }
+    info!("已导出 {} 个画廊", galleries.len());
+    Ok(())
+}
+
+/// 导出全部管理操作记录
+fn export_audit_log(db: &DataBase, path: &str) -> Result<(), Error> {
+    let mut out = export_output(path)?;
+    let logs = db.query_audit_log(-1)?;
+    for log in logs.iter().rev() {
+        let line = serde_json::json!({
+            "id": log.id,
+            "actor_id": log.actor_id,
+            "command": log.command,
+            "target": log.target,
+            "args": log.args,
+            "result": log.result,
+            "created_at": log.created_at.to_string(),
+        });
+        writeln!(out, "{}", line)?;
+    }
+    info!("已导出 {} 条操作记录", logs.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{insert_test_gallery, mock_exhentai, test_context};
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_find_gallery() {
+        let (server, ctx) = test_context();
+        mock_exhentai(&server);
+        let g = insert_test_gallery(&ctx, &server, 100).await;
+        for target in [
+            "100",
+            "https://t.me/exloli_test/100",
+            "https://exhentai.org/g/1000/abcdef1234/",
+        ] {
+            assert_eq!(find_gallery(&ctx, target).unwrap().id, g.id, "{}", target);
+        }
+        assert!(find_gallery(&ctx, "101").is_err());
     }
 }
@@ -0,0 +1,367 @@
+//! 测试用的 HTTP 服务器，按路径返回预设的响应，并记录收到的所有请求
+use crate::config::{Config, PostMode};
+use crate::context::AppContext;
+use crate::database::{DataBase, Gallery};
+use crate::exhentai::ExHentai;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// 收到的请求
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    /// 包含查询参数的路径
+    pub path: String,
+    pub body: String,
+}
+
+#[derive(Debug, Clone)]
+struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+#[derive(Default)]
+struct State {
+    /// 后设置的路由优先
+    routes: Vec<(String, Response)>,
+    requests: Vec<Request>,
+}
+
+impl State {
+    fn find(&self, path: &str) -> Option<&Response> {
+        self.routes
+            .iter()
+            .rev()
+            .find(|(pattern, _)| match_path(pattern, path))
+            .map(|(_, response)| response)
+    }
+}
+
+/// 路径不区分大小写，以 `*` 开头时匹配后缀，以 `*` 结尾时匹配前缀
+fn match_path(pattern: &str, path: &str) -> bool {
+    let (pattern, path) = (pattern.to_lowercase(), path.to_lowercase());
+    if let Some(suffix) = pattern.strip_prefix('*') {
+        path.ends_with(suffix)
+    } else if let Some(prefix) = pattern.strip_suffix('*') {
+        path.starts_with(prefix)
+    } else {
+        pattern == path
+    }
+}
+
+pub struct MockServer {
+    addr: String,
+    state: Arc<Mutex<State>>,
+}
+
+impl MockServer {
+    /// 在随机端口上启动服务器，服务器线程随测试进程一同退出
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("无法监听端口");
+        let addr = listener.local_addr().unwrap().to_string();
+        let state = Arc::new(Mutex::new(State::default()));
+        let state_ref = state.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let state = state_ref.clone();
+                thread::spawn(move || handle(stream, &state));
+            }
+        });
+        Self { addr, state }
+    }
+
+    /// 服务器地址，如 `http://127.0.0.1:12345`
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// 设置路径对应的响应，带查询参数的路径优先匹配，其次匹配去掉查询参数后的路径
+    ///
+    /// 路径不区分大小写，可以用 `*` 匹配任意前缀或后缀
+    pub fn route(&self, path: &str, status: u16, body: impl Into<Vec<u8>>) {
+        self.route_with_headers(path, status, &[], body)
+    }
+
+    pub fn route_with_headers(
+        &self,
+        path: &str,
+        status: u16,
+        headers: &[(&str, &str)],
+        body: impl Into<Vec<u8>>,
+    ) {
+        let response = Response {
+            status,
+            headers: headers
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            body: body.into(),
+        };
+        self.state
+            .lock()
+            .unwrap()
+            .routes
+            .push((path.to_owned(), response));
+    }
+
+    /// 到目前为止收到的请求
+    pub fn requests(&self) -> Vec<Request> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// 对指定接口的调用，返回解析为 JSON 的请求体，表单会转换为 JSON 对象
+    pub fn calls(&self, method: &str) -> Vec<serde_json::Value> {
+        let suffix = format!("/{}", method.to_lowercase());
+        self.requests()
+            .into_iter()
+            .filter(|r| {
+                r.path
+                    .split('?')
+                    .next()
+                    .unwrap()
+                    .to_lowercase()
+                    .ends_with(&suffix)
+            })
+            .map(|r| {
+                serde_json::from_str(&r.body).unwrap_or_else(|_| {
+                    url::form_urlencoded::parse(r.body.as_bytes())
+                        .map(|(k, v)| (k.into_owned(), v.into_owned().into()))
+                        .collect::<serde_json::Map<_, _>>()
+                        .into()
+                })
+            })
+            .collect()
+    }
+}
+
+fn handle(stream: TcpStream, state: &Mutex<State>) {
+    let mut reader = BufReader::new(&stream);
+    let mut line = String::new();
+    if reader.read_line(&mut line).is_err() {
+        return;
+    }
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_owned();
+    let path = parts.next().unwrap_or_default().to_owned();
+
+    let mut length = 0;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).unwrap_or(0) == 0 || header.trim().is_empty() {
+            break;
+        }
+        if let Some((k, v)) = header.split_once(':') {
+            if k.eq_ignore_ascii_case("content-length") {
+                length = v.trim().parse().unwrap_or(0);
+            }
+        }
+    }
+    let mut body = vec![0; length];
+    if reader.read_exact(&mut body).is_err() {
+        return;
+    }
+
+    let response = {
+        let mut state = state.lock().unwrap();
+        let without_query = path.split('?').next().unwrap_or_default();
+        let response = state
+            .find(&path)
+            .or_else(|| state.find(without_query))
+            .cloned();
+        state.requests.push(Request {
+            method,
+            path,
+            body: String::from_utf8_lossy(&body).into_owned(),
+        });
+        response
+    };
+    let response = response.unwrap_or(Response {
+        status: 404,
+        headers: vec![],
+        body: b"Not Found".to_vec(),
+    });
+
+    let mut head = format!(
+        "HTTP/1.1 {} Mock\r\nContent-Length: {}\r\nConnection: close\r\n",
+        response.status,
+        response.body.len()
+    );
+    for (k, v) in &response.headers {
+        head.push_str(&format!("{}: {}\r\n", k, v));
+    }
+    head.push_str("\r\n");
+    let mut stream = &stream;
+    let _ = stream
+        .write_all(head.as_bytes())
+        .and_then(|_| stream.write_all(&response.body));
+}
+
+/// 读取 tests/fixtures 下的测试数据，并将其中的 `{{base}}` 替换为服务器地址
+pub fn fixture(name: &str, base: &str) -> String {
+    let path = format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name);
+    std::fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("无法读取 {}：{}", path, e))
+        .replace("{{base}}", base)
+}
+
+/// 模拟 Telegram Bot API 和 telegraph API 的服务器，返回固定的结果
+pub fn fake_telegram() -> MockServer {
+    let server = MockServer::start();
+    let message = r#"{"ok":true,"result":{"message_id":100,"date":0,"chat":{"id":-1002,"type":"supergroup","title":"test"},"text":"ok"}}"#;
+    for method in [
+        "sendMessage",
+        "sendPhoto",
+        "editMessageText",
+        "editMessageCaption",
+    ] {
+        server.route(&format!("*/{}", method), 200, message);
+    }
+    for method in [
+        "deleteMessage",
+        "pinChatMessage",
+        "unpinChatMessage",
+        "setMyCommands",
+    ] {
+        server.route(
+            &format!("*/{}", method),
+            200,
+            r#"{"ok":true,"result":true}"#,
+        );
+    }
+    server.route("*/getChatAdministrators", 200, r#"{"ok":true,"result":[]}"#);
+
+    let page = r#"{"ok":true,"result":{"path":"Test-01-01","url":"https://telegra.ph/Test-01-01","title":"Test","content":[{"tag":"p","children":["test"]}]}}"#;
+    for method in ["/createPage", "/editPage/*", "/getPage/*"] {
+        server.route(method, 200, page);
+    }
+    server.route("/upload", 200, r#"[{"src":"/file/test.jpg"}]"#);
+    server
+}
+
+/// 在服务器上提供 tests/fixtures/exhentai 中的 E 站页面
+pub fn mock_exhentai(server: &MockServer) {
+    let base = server.url();
+    let pages = [
+        ("/", "search.html"),
+        ("/index.php", "login.html"),
+        ("/api.php", "gdata.json"),
+        ("/g/1000/abcdef1234/", "gallery_p0.html"),
+        ("/g/1000/abcdef1234/?p=1", "gallery_p1.html"),
+        ("/g/2000/0123456789/", "not_available.html"),
+        ("/s/aaaaaaaaaa/1000-1", "image.html"),
+    ];
+    for (path, name) in pages {
+        server.route(path, 200, fixture(&format!("exhentai/{}", name), &base));
+    }
+    // 所有图片页都指向同一张图片
+    server.route("/s/*", 200, fixture("exhentai/image.html", &base));
+    server.route("/h/*", 200, test_image());
+    server.route("/uconfig.php", 200, "");
+    server.route("/mytags", 200, "");
+}
+
+/// 一张可以直接上传的小图片
+fn test_image() -> Vec<u8> {
+    let img = image::DynamicImage::ImageRgb8(image::RgbImage::new(100, 140));
+    let mut buf = std::io::Cursor::new(vec![]);
+    img.write_to(&mut buf, image::ImageOutputFormat::Png)
+        .unwrap();
+    buf.into_inner()
+}
+
+/// 生成使用测试服务器的配置文件，E 站、Telegram 和 telegraph 的地址均指向测试服务器
+pub fn test_config(server: &MockServer, database_url: &str) -> Config {
+    let dir = tempfile::tempdir().unwrap().into_path();
+    let config = format!(
+        r#"
+log_level = "debug"
+threads_num = 1
+interval = 3600
+database_url = "{db}"
+translation = "{trans}"
+
+[exhentai]
+username = "tester"
+password = "secret"
+search_url = "{base}/"
+search_params = []
+max_pages = 1
+max_img_cnt = 10
+
+[telegraph]
+access_token = "TOKEN"
+author_name = "exloli"
+author_url = "https://t.me/exloli_test"
+api_url = "{base}"
+upload_url = "{base}/upload"
+
+[telegram]
+channel_id = "@exloli_test"
+bot_id = "test_bot"
+token = "TOKEN"
+api_url = "{base}"
+group_id = -1002
+owners = [1]
+"#,
+        db = database_url,
+        trans = concat!(env!("CARGO_MANIFEST_DIR"), "/db.text.json"),
+        base = server.url()
+    );
+    let config_file = dir.join("config.toml");
+    std::fs::write(&config_file, config).unwrap();
+    Config::new(&config_file).unwrap()
+}
+
+fn test_exhentai(config: &Config) -> ExHentai {
+    ExHentai::with_client(reqwest::Client::new(), config.exhentai.search_url.clone())
+}
+
+/// 创建使用测试服务器和临时数据库的上下文，E 站客户端不会登录
+pub fn test_context() -> (MockServer, Arc<AppContext>) {
+    test_context_with(|_| ())
+}
+
+/// 同 `test_context`，可以修改默认的测试配置
+pub fn test_context_with(f: impl FnOnce(&mut Config)) -> (MockServer, Arc<AppContext>) {
+    let server = fake_telegram();
+    let dir = tempfile::tempdir().unwrap().into_path();
+    let database_url = dir.join("exloli.db").display().to_string();
+    let mut config = test_config(&server, &database_url);
+    f(&mut config);
+    let exhentai = test_exhentai(&config);
+    let ctx = AppContext::with_exhentai(config, exhentai).unwrap();
+    (server, Arc::new(ctx))
+}
+
+/// 将测试服务器上的画廊 /g/1000/abcdef1234/ 作为频道消息 message_id 写入数据库，需要先调用 `mock_exhentai`
+pub async fn insert_test_gallery(
+    ctx: &AppContext,
+    server: &MockServer,
+    message_id: i32,
+) -> Gallery {
+    let url = format!("{}/g/1000/abcdef1234/", server.url());
+    let gallery = ctx
+        .exhentai
+        .get_gallery_by_url(url, ctx.config.default_profile())
+        .await
+        .unwrap();
+    let info = gallery.into_full_info().await.unwrap();
+    let id = ctx
+        .db
+        .insert_gallery(message_id, &info, String::new(), PostMode::Text, None)
+        .unwrap();
+    ctx.db.update_poll_id(id, &message_id.to_string()).unwrap();
+    ctx.db.query_gallery(id).unwrap()
+}
+
+/// 创建与 ctx 使用同一个数据库的只读上下文，与试运行时相同
+pub fn read_only_context(server: &MockServer, ctx: &AppContext) -> Arc<AppContext> {
+    let config = test_config(server, &ctx.config.database_url);
+    let exhentai = test_exhentai(&config);
+    let db = DataBase::open_read_only(&config.database_url, ctx.trans.clone()).unwrap();
+    Arc::new(AppContext::with_database(config, exhentai, db).unwrap())
+}
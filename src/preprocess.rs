@@ -0,0 +1,183 @@
+use crate::exhentai::ImageError;
+use anyhow::Result;
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::{ColorType, DynamicImage, GenericImageView};
+use tempfile::NamedTempFile;
+
+use std::io::Write;
+use std::path::Path;
+
+/// telegraph 允许上传的最大体积
+pub const MAX_SIZE: u64 = 5 * 1024 * 1024;
+/// 切分长图时每一块的最大高宽比，需要小于 telegraph 的限制 20
+const TILE_RATIO: u32 = 10;
+/// 依次尝试的 JPEG 质量
+const QUALITIES: [u8; 4] = [90, 80, 70, 60];
+/// 缩小图片时允许的最小宽度
+const MIN_WIDTH: u32 = 200;
+
+/// 检查图片是否满足 telegraph 的要求，必要时重新压缩或切分
+///
+/// 不需要处理时返回 None，否则返回处理后的一张或多张图片
+pub fn preprocess(path: &Path) -> Result<Option<Vec<NamedTempFile>>> {
+    let size = path.metadata()?.len();
+    let (width, height) = image::io::Reader::open(path)?
+        .with_guessed_format()?
+        .into_dimensions()?;
+    if height * 10 <= width {
+        return Err(ImageError::BadAspectRatio(width, height).into());
+    }
+    let too_tall = width * 20 <= height;
+    if size <= MAX_SIZE && !too_tall {
+        return Ok(None);
+    }
+
+    let img = image::io::Reader::open(path)?
+        .with_guessed_format()?
+        .decode()?;
+    let tiles = if too_tall {
+        let tiles = split_tiles(&img);
+        info!("切分长图：{}x{} -> {} 张", width, height, tiles.len());
+        tiles
+    } else {
+        vec![img]
+    };
+
+    let mut ret = vec![];
+    for tile in tiles {
+        let data = compress(tile, MAX_SIZE)?;
+        let mut file = tempfile::Builder::new().suffix(".jpg").tempfile()?;
+        file.write_all(&data)?;
+        ret.push(file);
+    }
+    Ok(Some(ret))
+}
+
+/// 将长图按固定高宽比均匀切分
+fn split_tiles(img: &DynamicImage) -> Vec<DynamicImage> {
+    let (width, height) = img.dimensions();
+    let max_height = width * TILE_RATIO;
+    let count = (height + max_height - 1) / max_height;
+    let tile_height = (height + count - 1) / count;
+    (0..height)
+        .step_by(tile_height as usize)
+        .map(|y| img.crop_imm(0, y, width, tile_height.min(height - y)))
+        .collect()
+}
+
+/// 将图片编码为 JPEG，依次降低质量和分辨率直至体积小于 max_size
+fn compress(mut img: DynamicImage, max_size: u64) -> Result<Vec<u8>> {
+    loop {
+        let (width, height) = img.dimensions();
+        for quality in QUALITIES {
+            let data = encode_jpeg(&img, quality)?;
+            let size = data.len() as u64;
+            if size <= max_size {
+                debug!(
+                    "压缩图片：{}x{} 质量 {} -> {} 字节",
+                    width, height, quality, size
+                );
+                return Ok(data);
+            }
+        }
+        if width * 3 / 4 < MIN_WIDTH {
+            let size = encode_jpeg(&img, QUALITIES[QUALITIES.len() - 1])?.len();
+            return Err(ImageError::TooLarge(size as u64).into());
+        }
+        info!("图片仍然过大，缩小分辨率：{}x{}", width, height);
+        img = img.resize(width * 3 / 4, height * 3 / 4, FilterType::Triangle);
+    }
+}
+
+fn encode_jpeg(img: &DynamicImage, quality: u8) -> Result<Vec<u8>> {
+    let img = img.to_rgb8();
+    let mut buf = vec![];
+    JpegEncoder::new_with_quality(&mut buf, quality).encode(
+        &img,
+        img.width(),
+        img.height(),
+        ColorType::Rgb8,
+    )?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageFormat, RgbImage};
+
+    /// 生成随机噪点图片，用于制造难以压缩的大图
+    fn noise(width: u32, height: u32) -> RgbImage {
+        let mut seed = 0x2545f491u32;
+        RgbImage::from_fn(width, height, |_, _| {
+            let mut px = [0; 3];
+            for c in &mut px {
+                seed ^= seed << 13;
+                seed ^= seed >> 17;
+                seed ^= seed << 5;
+                *c = seed as u8;
+            }
+            image::Rgb(px)
+        })
+    }
+
+    fn fixture(img: RgbImage) -> NamedTempFile {
+        let file = tempfile::Builder::new().suffix(".png").tempfile().unwrap();
+        img.save_with_format(file.path(), ImageFormat::Png).unwrap();
+        file
+    }
+
+    fn dimensions(file: &NamedTempFile) -> (u32, u32) {
+        image::image_dimensions(file.path()).unwrap()
+    }
+
+    #[test]
+    fn test_normal_image() {
+        let file = fixture(noise(100, 150));
+        assert!(preprocess(file.path()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_large_png() {
+        let file = fixture(noise(1500, 1500));
+        assert!(file.path().metadata().unwrap().len() > MAX_SIZE);
+        let ret = preprocess(file.path()).unwrap().unwrap();
+        assert_eq!(ret.len(), 1);
+        assert!(ret[0].path().metadata().unwrap().len() <= MAX_SIZE);
+    }
+
+    #[test]
+    fn test_downscale() {
+        let img = DynamicImage::ImageRgb8(noise(600, 600));
+        // 以最低质量编码后体积仍然超过限制，只能缩小分辨率
+        let max_size = encode_jpeg(&img, QUALITIES[QUALITIES.len() - 1])
+            .unwrap()
+            .len() as u64
+            / 2;
+        let data = compress(img, max_size).unwrap();
+        assert!(data.len() as u64 <= max_size);
+        let img = image::load_from_memory(&data).unwrap();
+        assert!(img.width() < 600);
+    }
+
+    #[test]
+    fn test_tall_strip() {
+        let file = fixture(RgbImage::new(100, 2500));
+        let ret = preprocess(file.path()).unwrap().unwrap();
+        assert_eq!(ret.len(), 3);
+        let heights = ret.iter().map(|f| dimensions(f).1).collect::<Vec<_>>();
+        assert_eq!(heights.iter().sum::<u32>(), 2500);
+        for file in &ret {
+            let (width, height) = dimensions(file);
+            assert_eq!(width, 100);
+            assert!(width * 20 > height && height * 10 > width);
+        }
+    }
+
+    #[test]
+    fn test_wide_image() {
+        let file = fixture(RgbImage::new(1000, 50));
+        assert!(preprocess(file.path()).is_err());
+    }
+}
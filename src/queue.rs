@@ -0,0 +1,137 @@
+use crate::context::AppContext;
+use crate::database::UploadJob;
+use crate::exloli::ExLoli;
+use anyhow::{Error, Result};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// 最大尝试次数，超过后任务标记为失败
+pub const MAX_ATTEMPTS: i32 = 5;
+
+/// 任务类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobKind {
+    /// 自动扫描到的画廊，target 为画廊地址
+    Scan,
+    /// 手动上传的画廊，target 为画廊地址
+    Upload,
+    /// 上传完整版，target 为画廊 id
+    Full,
+    /// 重新发布，target 为画廊 id
+    ReUpload,
+    /// 将已发布画廊的新版本作为新消息发布，target 为新版本地址
+    Republish,
+}
+
+/// 任务状态
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobState {
+    Pending,
+    Running,
+    Failed,
+    Done,
+}
+
+impl JobKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Scan => "scan",
+            Self::Upload => "upload",
+            Self::Full => "full",
+            Self::ReUpload => "reupload",
+            Self::Republish => "republish",
+        }
+    }
+}
+
+impl FromStr for JobKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "scan" => Ok(Self::Scan),
+            "upload" => Ok(Self::Upload),
+            "full" => Ok(Self::Full),
+            "reupload" => Ok(Self::ReUpload),
+            "republish" => Ok(Self::Republish),
+            _ => Err(anyhow!("未知的任务类型：{}", s)),
+        }
+    }
+}
+
+impl fmt::Display for JobKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl JobState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Pending => "pending",
+            Self::Running => "running",
+            Self::Failed => "failed",
+            Self::Done => "done",
+        }
+    }
+}
+
+impl fmt::Display for JobState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// 失败后的重试间隔，随尝试次数增加
+pub fn retry_delay(attempts: i32) -> chrono::Duration {
+    chrono::Duration::minutes(10 * attempts as i64)
+}
+
+/// 上传队列的工作线程，不断从数据库中取出到期的任务执行
+pub async fn run_worker(id: usize, ctx: Arc<AppContext>, exloli: Arc<ExLoli>) {
+    info!("上传队列 {} 启动", id);
+    loop {
+        let job = match ctx.db.take_job() {
+            Ok(Some(job)) => job,
+            Ok(None) => {
+                sleep(Duration::from_secs(10)).await;
+                continue;
+            }
+            Err(e) => {
+                error!("获取上传任务失败：{}", e);
+                sleep(Duration::from_secs(60)).await;
+                continue;
+            }
+        };
+        execute(id, &ctx, &exloli, &job).await;
+    }
+}
+
+/// 执行队列中所有到期的任务，队列为空时返回
+pub async fn run_pending(ctx: &AppContext, exloli: &ExLoli) -> Result<()> {
+    while let Some(job) = ctx.db.take_job()? {
+        execute(0, ctx, exloli, &job).await;
+    }
+    Ok(())
+}
+
+/// 执行单个任务并记录结果
+async fn execute(id: usize, ctx: &AppContext, exloli: &ExLoli, job: &UploadJob) {
+    info!("[{}] 执行任务 #{}：{} {}", id, job.id, job.kind, job.target);
+    let result = match exloli.run_job(job).await {
+        Ok(_) => {
+            info!("[{}] 任务 #{} 完成", id, job.id);
+            ctx.db.finish_job(job.id)
+        }
+        Err(e) => {
+            error!("[{}] 任务 #{} 失败：{}", id, job.id, e);
+            ctx.db.fail_job(job, &e.to_string())
+        }
+    };
+    if let Err(e) = result {
+        error!("更新任务 #{} 状态失败：{}", job.id, e);
+    }
+}
@@ -1,5 +1,18 @@
 table! {
-    gallery (message_id) {
+    audit_log (id) {
+        id -> Integer,
+        actor_id -> BigInt,
+        command -> Text,
+        target -> Nullable<Integer>,
+        args -> Text,
+        result -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    gallery (id) {
+        id -> Integer,
         message_id -> Integer,
         gallery_id -> Integer,
         token -> Text,
@@ -11,13 +24,49 @@ table! {
         poll_id -> Text,
         score -> Float,
         votes -> Text,
+        profile -> Text,
+        post_mode -> Text,
+        next_check_at -> Nullable<Timestamp>,
+        status -> Text,
+        deleted_by -> Nullable<BigInt>,
+        deleted_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    gallery_image (gallery_id, page) {
+        gallery_id -> Integer,
+        page -> Integer,
+        url -> Nullable<Text>,
+        error -> Nullable<Text>,
+    }
+}
+
+table! {
+    gallery_tag (gallery_ref, namespace, tag) {
+        gallery_ref -> Integer,
+        namespace -> Text,
+        tag -> Text,
+    }
+}
+
+table! {
+    gallery_version (gallery_id) {
+        gallery_id -> Integer,
+        token -> Text,
+        parent_id -> Integer,
+        parent_token -> Text,
+        parent_ref -> Integer,
+        action -> Text,
+        created_at -> Timestamp,
     }
 }
 
 table! {
-    image_hash (hash) {
+    image_hash (hash, host) {
         hash -> Text,
         url -> Text,
+        host -> Text,
     }
 }
 
@@ -28,6 +77,49 @@ table! {
     }
 }
 
+table! {
+    rejected (gallery_id) {
+        gallery_id -> Integer,
+        token -> Text,
+        title -> Text,
+        reason -> Text,
+        rejected_at -> Timestamp,
+        expires_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    telegraph_page (gallery_ref, part) {
+        gallery_ref -> Integer,
+        part -> Integer,
+        path -> Text,
+    }
+}
+
+table! {
+    upload_job (id) {
+        id -> Integer,
+        kind -> Text,
+        target -> Text,
+        profile -> Text,
+        state -> Text,
+        attempts -> Integer,
+        last_error -> Nullable<Text>,
+        next_retry_at -> Timestamp,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
+table! {
+    user_role (user_id) {
+        user_id -> BigInt,
+        role -> Text,
+        granted_by -> BigInt,
+        granted_at -> Timestamp,
+    }
+}
+
 table! {
     user_vote (user_id, poll_id) {
         user_id -> BigInt,
@@ -37,4 +129,19 @@ table! {
     }
 }
 
-allow_tables_to_appear_in_same_query!(gallery, image_hash, images, user_vote,);
+joinable!(gallery_tag -> gallery (gallery_ref));
+
+allow_tables_to_appear_in_same_query!(
+    audit_log,
+    gallery,
+    gallery_image,
+    gallery_tag,
+    gallery_version,
+    image_hash,
+    images,
+    rejected,
+    telegraph_page,
+    upload_job,
+    user_role,
+    user_vote,
+);
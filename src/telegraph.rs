@@ -0,0 +1,252 @@
+//! telegraph API 客户端，接口地址可以在配置文件中修改
+use anyhow::{Context, Result};
+use reqwest::multipart::{Form, Part};
+use reqwest::Client;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+
+use std::fs;
+use std::path::Path;
+
+/// 默认的 API 地址
+pub const DEFAULT_API_URL: &str = "https://api.telegra.ph";
+/// 默认的图片上传地址
+pub const DEFAULT_UPLOAD_URL: &str = "https://telegra.ph/upload";
+
+/// telegraph 文章
+#[derive(Debug, Clone, Deserialize)]
+pub struct Page {
+    pub path: String,
+    pub url: String,
+    pub title: String,
+    /// 文章内容，只有请求时指定了 return_content 才会返回
+    #[serde(default)]
+    pub content: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiResponse<T> {
+    ok: bool,
+    result: Option<T>,
+    error: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum UploadResponse {
+    Ok(Vec<UploadResult>),
+    Err { error: String },
+}
+
+#[derive(Debug, Deserialize)]
+struct UploadResult {
+    src: String,
+}
+
+#[derive(Debug)]
+pub struct Telegraph {
+    client: Client,
+    api_url: String,
+    access_token: String,
+    author_name: String,
+    author_url: String,
+}
+
+impl Telegraph {
+    pub fn new(
+        client: Client,
+        api_url: &str,
+        access_token: &str,
+        author_name: &str,
+        author_url: &str,
+    ) -> Self {
+        Self {
+            client,
+            api_url: api_url.trim_end_matches('/').to_owned(),
+            access_token: access_token.to_owned(),
+            author_name: author_name.to_owned(),
+            author_url: author_url.to_owned(),
+        }
+    }
+
+    async fn call<T: DeserializeOwned>(&self, method: &str, params: &[(&str, &str)]) -> Result<T> {
+        let response = self
+            .client
+            .post(format!("{}/{}", self.api_url, method))
+            .form(params)
+            .send()
+            .await?
+            .json::<ApiResponse<T>>()
+            .await?;
+        match response {
+            ApiResponse {
+                ok: true,
+                result: Some(v),
+                ..
+            } => Ok(v),
+            ApiResponse { error, .. } => Err(anyhow!(
+                "telegraph 接口 {} 出错：{}",
+                method,
+                error.unwrap_or_default()
+            )),
+        }
+    }
+
+    /// 创建文章，content 为 JSON 格式的节点列表
+    pub async fn create_page(
+        &self,
+        title: &str,
+        content: &str,
+        return_content: bool,
+    ) -> Result<Page> {
+        self.call(
+            "createPage",
+            &[
+                ("access_token", self.access_token.as_str()),
+                ("title", title),
+                ("author_name", self.author_name.as_str()),
+                ("author_url", self.author_url.as_str()),
+                ("content", content),
+                (
+                    "return_content",
+                    if return_content { "true" } else { "false" },
+                ),
+            ],
+        )
+        .await
+    }
+
+    /// 修改文章，content 为 JSON 格式的节点列表
+    pub async fn edit_page(
+        &self,
+        path: &str,
+        title: &str,
+        content: &str,
+        return_content: bool,
+    ) -> Result<Page> {
+        self.call(
+            &format!("editPage/{}", path),
+            &[
+                ("access_token", self.access_token.as_str()),
+                ("title", title),
+                ("author_name", self.author_name.as_str()),
+                ("author_url", self.author_url.as_str()),
+                ("content", content),
+                (
+                    "return_content",
+                    if return_content { "true" } else { "false" },
+                ),
+            ],
+        )
+        .await
+    }
+
+    pub async fn get_page(&self, path: &str, return_content: bool) -> Result<Page> {
+        self.call(
+            &format!("getPage/{}", path),
+            &[(
+                "return_content",
+                if return_content { "true" } else { "false" },
+            )],
+        )
+        .await
+    }
+}
+
+/// 上传图片，按顺序返回每张图片的地址，地址为 telegraph 的相对路径
+pub async fn upload(client: &Client, upload_url: &str, files: &[&Path]) -> Result<Vec<String>> {
+    let mut form = Form::new();
+    for (i, path) in files.iter().enumerate() {
+        let name = path
+            .file_name()
+            .and_then(|s| s.to_str())
+            .unwrap_or("image.jpg")
+            .to_owned();
+        let mime = match path.extension().and_then(|s| s.to_str()) {
+            Some("png") => "image/png",
+            Some("gif") => "image/gif",
+            _ => "image/jpeg",
+        };
+        let part = Part::bytes(fs::read(path)?)
+            .file_name(name)
+            .mime_str(mime)?;
+        form = form.part(format!("file{}", i), part);
+    }
+    let response = client
+        .post(upload_url)
+        .multipart(form)
+        .send()
+        .await?
+        .json::<UploadResponse>()
+        .await
+        .context("无法解析上传结果")?;
+    match response {
+        UploadResponse::Ok(v) => Ok(v.into_iter().map(|r| r.src).collect()),
+        UploadResponse::Err { error } => bail!("{}", error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::fake_telegram;
+    use std::io::Write;
+
+    #[tokio::test]
+    async fn test_telegraph() {
+        let server = fake_telegram();
+        let telegraph = Telegraph::new(
+            Client::new(),
+            &server.url(),
+            "TOKEN",
+            "exloli",
+            "https://t.me/exloli",
+        );
+        let content = r#"[{"tag":"p","children":["test"]}]"#;
+        let page = telegraph.create_page("标题", content, false).await.unwrap();
+        assert_eq!(page.path, "Test-01-01");
+        telegraph
+            .edit_page(&page.path, "新标题", content, false)
+            .await
+            .unwrap();
+        let page = telegraph.get_page(&page.path, true).await.unwrap();
+        assert!(page.content.is_some());
+
+        let created = server.calls("createPage");
+        assert_eq!(created.len(), 1);
+        assert_eq!(created[0]["title"], "标题");
+        assert_eq!(created[0]["access_token"], "TOKEN");
+        assert_eq!(created[0]["content"], content);
+        let edited = server.calls("editPage/Test-01-01");
+        assert_eq!(edited[0]["title"], "新标题");
+        assert_eq!(
+            server.calls("getPage/Test-01-01")[0]["return_content"],
+            "true"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_upload() {
+        let server = fake_telegram();
+        let upload_url = format!("{}/upload", server.url());
+        let mut file = tempfile::Builder::new().suffix(".png").tempfile().unwrap();
+        file.write_all(b"not really a png").unwrap();
+
+        let urls = upload(&Client::new(), &upload_url, &[file.path()])
+            .await
+            .unwrap();
+        assert_eq!(urls, vec!["/file/test.jpg"]);
+        let request = server.requests().pop().unwrap();
+        assert_eq!(request.method, "POST");
+        assert!(request
+            .body
+            .to_lowercase()
+            .contains("content-type: image/png"));
+
+        server.route("/upload", 200, r#"{"error":"File type invalid"}"#);
+        let err = upload(&Client::new(), &upload_url, &[file.path()])
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "File type invalid");
+    }
+}
@@ -0,0 +1,291 @@
+//! 一个简单的模板引擎，语法与 Handlebars 类似
+//!
+//! - `{{name}}`：插入变量，会进行 HTML 转义
+//! - `{{{name}}}`：插入变量，不进行转义
+//! - `{{#if name}}...{{else}}...{{/if}}`：变量非空时渲染
+//! - `{{#each name}}...{{/each}}`：遍历列表，循环体中可以访问列表项的字段
+use anyhow::Result;
+use v_htmlescape::escape;
+
+use std::collections::HashMap;
+
+/// 默认的 Telegram 消息模板
+pub const DEFAULT_MESSAGE: &str = r#"{{{tags}}}
+<code>  预览</code>: <a href="{{{article_url}}}">{{title}}</a>
+<code>原始地址</code>: {{{url}}} "#;
+
+/// 默认的 telegraph 文章模板
+pub const DEFAULT_ARTICLE: &str = concat!(
+    "{{{images}}}",
+    "{{#if is_last}}{{#if show_progress}}",
+    "<p>已上传 {{uploaded}}/{{total}}",
+    "{{#if last_uploaded}}，上次上传到 {{last_uploaded}}{{/if}}",
+    "{{#if partial}}，完整版请前往 E 站观看{{/if}}",
+    "</p>",
+    "{{/if}}{{/if}}"
+);
+
+pub type Context = HashMap<String, Value>;
+
+/// 模板变量
+#[derive(Debug, Clone)]
+pub enum Value {
+    Text(String),
+    Bool(bool),
+    List(Vec<Context>),
+}
+
+impl Value {
+    fn is_truthy(&self) -> bool {
+        match self {
+            Self::Text(s) => !s.is_empty(),
+            Self::Bool(b) => *b,
+            Self::List(v) => !v.is_empty(),
+        }
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Self::Text(s)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Self::Text(s.to_owned())
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Self::Bool(b)
+    }
+}
+
+impl From<usize> for Value {
+    fn from(n: usize) -> Self {
+        Self::Text(n.to_string())
+    }
+}
+
+impl From<Vec<Context>> for Value {
+    fn from(v: Vec<Context>) -> Self {
+        Self::List(v)
+    }
+}
+
+#[derive(Debug)]
+enum Node {
+    Text(String),
+    Var {
+        name: String,
+        raw: bool,
+    },
+    If {
+        name: String,
+        then: Vec<Node>,
+        otherwise: Vec<Node>,
+    },
+    Each {
+        name: String,
+        body: Vec<Node>,
+    },
+}
+
+/// 解析过程中尚未闭合的块
+struct Block {
+    kind: &'static str,
+    name: String,
+    nodes: Vec<Node>,
+    otherwise: Option<Vec<Node>>,
+}
+
+impl Block {
+    fn new(kind: &'static str, name: &str) -> Self {
+        Self {
+            kind,
+            name: name.trim().to_owned(),
+            nodes: vec![],
+            otherwise: None,
+        }
+    }
+
+    fn push(&mut self, node: Node) {
+        match &mut self.otherwise {
+            Some(nodes) => nodes.push(node),
+            None => self.nodes.push(node),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Template {
+    nodes: Vec<Node>,
+}
+
+impl Template {
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut stack = vec![Block::new("", "")];
+        let mut rest = s;
+        while let Some(start) = rest.find("{{") {
+            if start > 0 {
+                stack
+                    .last_mut()
+                    .unwrap()
+                    .push(Node::Text(rest[..start].to_owned()));
+            }
+            let raw = rest[start..].starts_with("{{{");
+            let (open, close) = if raw { ("{{{", "}}}") } else { ("{{", "}}") };
+            let tag_start = start + open.len();
+            let tag_end = rest[tag_start..]
+                .find(close)
+                .ok_or_else(|| anyhow!("标签没有闭合：{}", &rest[start..]))?
+                + tag_start;
+            let tag = rest[tag_start..tag_end].trim();
+            rest = &rest[tag_end + close.len()..];
+
+            if let Some(name) = tag.strip_prefix("#if ") {
+                stack.push(Block::new("if", name));
+            } else if let Some(name) = tag.strip_prefix("#each ") {
+                stack.push(Block::new("each", name));
+            } else if tag == "else" {
+                let block = stack.last_mut().unwrap();
+                if block.kind != "if" || block.otherwise.is_some() {
+                    bail!("else 只能出现在 if 块中");
+                }
+                block.otherwise = Some(vec![]);
+            } else if let Some(kind) = tag.strip_prefix('/') {
+                let block = stack.pop().unwrap();
+                if stack.is_empty() || block.kind != kind.trim() {
+                    bail!("多余的结束标签：{}", tag);
+                }
+                let node = match block.kind {
+                    "if" => Node::If {
+                        name: block.name,
+                        then: block.nodes,
+                        otherwise: block.otherwise.unwrap_or_default(),
+                    },
+                    _ => Node::Each {
+                        name: block.name,
+                        body: block.nodes,
+                    },
+                };
+                stack.last_mut().unwrap().push(node);
+            } else {
+                stack.last_mut().unwrap().push(Node::Var {
+                    name: tag.to_owned(),
+                    raw,
+                });
+            }
+        }
+        if !rest.is_empty() {
+            stack.last_mut().unwrap().push(Node::Text(rest.to_owned()));
+        }
+        if stack.len() != 1 {
+            let block = stack.pop().unwrap();
+            bail!("{} 块没有闭合：{}", block.kind, block.name);
+        }
+        Ok(Self {
+            nodes: stack.pop().unwrap().nodes,
+        })
+    }
+
+    pub fn render(&self, ctx: &Context) -> String {
+        let mut out = String::new();
+        render_nodes(&self.nodes, &mut vec![ctx], &mut out);
+        out
+    }
+}
+
+fn render_nodes<'a>(nodes: &[Node], scopes: &mut Vec<&'a Context>, out: &mut String) {
+    // 从内到外查找变量
+    let lookup = |scopes: &Vec<&'a Context>, name: &str| -> Option<&'a Value> {
+        scopes.iter().rev().find_map(|ctx| ctx.get(name))
+    };
+    for node in nodes {
+        match node {
+            Node::Text(s) => out.push_str(s),
+            Node::Var { name, raw } => match lookup(scopes, name) {
+                Some(Value::Text(s)) if *raw => out.push_str(s),
+                Some(Value::Text(s)) => out.push_str(&escape(s).to_string()),
+                Some(Value::Bool(b)) => out.push_str(&b.to_string()),
+                _ => (),
+            },
+            Node::If {
+                name,
+                then,
+                otherwise,
+            } => {
+                let truthy = matches!(lookup(scopes, name), Some(v) if v.is_truthy());
+                render_nodes(if truthy { then } else { otherwise }, scopes, out);
+            }
+            Node::Each { name, body } => {
+                if let Some(Value::List(items)) = lookup(scopes, name) {
+                    for item in items {
+                        scopes.push(item);
+                        render_nodes(body, scopes, out);
+                        scopes.pop();
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ctx() -> Context {
+        let mut row = Context::new();
+        row.insert("namespace".into(), "女性".into());
+        let mut ctx = Context::new();
+        ctx.insert("title".into(), "<标题>".into());
+        ctx.insert("empty".into(), "".into());
+        ctx.insert("flag".into(), true.into());
+        ctx.insert("rows".into(), vec![row.clone(), row].into());
+        ctx
+    }
+
+    #[test]
+    fn test_render() {
+        let ctx = ctx();
+        let render = |s: &str| Template::parse(s).unwrap().render(&ctx);
+        assert_eq!(render("a{{title}}b"), "a&lt;标题&gt;b");
+        assert_eq!(render("{{{title}}}"), "<标题>");
+        assert_eq!(render("{{ missing }}"), "");
+        assert_eq!(render("{{#if flag}}1{{else}}2{{/if}}"), "1");
+        assert_eq!(render("{{#if empty}}1{{else}}2{{/if}}"), "2");
+        assert_eq!(
+            render("{{#each rows}}[{{namespace}}{{#if flag}}!{{/if}}]{{/each}}"),
+            "[女性!][女性!]"
+        );
+    }
+
+    #[test]
+    fn test_parse_error() {
+        assert!(Template::parse("{{title").is_err());
+        assert!(Template::parse("{{#if a}}").is_err());
+        assert!(Template::parse("{{/if}}").is_err());
+        assert!(Template::parse("{{#each a}}{{/if}}").is_err());
+        assert!(Template::parse("{{#each a}}{{else}}{{/each}}").is_err());
+    }
+
+    #[test]
+    fn test_default_article() {
+        let template = Template::parse(DEFAULT_ARTICLE).unwrap();
+        let mut ctx = Context::new();
+        ctx.insert("images".into(), "<img>".into());
+        ctx.insert("is_last".into(), true.into());
+        ctx.insert("show_progress".into(), true.into());
+        ctx.insert("uploaded".into(), 2usize.into());
+        ctx.insert("total".into(), 5usize.into());
+        ctx.insert("last_uploaded".into(), "".into());
+        ctx.insert("partial".into(), true.into());
+        assert_eq!(
+            template.render(&ctx),
+            "<img><p>已上传 2/5，完整版请前往 E 站观看</p>"
+        );
+    }
+}
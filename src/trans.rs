@@ -1,9 +1,8 @@
-use once_cell::sync::Lazy;
+use anyhow::{Context, Result};
 use serde::Deserialize;
 use std::collections::HashMap;
 use std::fs::read_to_string;
-
-pub static TRANS: Lazy<Database> = Lazy::new(Database::new);
+use std::path::Path;
 
 #[derive(Deserialize)]
 pub struct Database {
@@ -28,9 +27,11 @@ pub struct Info {
 }
 
 impl Database {
-    fn new() -> Self {
-        let text = read_to_string("db.text.json").expect("Cannot open db.text.json");
-        serde_json::from_slice(text.as_bytes()).expect("Cannot parse translation database")
+    /// 从文件中读取翻译数据库
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let text = read_to_string(path).with_context(|| format!("无法打开 {}", path.display()))?;
+        serde_json::from_str(&text).context("翻译数据库解析失败")
     }
 
     pub fn trans<'a>(&'a self, namespace: &'a str, name: &'a str) -> &'a str {
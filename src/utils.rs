@@ -1,34 +1,37 @@
-use crate::trans::TRANS;
-use crate::CONFIG;
+use crate::trans;
 use anyhow::Context;
 use futures::TryFutureExt;
 use once_cell::sync::Lazy;
-use regex::Regex;
+use regex::{Captures, Regex};
 use reqwest::header::*;
 use reqwest::{Client, Response};
 use std::borrow::Cow;
+use std::collections::BTreeSet;
 use std::io::Write;
 use std::time::SystemTime;
+use teloxide::types::Recipient;
 use tempfile::NamedTempFile;
 
-pub static HOST: Lazy<&'static str> = Lazy::new(|| {
-    CONFIG
-        .exhentai
-        .search_url
-        .host_str()
-        .expect("failed to extract host from search_url")
-});
-
-/// 将图片地址格式化为 html
-pub fn img_urls_to_html(img_urls: &[String]) -> String {
+/// 将图片地址格式化为 html，切分过的长图一项中包含多张图片
+pub fn img_urls_to_html(img_urls: &[Option<Vec<String>>]) -> String {
     img_urls
         .iter()
-        .filter(|s| !s.is_empty())
+        .flatten()
+        .flatten()
         .map(|s| format!(r#"<img src="{}">"#, s))
         .collect::<Vec<_>>()
         .join("")
 }
 
+/// 图床返回的地址可能是 telegraph 的相对路径，转换为完整地址
+pub fn absolute_image_url(url: &str) -> String {
+    if url.starts_with('/') {
+        format!("https://telegra.ph{}", url)
+    } else {
+        url.to_owned()
+    }
+}
+
 /// 左填充空格
 fn pad_left(s: &str, len: usize) -> Cow<str> {
     let width = unicode_width::UnicodeWidthStr::width(s);
@@ -39,8 +42,11 @@ fn pad_left(s: &str, len: usize) -> Cow<str> {
     }
 }
 
-/// 将 tag 转换为可以直接发送至 tg 的文本格式
-pub fn tags_to_string(tags: &[(String, Vec<String>)]) -> String {
+/// 翻译 tag，返回翻译后的 namespace 和以 # 开头的 tag
+pub fn translate_tags(
+    db: &trans::Database,
+    tags: &[(String, Vec<String>)],
+) -> Vec<(String, Vec<String>)> {
     let replace_table = vec![
         (" ", "_"),
         ("_|_", " #"),
@@ -51,7 +57,7 @@ pub fn tags_to_string(tags: &[(String, Vec<String>)]) -> String {
     let trans = |namespace: &str, string: &str| -> String {
         // 形如 "usashiro mani | mani" 的 tag 只需要取第一部分翻译
         let to_translate = string.split(" | ").next().unwrap();
-        let mut result = TRANS.trans(namespace, to_translate).to_owned();
+        let mut result = db.trans(namespace, to_translate).to_owned();
         // 没有翻译的话，还是使用原始字符串
         if result == to_translate {
             result = string.to_owned();
@@ -61,16 +67,53 @@ pub fn tags_to_string(tags: &[(String, Vec<String>)]) -> String {
         }
         format!("#{}", result)
     };
-    let mut ret = vec![];
-    for (k, v) in tags {
-        let v = v.iter().map(|s| trans(k, s)).collect::<Vec<_>>().join(" ");
-        ret.push(format!(
-            "<code>{}</code>: {}",
-            pad_left(TRANS.trans("rows", k), 6),
-            v
-        ))
+    tags.iter()
+        .map(|(k, v)| {
+            let v = v.iter().map(|s| trans(k, s)).collect();
+            (db.trans("rows", k).to_owned(), v)
+        })
+        .collect()
+}
+
+/// 将 tag 转换为可以直接发送至 tg 的文本格式
+pub fn tags_to_string(db: &trans::Database, tags: &[(String, Vec<String>)]) -> String {
+    translate_tags(db, tags)
+        .iter()
+        .map(|(k, v)| format!("<code>{}</code>: {}", pad_left(k, 6), v.join(" ")))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// 比较两组 tag 是否相同，忽略顺序
+pub fn same_tags(a: &[(String, Vec<String>)], b: &[(String, Vec<String>)]) -> bool {
+    fn flatten(tags: &[(String, Vec<String>)]) -> BTreeSet<(&str, &str)> {
+        tags.iter()
+            .flat_map(|(ns, v)| v.iter().map(move |tag| (ns.as_str(), tag.as_str())))
+            .collect()
     }
-    ret.join("\n")
+    flatten(a) == flatten(b)
+}
+
+/// 反转义 HTML 实体
+pub fn unescape_html(s: &str) -> Cow<str> {
+    static RE: Lazy<Regex> =
+        Lazy::new(|| Regex::new(r"&(#[xX][0-9a-fA-F]+|#\d+|amp|lt|gt|quot|apos);").unwrap());
+    RE.replace_all(s, |caps: &Captures| match &caps[1] {
+        "amp" => "&".to_owned(),
+        "lt" => "<".to_owned(),
+        "gt" => ">".to_owned(),
+        "quot" => "\"".to_owned(),
+        "apos" => "'".to_owned(),
+        code => {
+            let code = match code.strip_prefix("#x").or_else(|| code.strip_prefix("#X")) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                None => code[1..].parse::<u32>().ok(),
+            };
+            code.and_then(char::from_u32)
+                .map(String::from)
+                .unwrap_or_else(|| caps[0].to_owned())
+        }
+    })
 }
 
 /// 从 e 站 url 中获取数字格式的 id，第二项为 token
@@ -96,9 +139,9 @@ pub fn get_hash_from_image(url: &str) -> Option<&str> {
     caps.get(1).map(|s| s.as_str())
 }
 
-/// 根据消息 id 生成当前频道的消息直链
-pub fn get_message_url(id: i32) -> String {
-    format!("https://t.me/{}/{}", CONFIG.telegram.channel_id, id)
+/// 根据消息 id 生成指定频道的消息直链
+pub fn get_message_url(channel_id: &Recipient, id: i32) -> String {
+    format!("https://t.me/{}/{}", channel_id, id)
         .replace("/-100", "/")
         .replace('@', "")
 }
@@ -123,6 +166,7 @@ pub async fn download_to_temp(client: &Client, url: &str) -> anyhow::Result<Name
         .header(CONNECTION, "keep-alive")
         .header(REFERER, "https://exhentai.org/")
         .send()
+        .and_then(|r| async move { r.error_for_status() })
         .and_then(Response::bytes)
         .await?;
     let suffix = String::from(".") + url.rsplit_once('.').context("找不到图片后缀")?.1;
@@ -134,3 +178,31 @@ pub async fn download_to_temp(client: &Client, url: &str) -> anyhow::Result<Name
     tmp.write_all(bytes.as_ref())?;
     Ok(tmp)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unescape_html() {
+        assert_eq!(unescape_html("a &amp; b"), "a & b");
+        assert_eq!(unescape_html("&#039;&quot;&lt;&gt;"), "'\"<>");
+        assert_eq!(unescape_html("&#x2661;&#9825;"), "♡♡");
+        assert_eq!(unescape_html("&amp;#9825;"), "&#9825;");
+        assert_eq!(unescape_html("&foo;"), "&foo;");
+    }
+
+    #[test]
+    fn test_same_tags() {
+        let a = vec![
+            ("female".to_owned(), vec!["a".to_owned(), "b".to_owned()]),
+            ("other".to_owned(), vec!["c".to_owned()]),
+        ];
+        let b = vec![
+            ("other".to_owned(), vec!["c".to_owned()]),
+            ("female".to_owned(), vec!["b".to_owned(), "a".to_owned()]),
+        ];
+        assert!(same_tags(&a, &b));
+        assert!(!same_tags(&a, &b[..1]));
+    }
+}